@@ -9,7 +9,7 @@ extern crate alloc;
 use core::panic::PanicInfo;
 
 use boot::{sleep_ms_until, sleep_us};
-use visionfive2_sd::{SDIo, SleepOps, Vf2SdDriver};
+use visionfive2_sd::{MmioSdIo, SdioInstance, SleepOps, Vf2SdDriver};
 
 use crate::boot::{hart_id, sleep_ms};
 use crate::config::UART_BASE;
@@ -24,26 +24,7 @@ mod console;
 mod fatfs;
 mod sbi;
 
-pub struct SdIoImpl;
-pub const SDIO_BASE: usize = 0x16020000;
-impl SDIo for SdIoImpl {
-    fn read_reg_at(&self, offset: usize) -> u32 {
-        let addr = (SDIO_BASE + offset) as *mut u32;
-        unsafe { addr.read_volatile() }
-    }
-    fn write_reg_at(&mut self, offset: usize, val: u32) {
-        let addr = (SDIO_BASE + offset) as *mut u32;
-        unsafe { addr.write_volatile(val) }
-    }
-    fn read_data_at(&self, offset: usize) -> u64 {
-        let addr = (SDIO_BASE + offset) as *mut u64;
-        unsafe { addr.read_volatile() }
-    }
-    fn write_data_at(&mut self, offset: usize, val: u64) {
-        let addr = (SDIO_BASE + offset) as *mut u64;
-        unsafe { addr.write_volatile(val) }
-    }
-}
+pub type SdIoImpl = MmioSdIo;
 
 pub struct SleepOpsImpl;
 
@@ -63,14 +44,14 @@ pub fn main() {
     console::init_logger();
     println!("boot hart_id: {}", hart_id());
     // init_print(&PrePrint);
-    let mut sd = Vf2SdDriver::<_, SleepOpsImpl>::new(SdIoImpl);
-    sd.init();
+    let mut sd = unsafe { Vf2SdDriver::<_, SleepOpsImpl>::from_instance(SdioInstance::Sdio1) };
+    sd.init().unwrap();
     // serial::init_log(log::LevelFilter::Error).unwrap();
     // let sd = SdHost;
     // sd.init().unwrap();
     println!("sd init ok");
     let mut buf = [0; 512];
-    sd.read_block(0, &mut buf);
+    sd.read_block(0, &mut buf).unwrap();
     println!("buf: {:x?}", &buf[..16]);
     // init_fatfs2(sd);
     init_fatfs(sd);
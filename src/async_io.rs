@@ -0,0 +1,402 @@
+//! Block transfers as futures woken by the interrupt handler
+//!
+//! The interrupt handler only needs its own register access to the controller, e.g. a
+//! [`MmioSdIo`] of the same instance, and the [`SdIrq`] shared with the futures. The data
+//! is moved by the future when it is polled, so the buffers stay borrowed by the future.
+use crate::irq::{
+    abort_data_transfer, advance_transfer, mask_transfer_interrupts, start_transfer_cmd,
+    transfer_interrupts, unmask_idle_interrupts,
+};
+use crate::*;
+use atomic_waker::AtomicWaker;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Wakes the transfer future of one controller from its interrupt handler
+pub struct SdIrq {
+    waker: AtomicWaker,
+}
+
+impl SdIrq {
+    pub const fn new() -> Self {
+        Self {
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Entry point of the interrupt handler of the controller
+    ///
+    /// The interrupts are masked so the line drops until the woken future polled the
+    /// controller and unmasked them again. Without a transfer in flight the card detect
+    /// interrupt stays masked until [`Vf2SdDriver::take_card_event`].
+    pub fn on_interrupt<T: SDIo>(&self, io: &mut T) {
+        write_reg(io, INT_MASK_REG, 0);
+        self.waker.wake();
+    }
+}
+
+impl Default for SdIrq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CMD13 sent per millisecond of the programming timeout, a status round trip takes about 10 us
+const STATUS_POLLS_PER_MS: usize = 100;
+
+/// Send `cmd` without waiting for its response, the command done interrupt wakes the future
+fn start_cmd<T: SDIo, S: SleepOps>(io: &mut T, cmd: Cmd, arg: u32) -> Result<()> {
+    wait_ms_util_can_send_cmd::<_, S>(io)?;
+    write_reg(io, ARG_REG, arg);
+    write_reg(io, CMD_REG, CmdReg::from(cmd).into());
+    wait_ms_util_can_send_cmd::<_, S>(io)
+}
+
+/// The response of `cmd` started by [`start_cmd`], once `pending` reports it
+fn cmd_result<T: SDIo>(io: &mut T, cmd: Cmd, pending: u32) -> Option<Result<u32>> {
+    let mut ints = RawInterrupt::from(pending as u16);
+    if !ints.command_done() && !ints.have_error() {
+        return None;
+    }
+    write_reg(io, RAW_INT_STATUS_REG, pending);
+    let resp = read_reg(io, RESP0_REG);
+    if ints.have_error() {
+        error!("cmd {:?} has error {:#?}", cmd, ints);
+        Some(Err(interrupt_error(cmd, &mut ints)))
+    } else if cmd.response_is_card_status() && resp & CARD_STATUS_ERROR_MASK != 0 {
+        error!("cmd {:?} card status {:#x}", cmd, resp);
+        Some(Err(Vf2SdDriverError::CardStatus(cmd, resp)))
+    } else {
+        Some(Ok(resp))
+    }
+}
+
+/// What the transfer future waits for
+enum Stage {
+    /// The data phase of the data command
+    Data,
+    /// The response of CMD12 stopping the failed data phase
+    Stop(Vf2SdDriverError),
+    /// The response of CMD13 while the card stores the `len` bytes written
+    Program { len: usize, polls: usize },
+}
+
+/// A started transfer, aborted if dropped before it completed
+struct TransferFuture<'a, T: SDIo, S: SleepOps> {
+    driver: &'a mut Vf2SdDriver<T, S>,
+    irq: &'a SdIrq,
+    cmd: Cmd,
+    data: DataTransType<'a>,
+    offset: usize,
+    stage: Stage,
+    done: bool,
+}
+
+impl<T: SDIo, S: SleepOps> TransferFuture<'_, T, S> {
+    /// Handle the `pending` interrupts, returning the result once the transfer ended
+    fn advance(&mut self, pending: u32) -> Option<Result<usize>> {
+        let io = &mut self.driver.io;
+        match self.stage {
+            Stage::Data => {
+                let result =
+                    advance_transfer(io, self.cmd, &mut self.data, &mut self.offset, pending)?;
+                match result {
+                    Ok(len)
+                        if matches!(self.cmd, Cmd::WriteSingleBlock | Cmd::WriteMultipleBlock) =>
+                    {
+                        self.stage = Stage::Program { len, polls: 0 };
+                        self.send_status(len)
+                    }
+                    Err(e)
+                        if matches!(self.cmd, Cmd::ReadMultipleBlock | Cmd::WriteMultipleBlock) =>
+                    {
+                        if let Err(stop) = start_cmd::<_, S>(io, Cmd::StopTransmission, 0) {
+                            warn!("stop transmission failed: {:?}", stop);
+                            return Some(Err(e));
+                        }
+                        self.stage = Stage::Stop(e);
+                        None
+                    }
+                    result => Some(result),
+                }
+            }
+            Stage::Stop(e) => {
+                if let Err(stop) = cmd_result(io, Cmd::StopTransmission, pending)? {
+                    warn!("stop transmission failed: {:?}", stop);
+                }
+                Some(Err(e))
+            }
+            Stage::Program { len, polls } => match cmd_result(io, Cmd::SendStatus, pending)? {
+                Ok(status) if CardStatus::from(status).is_transfer_ready() => Some(Ok(len)),
+                Ok(_) if polls >= data_timeout_ms(len) * STATUS_POLLS_PER_MS => {
+                    error!("card did not store {} bytes", len);
+                    Some(Err(Vf2SdDriverError::TimeoutError))
+                }
+                Ok(_) => {
+                    self.stage = Stage::Program {
+                        len,
+                        polls: polls + 1,
+                    };
+                    self.send_status(len)
+                }
+                Err(e) => Some(Err(e)),
+            },
+        }
+    }
+    /// Ask for the card status while it stores the `len` bytes written, answered with a
+    /// command done interrupt
+    fn send_status(&mut self, len: usize) -> Option<Result<usize>> {
+        // the card can only be addressed after init
+        let Some(rca) = self.driver.card_info().map(|info| info.rca()) else {
+            return Some(Ok(len));
+        };
+        start_cmd::<_, S>(&mut self.driver.io, Cmd::SendStatus, rca << 16)
+            .err()
+            .map(Err)
+    }
+}
+
+impl<T: SDIo, S: SleepOps> Future for TransferFuture<'_, T, S> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        // the interrupts are masked by the handler, look at the raw status
+        let pending = read_reg(&this.driver.io, RAW_INT_STATUS_REG) & transfer_interrupts();
+        if pending != 0 {
+            if let Some(result) = this.advance(pending) {
+                this.done = true;
+                mask_transfer_interrupts(&mut this.driver.io);
+                unmask_idle_interrupts(this.driver);
+                return Poll::Ready(result);
+            }
+        }
+        this.irq.waker.register(cx.waker());
+        // an interrupt raised from here on wakes the registered waker
+        let ints = this.driver.idle_interrupts() | transfer_interrupts();
+        write_reg(&mut this.driver.io, INT_MASK_REG, ints);
+        Poll::Pending
+    }
+}
+
+impl<T: SDIo, S: SleepOps> Drop for TransferFuture<'_, T, S> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let io = &mut self.driver.io;
+        match self.stage {
+            Stage::Data => {
+                warn!("abort {:?} at offset {}", self.cmd, self.offset);
+                abort_data_transfer::<_, S>(io);
+            }
+            // the data phase is over, only drop the response of the CMD12 or CMD13 in flight,
+            // the next command waits until the card is not busy
+            Stage::Stop(_) | Stage::Program { .. } => {
+                if wait_ms_util_response::<_, S>(io).is_err() {
+                    warn!("no response after {:?}", self.cmd);
+                }
+                mask_transfer_interrupts(io);
+                let status = read_reg(io, RAW_INT_STATUS_REG) & !INT_CARD_DETECT;
+                write_reg(io, RAW_INT_STATUS_REG, status);
+            }
+        }
+        unmask_idle_interrupts(self.driver);
+    }
+}
+
+impl<T: SDIo, S: SleepOps> Vf2SdDriver<T, S> {
+    /// Read `buf.len() / BLOCK_SIZE` consecutive blocks starting at `block`
+    ///
+    /// The future is woken through `irq`, the interrupts must be enabled with
+    /// [`Vf2SdDriver::enable_interrupts`]. Dropping it before completion aborts the transfer.
+    pub async fn read_blocks_async(
+        &mut self,
+        irq: &SdIrq,
+        block: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.start_async(irq, block, DataTransType::Read(buf))?
+            .await
+    }
+    /// Write `buf.len() / BLOCK_SIZE` consecutive blocks starting at `block`
+    ///
+    /// See [`Vf2SdDriver::read_blocks_async`], the future completes once the card stored the
+    /// data.
+    pub async fn write_blocks_async(
+        &mut self,
+        irq: &SdIrq,
+        block: usize,
+        buf: &[u8],
+    ) -> Result<usize> {
+        self.start_async(irq, block, DataTransType::Write(buf))?
+            .await
+    }
+    fn start_async<'a>(
+        &'a mut self,
+        irq: &'a SdIrq,
+        block: usize,
+        data: DataTransType<'a>,
+    ) -> Result<TransferFuture<'a, T, S>> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        let (write, len) = match &data {
+            DataTransType::Read(buf) => (false, buf.len()),
+            DataTransType::Write(buf) => (true, buf.len()),
+            _ => unreachable!(),
+        };
+        let cmd = start_transfer_cmd::<_, S>(&mut self.io, write, addr, len)?;
+        Ok(TransferFuture {
+            driver: self,
+            irq,
+            cmd,
+            data,
+            offset: 0,
+            stage: Stage::Data,
+            done: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irq::tests::{driver, driver_with};
+    use crate::sim::{Fault, FaultPlan, SimulatedController};
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::boxed::Box;
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Poll `fut` and run the interrupt handler while the line is raised
+    fn block_on<F: Future>(isr_io: &mut SimulatedController, irq: &SdIrq, fut: F) -> F::Output {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+        for _ in 0..100 {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+            assert!(isr_io.interrupt_pending());
+            irq.on_interrupt(isr_io);
+            assert!(!isr_io.interrupt_pending());
+            assert!(flag.0.swap(false, Ordering::SeqCst));
+        }
+        panic!("future did not complete");
+    }
+
+    #[test]
+    fn test_async_read_write() {
+        let mut driver = driver();
+        let mut isr_io = driver.io.share();
+        let irq = SdIrq::new();
+        let data = [0xa5; BLOCK_SIZE * 3];
+        let res = block_on(&mut isr_io, &irq, driver.write_blocks_async(&irq, 5, &data));
+        assert_eq!(res, Ok(BLOCK_SIZE * 3));
+        let mut buf = [0u8; BLOCK_SIZE * 4];
+        let res = block_on(
+            &mut isr_io,
+            &irq,
+            driver.read_blocks_async(&irq, 4, &mut buf),
+        );
+        assert_eq!(res, Ok(BLOCK_SIZE * 4));
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 4));
+        assert!(buf[BLOCK_SIZE..].iter().all(|&b| b == 0xa5));
+        let res = block_on(
+            &mut isr_io,
+            &irq,
+            driver.read_blocks_async(&irq, 1, &mut buf[..8]),
+        );
+        assert_eq!(res, Err(Vf2SdDriverError::BufferSizeError));
+    }
+
+    #[test]
+    fn test_async_error() {
+        let mut driver = driver();
+        let mut isr_io = driver.io.share();
+        let irq = SdIrq::new();
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(18, Fault::DataCrc));
+        let mut buf = [0u8; BLOCK_SIZE * 2];
+        let res = block_on(
+            &mut isr_io,
+            &irq,
+            driver.read_blocks_async(&irq, 0, &mut buf),
+        );
+        assert_eq!(res, Err(Vf2SdDriverError::DataCrc(Cmd::ReadMultipleBlock)));
+        assert!(driver.status().unwrap().is_transfer_ready());
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(25, Fault::DataCrc));
+        let res = block_on(&mut isr_io, &irq, driver.write_blocks_async(&irq, 0, &buf));
+        assert_eq!(res, Err(Vf2SdDriverError::DataCrc(Cmd::WriteMultipleBlock)));
+        assert!(driver.status().unwrap().is_transfer_ready());
+    }
+
+    #[test]
+    fn test_async_programming() {
+        let mut driver = driver_with(|card| card.with_program_polls(3));
+        let mut isr_io = driver.io.share();
+        let irq = SdIrq::new();
+        let data = [0x3c; BLOCK_SIZE * 2];
+        let res = block_on(&mut isr_io, &irq, driver.write_blocks_async(&irq, 1, &data));
+        assert_eq!(res, Ok(BLOCK_SIZE * 2));
+        assert!(driver.status().unwrap().is_transfer_ready());
+
+        let mut driver = driver_with(|card| card.with_program_polls(usize::MAX));
+        let mut isr_io = driver.io.share();
+        // the data phase completed, dropping the future must not stop the transmission
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(12, Fault::StuckStartCmd));
+        {
+            let waker = Waker::from(Arc::new(Flag(AtomicBool::new(false))));
+            let mut cx = Context::from_waker(&waker);
+            let mut fut = Box::pin(driver.write_blocks_async(&irq, 1, &data));
+            // the card status is polled from the wakes instead of waiting in poll
+            for _ in 0..50 {
+                assert!(fut.as_mut().poll(&mut cx).is_pending());
+                assert!(isr_io.interrupt_pending());
+                irq.on_interrupt(&mut isr_io);
+            }
+        }
+        assert_eq!(driver.status().unwrap().state(), CardState::Prg);
+        assert!(!isr_io.interrupt_pending());
+    }
+
+    #[test]
+    fn test_async_cancel() {
+        let mut driver = driver();
+        let isr_io = driver.io.share();
+        let irq = SdIrq::new();
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(18, Fault::DataStall));
+        let mut buf = [0u8; BLOCK_SIZE * 2];
+        {
+            let waker = Waker::from(Arc::new(Flag(AtomicBool::new(false))));
+            let mut cx = Context::from_waker(&waker);
+            let mut fut = Box::pin(driver.read_blocks_async(&irq, 2, &mut buf));
+            assert!(fut.as_mut().poll(&mut cx).is_pending());
+            assert!(fut.as_mut().poll(&mut cx).is_pending());
+            // the stalled data phase only raised command done
+            assert!(!isr_io.interrupt_pending());
+        }
+        assert!(!isr_io.interrupt_pending());
+        assert!(driver.status().unwrap().is_transfer_ready());
+        driver.read_blocks(2, &mut buf).unwrap();
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 2));
+        assert!(buf[BLOCK_SIZE..].iter().all(|&b| b == 3));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::register::{Cid, Csd, ExtCsd, Scr};
+use crate::utils::GetBit;
+
+/// Change of the card slot reported by the card detect interrupt
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardEvent {
+    Inserted,
+    Removed,
+}
+
+/// Hardware partitions of a MMC, selected by PARTITION_ACCESS of the EXT_CSD
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HwPartition {
+    User,
+    Boot1,
+    Boot2,
+    /// Replay protected memory block, only accessed with authenticated frames
+    Rpmb,
+    /// General purpose partition 1 to 4
+    General(u8),
+}
+
+impl HwPartition {
+    /// The PARTITION_ACCESS value
+    pub fn access(&self) -> u8 {
+        match *self {
+            HwPartition::User => 0,
+            HwPartition::Boot1 => 1,
+            HwPartition::Boot2 => 2,
+            HwPartition::Rpmb => 3,
+            HwPartition::General(n) => 3 + n,
+        }
+    }
+
+    pub fn from_access(access: u8) -> Self {
+        match access & 0x7 {
+            0 => HwPartition::User,
+            1 => HwPartition::Boot1,
+            2 => HwPartition::Boot2,
+            3 => HwPartition::Rpmb,
+            n => HwPartition::General(n - 3),
+        }
+    }
+}
+
+/// Registers of the card read during initialization
+#[derive(Debug, Copy, Clone)]
+pub struct CardInfo {
+    ocr: u32,
+    cid: Cid,
+    csd: Csd,
+    scr: Scr,
+    ext_csd: Option<ExtCsd>,
+    rca: u32,
+    oem_id: [u8; 2],
+    /// NUL padded, SD product names have 5 characters and MMC ones 6
+    product_name: [u8; 6],
+    product_revision: (u8, u8),
+    serial: u32,
+    manufacture_date: (u16, u8),
+}
+
+/// Trim the NUL padding of a CID string
+fn cid_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+impl CardInfo {
+    pub(crate) fn new(ocr: u32, cid: Cid, csd: Csd, scr: Scr, rca: u32) -> Self {
+        let pnm = cid.pnm_bytes();
+        Self {
+            ocr,
+            cid,
+            csd,
+            scr,
+            ext_csd: None,
+            rca,
+            oem_id: cid.oid_bytes(),
+            product_name: [pnm[0], pnm[1], pnm[2], pnm[3], pnm[4], 0],
+            product_revision: cid.prv_bytes(),
+            serial: cid.psn(),
+            manufacture_date: cid.mdt_bytes(),
+        }
+    }
+
+    pub(crate) fn new_mmc(ocr: u32, cid: Cid, csd: Csd, ext_csd: ExtCsd, rca: u32) -> Self {
+        Self {
+            ocr,
+            cid,
+            csd,
+            scr: Scr::new(0),
+            ext_csd: Some(ext_csd),
+            rca,
+            oem_id: [cid.mmc_oid(), 0],
+            product_name: cid.mmc_pnm_bytes(),
+            product_revision: cid.mmc_prv_bytes(),
+            serial: cid.mmc_psn(),
+            manufacture_date: cid.mmc_mdt_bytes(ext_csd.ext_csd_rev()),
+        }
+    }
+
+    /// Whether the card is a MMC/eMMC rather than a SD card
+    pub fn is_mmc(&self) -> bool {
+        self.ext_csd.is_some()
+    }
+
+    pub fn ocr(&self) -> u32 {
+        self.ocr
+    }
+
+    pub fn cid(&self) -> &Cid {
+        &self.cid
+    }
+
+    pub fn csd(&self) -> &Csd {
+        &self.csd
+    }
+
+    /// SD Configuration Register, all zero for MMC
+    pub fn scr(&self) -> &Scr {
+        &self.scr
+    }
+
+    /// Extended CSD of MMC, read before the bus width and timing were switched
+    pub fn ext_csd(&self) -> Option<&ExtCsd> {
+        self.ext_csd.as_ref()
+    }
+
+    /// Relative card address published by CMD3 (SD) or assigned by the host (MMC)
+    pub fn rca(&self) -> u32 {
+        self.rca
+    }
+
+    /// Manufacturer ID (MID) assigned by the SD-3C or JEDEC
+    pub fn manufacturer_id(&self) -> u8 {
+        self.cid.mid()
+    }
+
+    /// OEM/application ID (OID), empty if it is not ASCII
+    pub fn oem_id(&self) -> &str {
+        cid_str(&self.oem_id)
+    }
+
+    /// Product name (PNM), empty if it is not ASCII
+    pub fn product_name(&self) -> &str {
+        cid_str(&self.product_name)
+    }
+
+    /// Product revision (PRV) as (major, minor)
+    pub fn product_revision(&self) -> (u8, u8) {
+        self.product_revision
+    }
+
+    /// Product serial number (PSN)
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Manufacturing date (MDT) as (year, month)
+    pub fn manufacture_date(&self) -> (u16, u8) {
+        self.manufacture_date
+    }
+
+    pub fn capacity_blocks(&self) -> u64 {
+        self.capacity_bytes() / 512
+    }
+
+    pub fn capacity_bytes(&self) -> u64 {
+        match self.ext_csd {
+            Some(ext_csd) if self.is_high_capacity() && ext_csd.sec_count() != 0 => {
+                ext_csd.sec_count() as u64 * 512
+            }
+            Some(_) => self.csd.mmc_capacity_bytes(),
+            None => self.csd.capacity_bytes(),
+        }
+    }
+
+    /// Size of a hardware partition, 0 if the card does not have it
+    ///
+    /// SD cards only have the user data area.
+    pub fn partition_bytes(&self, partition: HwPartition) -> u64 {
+        let Some(ext_csd) = self.ext_csd else {
+            return if partition == HwPartition::User {
+                self.capacity_bytes()
+            } else {
+                0
+            };
+        };
+        match partition {
+            HwPartition::User => self.capacity_bytes(),
+            HwPartition::Boot1 | HwPartition::Boot2 => ext_csd.boot_partition_bytes(),
+            HwPartition::Rpmb => ext_csd.rpmb_partition_bytes(),
+            HwPartition::General(n @ 1..=4) => ext_csd.gp_partition_bytes(n as usize - 1),
+            HwPartition::General(_) => 0,
+        }
+    }
+
+    /// The unit of erasing in blocks, a MMC erases whole erase groups
+    ///
+    /// SD cards without ERASE_BLK_EN erase whole sectors.
+    pub fn erase_unit_blocks(&self) -> u32 {
+        match self.ext_csd {
+            Some(ext_csd) if ext_csd.erase_group_def() & 1 != 0 => {
+                ext_csd.hc_erase_grp_size() as u32 * 1024
+            }
+            Some(_) => self.csd.mmc_erase_grp_blocks(),
+            None if self.csd.erase_blk_en() => 1,
+            None => self.csd.sector_size() as u32,
+        }
+    }
+
+    /// The value of all bytes of an erased block, from DATA_STAT_AFTER_ERASE of the SCR or
+    /// ERASED_MEM_CONT of the EXT_CSD
+    pub fn erased_byte(&self) -> u8 {
+        let ones = match self.ext_csd {
+            Some(ext_csd) => ext_csd.erased_mem_cont() != 0,
+            None => self.scr.data_stat_after_erase(),
+        };
+        if ones {
+            0xff
+        } else {
+            0
+        }
+    }
+
+    /// Card Capacity Status of the OCR, set for SDHC/SDXC cards and sector addressed MMC
+    pub fn is_high_capacity(&self) -> bool {
+        self.ocr.get_bit(30)
+    }
+
+    /// SD physical layer specification version as (major, minor)
+    pub fn spec_version(&self) -> (u8, u8) {
+        self.scr.spec_version()
+    }
+
+    /// SD_BUS_WIDTHS of the SCR, bit 0 is 1-bit and bit 2 is 4-bit
+    pub fn bus_widths(&self) -> u8 {
+        self.scr.bus_widths()
+    }
+
+    pub fn support_4bit(&self) -> bool {
+        self.scr.support_4bit()
+    }
+}
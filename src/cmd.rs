@@ -1,10 +1,11 @@
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Cmd {
     GoIdleState,
     AllSendCid,
     SendRelativeAddr,
     SetDSR,
+    SwitchFunc,
     SelectCard,
     SendIfCond,
     SendCsd,
@@ -29,9 +30,70 @@ pub enum Cmd {
     SdSendOpCond,
     SetClrCardDetect,
     SendScr,
+    // MMC
+    SendOpCond,
+    SetRelativeAddr,
+    Switch,
+    SendExtCsd,
+    SetBlockCount,
+    EraseGroupStart,
+    EraseGroupEnd,
     // Private
     ResetClock,
+    /// A command unknown to the driver, e.g. a vendor command
+    Raw(RawCmd),
 }
+
+/// Response format of a command
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResponseType {
+    None,
+    /// Card status
+    R1,
+    /// Card status, the card signals busy on DAT0 afterwards
+    R1b,
+    /// CID or CSD, 136 bits
+    R2,
+    /// OCR, without CRC
+    R3,
+    /// Published RCA of a SD card
+    R6,
+    /// Card interface condition
+    R7,
+}
+
+impl ResponseType {
+    /// Whether the controller checks the CRC of the response
+    ///
+    /// The CRC of R2 covers the CID/CSD only and R3 has none.
+    pub fn has_crc(&self) -> bool {
+        !matches!(
+            self,
+            ResponseType::None | ResponseType::R2 | ResponseType::R3
+        )
+    }
+}
+
+/// Direction of the data phase of a command
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataDir {
+    None,
+    /// From the card to the host
+    Read,
+    /// From the host to the card
+    Write,
+}
+
+/// Description of a command sent with [`Vf2SdDriver::raw_cmd`](crate::Vf2SdDriver::raw_cmd)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RawCmd {
+    pub index: u8,
+    /// Application specific command, preceded by CMD55
+    pub app: bool,
+    pub response: ResponseType,
+    pub data: DataDir,
+}
+
 impl From<Cmd> for u8 {
     fn from(val: Cmd) -> Self {
         match val {
@@ -39,6 +101,7 @@ impl From<Cmd> for u8 {
             Cmd::AllSendCid => 2,
             Cmd::SendRelativeAddr => 3,
             Cmd::SetDSR => 4,
+            Cmd::SwitchFunc => 6,
             Cmd::SelectCard => 7,
             Cmd::SendIfCond => 8,
             Cmd::SendCsd => 9,
@@ -63,9 +126,79 @@ impl From<Cmd> for u8 {
             Cmd::SdSendOpCond => 41,
             Cmd::SetClrCardDetect => 42,
             Cmd::SendScr => 51,
-            _ => {
-                panic!("Not implemented for cmd {:?}", val);
+            Cmd::SendOpCond => 1,
+            Cmd::SetRelativeAddr => 3,
+            Cmd::Switch => 6,
+            Cmd::SendExtCsd => 8,
+            Cmd::SetBlockCount => 23,
+            Cmd::EraseGroupStart => 35,
+            Cmd::EraseGroupEnd => 36,
+            // the clock update is not sent to the card
+            Cmd::ResetClock => 0,
+            Cmd::Raw(raw) => raw.index,
+        }
+    }
+}
+
+impl Cmd {
+    /// Whether the command is an application specific command, preceded by CMD55
+    pub fn is_app_cmd(&self) -> bool {
+        match self {
+            Cmd::SetBusWidth
+            | Cmd::SdStatus
+            | Cmd::SendNumWrBlocks
+            | Cmd::SetWrBlkEraseCnt
+            | Cmd::SdSendOpCond
+            | Cmd::SetClrCardDetect
+            | Cmd::SendScr => true,
+            Cmd::Raw(raw) => raw.app,
+            _ => false,
+        }
+    }
+
+    pub fn response_type(&self) -> ResponseType {
+        match self {
+            Cmd::GoIdleState | Cmd::SetDSR | Cmd::GoInactiveState | Cmd::ResetClock => {
+                ResponseType::None
             }
+            Cmd::AllSendCid | Cmd::SendCsd | Cmd::SendCid => ResponseType::R2,
+            Cmd::SdSendOpCond | Cmd::SendOpCond => ResponseType::R3,
+            Cmd::SendRelativeAddr => ResponseType::R6,
+            Cmd::SendIfCond => ResponseType::R7,
+            Cmd::SelectCard | Cmd::StopTransmission | Cmd::Erase | Cmd::Switch => ResponseType::R1b,
+            Cmd::Raw(raw) => raw.response,
+            _ => ResponseType::R1,
         }
     }
+
+    /// Direction of the data phase
+    ///
+    /// CMD56 is described as a read, GEN_CMD writes are sent as [`Cmd::Raw`].
+    pub fn data_dir(&self) -> DataDir {
+        match self {
+            Cmd::SwitchFunc
+            | Cmd::ReadSingleBlock
+            | Cmd::ReadMultipleBlock
+            | Cmd::GenCmd
+            | Cmd::SdStatus
+            | Cmd::SendNumWrBlocks
+            | Cmd::SendScr
+            | Cmd::SendExtCsd => DataDir::Read,
+            Cmd::WriteSingleBlock | Cmd::WriteMultipleBlock => DataDir::Write,
+            Cmd::Raw(raw) => raw.data,
+            _ => DataDir::None,
+        }
+    }
+
+    /// Whether the card signals busy on DAT0 after the response, the caller waits for it
+    pub fn expects_busy(&self) -> bool {
+        self.response_type() == ResponseType::R1b
+    }
+
+    /// Whether the command is answered with a R1/R1b card status which reports errors
+    ///
+    /// CMD55 is excluded, its status reports the illegal CMD8 of v1.x cards.
+    pub fn response_is_card_status(&self) -> bool {
+        matches!(self.response_type(), ResponseType::R1 | ResponseType::R1b) && *self != Cmd::AppCmd
+    }
 }
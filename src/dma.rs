@@ -0,0 +1,201 @@
+use crate::{Result, Vf2SdDriverError};
+use core::mem::size_of;
+
+/// Own bit, the descriptor is owned by the IDMAC
+pub(crate) const DES0_OWN: u32 = 1 << 31;
+/// Card error summary
+const DES0_CES: u32 = 1 << 30;
+/// End of ring
+const DES0_ER: u32 = 1 << 5;
+/// Second address chained, des6/des7 point to the next descriptor
+const DES0_CH: u32 = 1 << 4;
+/// First descriptor
+const DES0_FS: u32 = 1 << 3;
+/// Last descriptor
+pub(crate) const DES0_LD: u32 = 1 << 2;
+/// Disable interrupt on completion
+const DES0_DIC: u32 = 1 << 1;
+
+/// Max bytes of one descriptor buffer, des2[12:0] can hold 8191 bytes
+const DESC_MAX_LEN: usize = 4096;
+
+/// A physically contiguous buffer the IDMAC reads from or writes to
+///
+/// Only the bus address is kept, the buffer must stay valid for the transfer it is handed
+/// to, see [`Vf2SdDriver::read_blocks_dma`](crate::Vf2SdDriver::read_blocks_dma).
+#[derive(Debug, Copy, Clone)]
+pub struct DmaSegment {
+    pub phys_addr: usize,
+    pub len: usize,
+}
+
+impl DmaSegment {
+    pub fn new(phys_addr: usize, len: usize) -> Self {
+        Self { phys_addr, len }
+    }
+}
+
+/// Descriptor of the internal DMA controller in 64-bit address mode
+#[repr(C, align(8))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct IdmacDesc {
+    /// Control bits, see `DES0_*`
+    des0: u32,
+    des1: u32,
+    /// Buffer 1 size in bits[12:0]
+    des2: u32,
+    des3: u32,
+    /// Buffer 1 address lower/upper 32 bits
+    des4: u32,
+    des5: u32,
+    /// Next descriptor address lower/upper 32 bits
+    des6: u32,
+    des7: u32,
+}
+
+impl IdmacDesc {
+    pub const fn new() -> Self {
+        Self {
+            des0: 0,
+            des1: 0,
+            des2: 0,
+            des3: 0,
+            des4: 0,
+            des5: 0,
+            des6: 0,
+            des7: 0,
+        }
+    }
+
+    /// The IDMAC still owns the descriptor
+    pub fn is_owned(&self) -> bool {
+        // SAFETY: the field is a valid, aligned u32 which the IDMAC may update
+        let des0 = unsafe { core::ptr::read_volatile(&self.des0) };
+        des0 & DES0_OWN != 0
+    }
+
+    /// The card reported an error for the transfer of this descriptor
+    pub fn card_error(&self) -> bool {
+        // SAFETY: the field is a valid, aligned u32 which the IDMAC may update
+        let des0 = unsafe { core::ptr::read_volatile(&self.des0) };
+        des0 & DES0_CES != 0
+    }
+
+    fn set_des0(&mut self, des0: u32) {
+        // SAFETY: the field is a valid, aligned u32
+        unsafe { core::ptr::write_volatile(&mut self.des0, des0) }
+    }
+
+    fn write(&mut self, des0: u32, buf: DmaSegment, next: usize) {
+        let value = IdmacDesc {
+            des0,
+            des1: 0,
+            des2: buf.len as u32,
+            des3: 0,
+            des4: buf.phys_addr as u32,
+            des5: (buf.phys_addr as u64 >> 32) as u32,
+            des6: next as u32,
+            des7: (next as u64 >> 32) as u32,
+        };
+        // SAFETY: self is a valid descriptor, the volatile write keeps the store
+        // from being elided since only the IDMAC reads it
+        unsafe { core::ptr::write_volatile(self, value) }
+    }
+}
+
+/// A chain of IDMAC descriptors
+///
+/// The descriptors must live in memory the controller can access, `phys_addr`
+/// is the bus address of `descs[0]`. They are written by the transfer itself, so they
+/// have to be mapped uncached or coherent with the controller.
+pub struct IdmacRing<'a> {
+    descs: &'a mut [IdmacDesc],
+    phys_addr: usize,
+}
+
+impl<'a> IdmacRing<'a> {
+    pub fn new(descs: &'a mut [IdmacDesc], phys_addr: usize) -> Self {
+        Self { descs, phys_addr }
+    }
+
+    pub fn phys_addr(&self) -> usize {
+        self.phys_addr
+    }
+
+    pub fn descs(&self) -> &[IdmacDesc] {
+        self.descs
+    }
+
+    /// Fill the chained descriptors for `segments`, returning the total bytes
+    pub(crate) fn build(&mut self, segments: &[DmaSegment]) -> Result<usize> {
+        if self.phys_addr % 8 != 0 {
+            return Err(Vf2SdDriverError::BufferSizeError);
+        }
+        let mut total = 0;
+        let mut index = 0;
+        for seg in segments {
+            if seg.len == 0 || seg.phys_addr % 4 != 0 || seg.len % 4 != 0 {
+                return Err(Vf2SdDriverError::BufferSizeError);
+            }
+            let mut offset = 0;
+            while offset < seg.len {
+                if index >= self.descs.len() {
+                    return Err(Vf2SdDriverError::BufferSizeError);
+                }
+                let len = (seg.len - offset).min(DESC_MAX_LEN);
+                let next = self.phys_addr + (index + 1) * size_of::<IdmacDesc>();
+                let mut des0 = DES0_OWN | DES0_CH | DES0_DIC;
+                if index == 0 {
+                    des0 |= DES0_FS;
+                }
+                self.descs[index].write(des0, DmaSegment::new(seg.phys_addr + offset, len), next);
+                offset += len;
+                index += 1;
+            }
+            total += seg.len;
+        }
+        if index == 0 {
+            return Err(Vf2SdDriverError::BufferSizeError);
+        }
+        // the last descriptor ends the chain and raises the interrupt
+        let last = &mut self.descs[index - 1];
+        let des0 = (last.des0 | DES0_LD | DES0_ER) & !(DES0_CH | DES0_DIC);
+        last.set_des0(des0);
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_chain() {
+        let mut descs = [IdmacDesc::new(); 4];
+        let mut ring = IdmacRing::new(&mut descs, 0x8000_0000);
+        let segments = [
+            DmaSegment::new(0x1_0000_0000, 6144),
+            DmaSegment::new(0x9000_0000, 512),
+        ];
+        assert_eq!(ring.build(&segments).unwrap(), 6656);
+        let descs = ring.descs();
+        assert_eq!(descs[0].des0, DES0_OWN | DES0_CH | DES0_DIC | DES0_FS);
+        assert_eq!(descs[0].des2, 4096);
+        assert_eq!((descs[0].des4, descs[0].des5), (0, 1));
+        assert_eq!(descs[0].des6, 0x8000_0020);
+        assert_eq!(descs[1].des2, 2048);
+        assert_eq!(descs[1].des4, 0x1000);
+        assert_eq!(descs[2].des0, DES0_OWN | DES0_LD | DES0_ER);
+        assert_eq!(descs[2].des4, 0x9000_0000);
+        assert!(descs[2].is_owned());
+    }
+
+    #[test]
+    fn test_build_ring_too_small() {
+        let mut descs = [IdmacDesc::new(); 1];
+        let mut ring = IdmacRing::new(&mut descs, 0x8000_0000);
+        let segments = [DmaSegment::new(0x9000_0000, 8192)];
+        assert!(ring.build(&segments).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! Byte stream over the card for the [`fatfs`] crate
+use crate::{SDIo, SleepOps, Vf2SdDriver, Vf2SdDriverError, BLOCK_SIZE};
+use core::fmt::{Display, Formatter};
+use fatfs::{IoBase, IoError, Read, Seek, SeekFrom, Write};
+
+/// Errors of [`SdStream`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SdStreamError {
+    /// The driver failed to read or write a block
+    Driver(Vf2SdDriverError),
+    UnexpectedEof,
+    WriteZero,
+    /// Seek before the start or past the end of the card
+    InvalidSeek,
+}
+
+impl From<Vf2SdDriverError> for SdStreamError {
+    fn from(value: Vf2SdDriverError) -> Self {
+        SdStreamError::Driver(value)
+    }
+}
+
+impl Display for SdStreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SdStreamError::Driver(e) => write!(f, "{}", e),
+            SdStreamError::UnexpectedEof => write!(f, "unexpected end of card"),
+            SdStreamError::WriteZero => write!(f, "write beyond the end of card"),
+            SdStreamError::InvalidSeek => write!(f, "invalid seek"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SdStreamError {}
+
+impl IoError for SdStreamError {
+    fn is_interrupted(&self) -> bool {
+        false
+    }
+    fn new_unexpected_eof_error() -> Self {
+        SdStreamError::UnexpectedEof
+    }
+    fn new_write_zero_error() -> Self {
+        SdStreamError::WriteZero
+    }
+}
+
+/// The whole card as a seekable byte stream
+///
+/// Aligned blocks are moved with multi-block transfers straight from/to the caller's
+/// buffer, only the unaligned edges go through a read-modify-write of one block.
+///
+/// # Example
+/// ```rust ignore
+/// let mut driver = Vf2SdDriver::<_, SleepOpsImpl>::new(SdIoImpl);
+/// driver.init().unwrap();
+/// let fs = fatfs::FileSystem::new(SdStream::new(driver), fatfs::FsOptions::new()).unwrap();
+/// ```
+pub struct SdStream<T, S> {
+    driver: Vf2SdDriver<T, S>,
+    offset: u64,
+    size: u64,
+}
+
+impl<T: SDIo, S: SleepOps> SdStream<T, S> {
+    /// Wrap an initialized driver
+    pub fn new(driver: Vf2SdDriver<T, S>) -> Self {
+        let size = driver.capacity_bytes();
+        Self {
+            driver,
+            offset: 0,
+            size,
+        }
+    }
+
+    pub fn into_inner(self) -> Vf2SdDriver<T, S> {
+        self.driver
+    }
+
+    /// Size of the stream in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<T, S> IoBase for SdStream<T, S> {
+    type Error = SdStreamError;
+}
+
+impl<T: SDIo, S: SleepOps> Read for SdStream<T, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = (self.size - self.offset).min(buf.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            let block = (self.offset / BLOCK_SIZE as u64) as usize;
+            let block_offset = (self.offset % BLOCK_SIZE as u64) as usize;
+            let remain = len - done;
+            let n = if block_offset == 0 && remain >= BLOCK_SIZE {
+                let n = remain / BLOCK_SIZE * BLOCK_SIZE;
+                self.driver.read_blocks(block, &mut buf[done..done + n])?;
+                n
+            } else {
+                let mut tmp = [0u8; BLOCK_SIZE];
+                self.driver.read_block(block, &mut tmp)?;
+                let n = (BLOCK_SIZE - block_offset).min(remain);
+                buf[done..done + n].copy_from_slice(&tmp[block_offset..block_offset + n]);
+                n
+            };
+            done += n;
+            self.offset += n as u64;
+        }
+        Ok(done)
+    }
+}
+
+impl<T: SDIo, S: SleepOps> Write for SdStream<T, S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = (self.size - self.offset).min(buf.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            let block = (self.offset / BLOCK_SIZE as u64) as usize;
+            let block_offset = (self.offset % BLOCK_SIZE as u64) as usize;
+            let remain = len - done;
+            let n = if block_offset == 0 && remain >= BLOCK_SIZE {
+                let n = remain / BLOCK_SIZE * BLOCK_SIZE;
+                self.driver.write_blocks(block, &buf[done..done + n])?;
+                n
+            } else {
+                let mut tmp = [0u8; BLOCK_SIZE];
+                self.driver.read_block(block, &mut tmp)?;
+                let n = (BLOCK_SIZE - block_offset).min(remain);
+                tmp[block_offset..block_offset + n].copy_from_slice(&buf[done..done + n]);
+                self.driver.write_block(block, &tmp)?;
+                n
+            };
+            done += n;
+            self.offset += n as u64;
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<T, S> Seek for SdStream<T, S> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let offset = match pos {
+            SeekFrom::Start(pos) => pos as i128,
+            SeekFrom::Current(pos) => self.offset as i128 + pos as i128,
+            SeekFrom::End(pos) => self.size as i128 + pos as i128,
+        };
+        if offset < 0 || offset > self.size as i128 {
+            return Err(SdStreamError::InvalidSeek);
+        }
+        self.offset = offset as u64;
+        Ok(self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimCard, SimSleep, SimulatedController};
+    use std::vec;
+
+    const IMAGE_SIZE: usize = 4 * 1024 * 1024;
+
+    fn stream() -> SdStream<SimulatedController, SimSleep> {
+        let controller = SimulatedController::new(SimCard::new(vec![0; IMAGE_SIZE]));
+        let mut driver = Vf2SdDriver::new(controller);
+        driver.init().unwrap();
+        SdStream::new(driver)
+    }
+
+    #[test]
+    fn test_stream_unaligned() {
+        let mut stream = stream();
+        let data: vec::Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        stream.seek(SeekFrom::Start(100)).unwrap();
+        stream.write_all(&data).unwrap();
+        let mut buf = vec![0u8; 3200];
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert!(buf[..100].iter().all(|&b| b == 0));
+        assert_eq!(&buf[100..3100], &data[..]);
+        assert!(buf[3100..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_stream_end() {
+        let mut stream = stream();
+        assert_eq!(stream.size(), IMAGE_SIZE as u64);
+        assert_eq!(
+            stream.seek(SeekFrom::End(-10)).unwrap(),
+            IMAGE_SIZE as u64 - 10
+        );
+        assert_eq!(stream.write(&[1; 20]).unwrap(), 10);
+        assert_eq!(stream.write_all(&[1; 20]), Err(SdStreamError::WriteZero));
+        let mut buf = [0u8; 20];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            stream.seek(SeekFrom::Current(1)),
+            Err(SdStreamError::InvalidSeek)
+        );
+        assert_eq!(
+            stream.seek(SeekFrom::End(-20)).unwrap(),
+            IMAGE_SIZE as u64 - 20
+        );
+        assert_eq!(stream.read(&mut buf).unwrap(), 20);
+        assert_eq!(&buf[10..], &[1; 10]);
+    }
+
+    #[test]
+    fn test_fatfs() {
+        let mut stream = stream();
+        fatfs::format_volume(&mut stream, fatfs::FormatVolumeOptions::new()).unwrap();
+        let fs = fatfs::FileSystem::new(stream, fatfs::FsOptions::new()).unwrap();
+        let data: vec::Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        {
+            let root = fs.root_dir();
+            let mut file = root.create_file("hello.txt").unwrap();
+            file.write_all(&data).unwrap();
+        }
+        let mut file = fs.root_dir().open_file("hello.txt").unwrap();
+        let mut buf = vec![0u8; data.len()];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+}
@@ -0,0 +1,515 @@
+//! Interrupt driven transfers
+//!
+//! [`Vf2SdDriver::enable_interrupts`] sets the global interrupt enable of the controller, but
+//! the interrupts are only unmasked while a transfer started by
+//! [`Vf2SdDriver::start_read_blocks`] or [`Vf2SdDriver::start_write_blocks`] is in flight.
+//! The interrupt handler advances the transfer with [`Vf2SdDriver::handle_interrupt`], and
+//! the result is collected with [`Vf2SdDriver::take_result`] once it returned `true`.
+//! All other methods keep polling with the interrupts masked, they fail with
+//! [`Vf2SdDriverError::Busy`] while [`Vf2SdDriver::is_busy`] until the transfer completed or
+//! [`Vf2SdDriver::abort_transfer`] stopped it. Only the card detect interrupt stays unmasked,
+//! its change is taken with [`Vf2SdDriver::take_card_event`].
+use crate::*;
+use core::ptr::NonNull;
+
+/// A block transfer advanced by [`Vf2SdDriver::handle_interrupt`]
+struct IrqTransfer {
+    cmd: Cmd,
+    buf: NonNull<u8>,
+    len: usize,
+    /// Bytes moved through the FIFO
+    offset: usize,
+}
+
+// The buffer is only accessed by the driver until the transfer completed, see the safety
+// contract of the start functions.
+unsafe impl Send for IrqTransfer {}
+
+impl IrqTransfer {
+    fn is_write(&self) -> bool {
+        matches!(self.cmd, Cmd::WriteSingleBlock | Cmd::WriteMultipleBlock)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct IrqState {
+    enabled: bool,
+    transfer: Option<IrqTransfer>,
+    /// The command and the result of the last completed transfer
+    result: Option<(Cmd, Result<usize>)>,
+}
+
+/// Interrupts unmasked during a transfer
+pub(crate) fn transfer_interrupts() -> u32 {
+    let ints = RawInterrupt::new()
+        .with_ebe(true)
+        .with_sbe(true)
+        .with_hle(true)
+        .with_frun(true)
+        .with_drto(true)
+        .with_rto(true)
+        .with_dcrc(true)
+        .with_rcrc(true)
+        .with_rxdr(true)
+        .with_txdr(true)
+        .with_dto(true)
+        .with_command_done(true)
+        .with_response_err(true);
+    u16::from(ints) as u32
+}
+
+/// Unmask the interrupts of the driver without a transfer in flight
+pub(crate) fn unmask_idle_interrupts<T: SDIo, S: SleepOps>(driver: &mut Vf2SdDriver<T, S>) {
+    if driver.irq.enabled && !driver.is_busy() {
+        let ints = driver.idle_interrupts();
+        write_reg(&mut driver.io, INT_MASK_REG, ints);
+    }
+}
+
+/// Mask the transfer interrupts, keeping the card detect interrupt as it is
+pub(crate) fn mask_transfer_interrupts<T: SDIo>(io: &mut T) {
+    let ints = read_reg(io, INT_MASK_REG) & INT_CARD_DETECT;
+    write_reg(io, INT_MASK_REG, ints);
+}
+
+/// Stop the data phase in flight with CMD12 and drop the data in the FIFO
+pub(crate) fn abort_data_transfer<T: SDIo, S: SleepOps>(io: &mut T) {
+    mask_transfer_interrupts(io);
+    if let Err(e) = stop_transmission::<_, S>(io) {
+        warn!("stop transmission failed: {:?}", e);
+    }
+    let ctrl = ControlReg::from(read_reg(io, CTRL_REG)).with_fifo_reset(true);
+    write_reg(io, CTRL_REG, ctrl.into());
+    S::sleep_ms_until(1, || !ControlReg::from(read_reg(io, CTRL_REG)).fifo_reset());
+    let status = read_reg(io, RAW_INT_STATUS_REG) & !INT_CARD_DETECT;
+    write_reg(io, RAW_INT_STATUS_REG, status);
+}
+
+/// Send the data command of a `len` bytes transfer at `addr` with the transfer interrupts unmasked
+pub(crate) fn start_transfer_cmd<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    write: bool,
+    addr: u32,
+    len: usize,
+) -> Result<Cmd> {
+    if len == 0 || len % BLOCK_SIZE != 0 {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
+    wait_ms_util_can_send_cmd::<_, S>(io)?;
+    wait_ms_util_can_send_data::<_, S>(io)?;
+    let cmd_type = match (write, len == BLOCK_SIZE) {
+        (false, true) => Cmd::ReadSingleBlock,
+        (false, false) => Cmd::ReadMultipleBlock,
+        (true, true) => Cmd::WriteSingleBlock,
+        (true, false) => Cmd::WriteMultipleBlock,
+    };
+    set_transaction_size(io, BLOCK_SIZE as u32, len as u32);
+    // drop the status of the previous commands
+    let status = read_reg(io, RAW_INT_STATUS_REG) & !INT_CARD_DETECT;
+    write_reg(io, RAW_INT_STATUS_REG, status);
+    let ints = read_reg(io, INT_MASK_REG) & INT_CARD_DETECT;
+    write_reg(io, INT_MASK_REG, ints | transfer_interrupts());
+    let cmd = CmdReg::from(cmd_type);
+    info!("start cmd type:{:?}, value:{:#?}", cmd_type, cmd);
+    write_reg(io, ARG_REG, addr);
+    write_reg(io, CMD_REG, cmd.into());
+    if let Err(e) = wait_ms_util_can_send_cmd::<_, S>(io) {
+        mask_transfer_interrupts(io);
+        return Err(e);
+    }
+    Ok(cmd_type)
+}
+
+/// Move the data of the transfer of `cmd` and acknowledge the `pending` interrupts
+///
+/// Returns the result once the transfer ended.
+pub(crate) fn advance_transfer<T: SDIo>(
+    io: &mut T,
+    cmd: Cmd,
+    data: &mut DataTransType,
+    offset: &mut usize,
+    pending: u32,
+) -> Option<Result<usize>> {
+    let mut ints = RawInterrupt::from(pending as u16);
+    let len = match data {
+        DataTransType::Write(buf) => {
+            if ints.txdr() {
+                fill_fifo(io, buf, offset);
+            }
+            buf.len()
+        }
+        DataTransType::Read(buf) => {
+            if ints.rxdr() || ints.dto() {
+                drain_fifo(io, buf, offset);
+            }
+            buf.len()
+        }
+        _ => 0,
+    };
+    // Clear interrupt by writing 1
+    write_reg(io, RAW_INT_STATUS_REG, pending);
+    let status = read_reg(io, RESP0_REG);
+    if ints.have_error() {
+        error!("card has error {:#?}", ints);
+        Some(Err(interrupt_error(cmd, &mut ints)))
+    } else if ints.command_done() && status & CARD_STATUS_ERROR_MASK != 0 {
+        error!("cmd {:?} card status {:#x}", cmd, status);
+        Some(Err(Vf2SdDriverError::CardStatus(cmd, status)))
+    } else if ints.dto() {
+        info!("buf_offset:{}, transfer done", offset);
+        Some(Ok(len))
+    } else {
+        None
+    }
+}
+
+impl<T: SDIo, S: SleepOps> Vf2SdDriver<T, S> {
+    /// Raise the interrupt line of the controller for the transfers started by
+    /// [`Vf2SdDriver::start_read_blocks`] and [`Vf2SdDriver::start_write_blocks`]
+    ///
+    /// A transfer already in flight keeps its interrupts unmasked and completes through
+    /// [`Vf2SdDriver::handle_interrupt`].
+    pub fn enable_interrupts(&mut self) {
+        let mut ints = self.idle_interrupts();
+        if self.is_busy() {
+            ints |= transfer_interrupts();
+        }
+        write_reg(&mut self.io, INT_MASK_REG, ints);
+        let ctrl = ControlReg::from(read_reg(&self.io, CTRL_REG)).with_int_enable(true);
+        write_reg(&mut self.io, CTRL_REG, ctrl.into());
+        self.irq.enabled = true;
+    }
+    pub fn disable_interrupts(&mut self) {
+        let ctrl = ControlReg::from(read_reg(&self.io, CTRL_REG)).with_int_enable(false);
+        write_reg(&mut self.io, CTRL_REG, ctrl.into());
+        self.irq.enabled = false;
+    }
+    pub fn interrupts_enabled(&self) -> bool {
+        self.irq.enabled
+    }
+    /// Interrupts unmasked without a transfer in flight
+    pub(crate) fn idle_interrupts(&self) -> u32 {
+        if self.card_detect {
+            INT_CARD_DETECT
+        } else {
+            0
+        }
+    }
+    /// A transfer is in flight, its result is not available yet
+    pub fn is_busy(&self) -> bool {
+        self.irq.transfer.is_some()
+    }
+    /// Fail with [`Vf2SdDriverError::Busy`] while a transfer is in flight
+    pub(crate) fn check_idle(&self) -> Result<()> {
+        if self.is_busy() {
+            return Err(Vf2SdDriverError::Busy);
+        }
+        Ok(())
+    }
+    /// Start reading `buf.len() / BLOCK_SIZE` blocks starting at `block`
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid and must not be accessed until [`Vf2SdDriver::take_result`]
+    /// returned the result of the transfer or [`Vf2SdDriver::abort_transfer`] stopped it.
+    pub unsafe fn start_read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len();
+        self.start_transfer(block, NonNull::from(buf).cast(), len, false)
+    }
+    /// Start writing `buf.len() / BLOCK_SIZE` blocks starting at `block`
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid and must not be modified until [`Vf2SdDriver::take_result`]
+    /// returned the result of the transfer or [`Vf2SdDriver::abort_transfer`] stopped it.
+    pub unsafe fn start_write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<()> {
+        self.start_transfer(block, NonNull::from(buf).cast(), buf.len(), true)
+    }
+    fn start_transfer(
+        &mut self,
+        block: usize,
+        buf: NonNull<u8>,
+        len: usize,
+        write: bool,
+    ) -> Result<()> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        self.irq.result = None;
+        let cmd = start_transfer_cmd::<_, S>(&mut self.io, write, addr, len)?;
+        self.irq.transfer = Some(IrqTransfer {
+            cmd,
+            buf,
+            len,
+            offset: 0,
+        });
+        Ok(())
+    }
+    /// Advance the transfer in flight, to be called by the interrupt handler of the controller
+    ///
+    /// Returns `true` when the transfer completed and its result can be taken with
+    /// [`Vf2SdDriver::take_result`]. A card change is acknowledged and kept for
+    /// [`Vf2SdDriver::take_card_event`].
+    pub fn handle_interrupt(&mut self) -> bool {
+        if read_reg(&self.io, MASKED_INT_STATUS_REG) & INT_CARD_DETECT != 0 {
+            self.update_card_detect();
+        }
+        let io = &mut self.io;
+        let Some(transfer) = self.irq.transfer.as_mut() else {
+            return false;
+        };
+        let pending = read_reg(io, MASKED_INT_STATUS_REG) & transfer_interrupts();
+        if pending == 0 {
+            return false;
+        }
+        // the buffer is valid until the transfer completed, by the contract of the start functions
+        let mut data = unsafe {
+            if transfer.is_write() {
+                DataTransType::Write(core::slice::from_raw_parts(
+                    transfer.buf.as_ptr(),
+                    transfer.len,
+                ))
+            } else {
+                DataTransType::Read(core::slice::from_raw_parts_mut(
+                    transfer.buf.as_ptr(),
+                    transfer.len,
+                ))
+            }
+        };
+        let Some(result) =
+            advance_transfer(io, transfer.cmd, &mut data, &mut transfer.offset, pending)
+        else {
+            return false;
+        };
+        mask_transfer_interrupts(io);
+        self.irq.result = Some((transfer.cmd, result));
+        self.irq.transfer = None;
+        true
+    }
+    /// Stop the transfer in flight with CMD12, returning whether there was one
+    ///
+    /// The buffer of the transfer is released, the data moved so far is undefined.
+    pub fn abort_transfer(&mut self) -> bool {
+        let Some(transfer) = self.irq.transfer.take() else {
+            return false;
+        };
+        warn!("abort {:?} at offset {}", transfer.cmd, transfer.offset);
+        abort_data_transfer::<_, S>(&mut self.io);
+        unmask_idle_interrupts(self);
+        true
+    }
+    /// The result of the last completed transfer
+    ///
+    /// After a write this waits until the card stored the data, after a failed multiple block
+    /// transfer the card is brought back to the transfer state.
+    pub fn take_result(&mut self) -> Option<Result<usize>> {
+        let (cmd, result) = self.irq.result.take()?;
+        Some(self.finish_transfer(cmd, result))
+    }
+    fn finish_transfer(&mut self, cmd: Cmd, result: Result<usize>) -> Result<usize> {
+        match result {
+            Ok(len) if matches!(cmd, Cmd::WriteSingleBlock | Cmd::WriteMultipleBlock) => {
+                self.wait_programmed(len).map(|_| len)
+            }
+            Err(e) if matches!(cmd, Cmd::ReadMultipleBlock | Cmd::WriteMultipleBlock) => {
+                let _ = stop_transmission::<_, S>(&mut self.io);
+                Err(e)
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sim::{Fault, FaultPlan, SimCard, SimSleep, SimulatedController};
+
+    /// An initialized driver with the interrupts enabled, block `n` of the card is filled with `n`
+    pub(crate) fn driver_with(
+        card: impl FnOnce(SimCard) -> SimCard,
+    ) -> Vf2SdDriver<SimulatedController, SimSleep> {
+        let image = (0..1024 * 1024).map(|i| (i / BLOCK_SIZE) as u8).collect();
+        let mut driver = Vf2SdDriver::new(SimulatedController::new(card(SimCard::new(image))));
+        driver.init().unwrap();
+        driver.enable_interrupts();
+        driver
+    }
+
+    pub(crate) fn driver() -> Vf2SdDriver<SimulatedController, SimSleep> {
+        driver_with(|card| card)
+    }
+
+    /// Run the interrupt handler while the interrupt line is raised
+    fn run(driver: &mut Vf2SdDriver<SimulatedController, SimSleep>) -> Result<usize> {
+        for _ in 0..1000 {
+            if driver.io.interrupt_pending() && driver.handle_interrupt() {
+                assert!(!driver.is_busy());
+                assert!(!driver.io.interrupt_pending());
+                return driver.take_result().unwrap();
+            }
+        }
+        panic!("transfer did not complete");
+    }
+
+    #[test]
+    fn test_irq_read() {
+        let mut driver = driver();
+        assert!(driver.interrupts_enabled());
+        assert!(!driver.handle_interrupt());
+        let mut buf = [0u8; BLOCK_SIZE * 3];
+        unsafe { driver.start_read_blocks(2, &mut buf) }.unwrap();
+        assert!(driver.is_busy());
+        assert_eq!(
+            unsafe { driver.start_read_blocks(2, &mut [0; BLOCK_SIZE]) },
+            Err(Vf2SdDriverError::Busy)
+        );
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE * 3));
+        for (i, block) in buf.chunks(BLOCK_SIZE).enumerate() {
+            assert!(block.iter().all(|&b| b == i as u8 + 2));
+        }
+        assert_eq!(driver.take_result(), None);
+        // the blocking API still works with the interrupts enabled
+        let mut block = [0u8; BLOCK_SIZE];
+        driver.read_block(7, &mut block).unwrap();
+        assert!(block.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn test_irq_write() {
+        let mut driver = driver();
+        let data = [0x5a; BLOCK_SIZE * 2];
+        unsafe { driver.start_write_blocks(4, &data) }.unwrap();
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE * 2));
+        assert!(driver.status().unwrap().is_transfer_ready());
+        unsafe { driver.start_write_blocks(9, &data[..BLOCK_SIZE]) }.unwrap();
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE));
+        let mut buf = [0u8; BLOCK_SIZE * 7];
+        driver.read_blocks(4, &mut buf).unwrap();
+        assert!(buf[..BLOCK_SIZE * 2].iter().all(|&b| b == 0x5a));
+        assert!(buf[BLOCK_SIZE * 2..BLOCK_SIZE * 5]
+            .iter()
+            .all(|&b| b != 0x5a));
+        assert!(buf[BLOCK_SIZE * 5..BLOCK_SIZE * 6]
+            .iter()
+            .all(|&b| b == 0x5a));
+    }
+
+    #[test]
+    fn test_irq_errors() {
+        let mut driver = driver();
+        let mut buf = [0u8; BLOCK_SIZE * 2];
+        assert_eq!(
+            unsafe { driver.start_read_blocks(0, &mut buf[..100]) },
+            Err(Vf2SdDriverError::BufferSizeError)
+        );
+        driver.io.set_fault_plan(
+            FaultPlan::new()
+                .on_cmd(17, Fault::ResponseTimeout)
+                .on_cmd(18, Fault::DataCrc),
+        );
+        unsafe { driver.start_read_blocks(0, &mut buf[..BLOCK_SIZE]) }.unwrap();
+        assert_eq!(
+            run(&mut driver),
+            Err(Vf2SdDriverError::ResponseTimeout(Cmd::ReadSingleBlock))
+        );
+        unsafe { driver.start_read_blocks(0, &mut buf) }.unwrap();
+        assert_eq!(
+            run(&mut driver),
+            Err(Vf2SdDriverError::DataCrc(Cmd::ReadMultipleBlock))
+        );
+        // out of range blocks are refused by the card
+        unsafe { driver.start_read_blocks(4096, &mut buf) }.unwrap();
+        assert_eq!(
+            run(&mut driver),
+            Err(Vf2SdDriverError::DataReadTimeout(Cmd::ReadMultipleBlock))
+        );
+        assert!(driver.status().unwrap().is_transfer_ready());
+        unsafe { driver.start_read_blocks(1, &mut buf) }.unwrap();
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE * 2));
+    }
+
+    #[test]
+    fn test_irq_busy_abort() {
+        let mut driver = driver();
+        assert!(!driver.abort_transfer());
+        let mut buf = [0u8; BLOCK_SIZE * 2];
+        unsafe { driver.start_read_blocks(2, &mut buf) }.unwrap();
+        // a card event taken meanwhile keeps the transfer interrupts unmasked
+        assert_eq!(driver.take_card_event(), None);
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE * 2));
+
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(18, Fault::DataStall));
+        unsafe { driver.start_read_blocks(2, &mut buf) }.unwrap();
+        let mut block = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            driver.read_block(0, &mut block),
+            Err(Vf2SdDriverError::Busy)
+        );
+        assert_eq!(driver.status(), Err(Vf2SdDriverError::Busy));
+        assert_eq!(
+            driver.set_clock(IDENT_CLOCK_HZ),
+            Err(Vf2SdDriverError::Busy)
+        );
+        assert!(matches!(driver.init(), Err(Vf2SdDriverError::Busy)));
+        assert!(driver.abort_transfer());
+        assert!(!driver.is_busy());
+        assert!(!driver.io.interrupt_pending());
+        assert_eq!(driver.take_result(), None);
+        assert!(driver.status().unwrap().is_transfer_ready());
+        unsafe { driver.start_read_blocks(5, &mut buf) }.unwrap();
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE * 2));
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 5));
+    }
+
+    #[test]
+    fn test_irq_disabled() {
+        let mut driver = driver();
+        driver.disable_interrupts();
+        assert!(!driver.interrupts_enabled());
+        let mut buf = [0u8; BLOCK_SIZE];
+        unsafe { driver.start_read_blocks(3, &mut buf) }.unwrap();
+        // the line stays low, the handler can still be polled
+        assert!(!driver.io.interrupt_pending());
+        assert!(driver.handle_interrupt());
+        assert_eq!(driver.take_result(), Some(Ok(BLOCK_SIZE)));
+        assert!(buf.iter().all(|&b| b == 3));
+    }
+
+    #[test]
+    fn test_irq_enable_during_transfer() {
+        let mut driver = driver();
+        driver.disable_interrupts();
+        let mut buf = [0u8; 2 * BLOCK_SIZE];
+        unsafe { driver.start_read_blocks(5, &mut buf) }.unwrap();
+        driver.enable_interrupts();
+        assert_eq!(run(&mut driver), Ok(2 * BLOCK_SIZE));
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 5));
+        assert!(buf[BLOCK_SIZE..].iter().all(|&b| b == 6));
+    }
+
+    #[test]
+    fn test_irq_card_detect() {
+        let mut driver = driver();
+        assert!(!driver.io.interrupt_pending());
+        let card = driver.io.remove_card().unwrap();
+        assert!(driver.io.interrupt_pending());
+        assert!(!driver.handle_interrupt());
+        assert!(!driver.io.interrupt_pending());
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            unsafe { driver.start_read_blocks(0, &mut buf) },
+            Err(Vf2SdDriverError::NoCard)
+        );
+        assert_eq!(driver.take_card_event(), Some(CardEvent::Removed));
+        driver.io.insert_card(card);
+        assert!(driver.io.interrupt_pending());
+        assert!(!driver.handle_interrupt());
+        assert_eq!(driver.take_card_event(), Some(CardEvent::Inserted));
+        driver.init().unwrap();
+        unsafe { driver.start_read_blocks(3, &mut buf).unwrap() };
+        assert_eq!(run(&mut driver), Ok(BLOCK_SIZE));
+        assert!(buf.iter().all(|&b| b == 3));
+    }
+}
@@ -1,8 +1,9 @@
 #![no_std]
 #[cfg(feature = "alloc")]
 extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
 
-use crate::cmd::*;
 use crate::register::*;
 use crate::utils::*;
 use core::fmt::{Display, Formatter};
@@ -10,37 +11,81 @@ use core::mem::size_of;
 use log::*;
 use preprint::pprintln;
 
-pub use utils::{SDIo, SleepOps};
+#[cfg(feature = "async")]
+pub use async_io::SdIrq;
+pub use card::{CardEvent, CardInfo, HwPartition};
+pub use cmd::{Cmd, DataDir, RawCmd, ResponseType};
+pub use dma::{DmaSegment, IdmacDesc, IdmacRing};
+#[cfg(feature = "fatfs")]
+pub use fatfs_io::{SdStream, SdStreamError};
+pub use partition::{Guid, Partition, PartitionTable, PartitionType, PartitionView};
+pub use register::{CardState, CardStatus, Cid, Csd, ExtCsd, Scr};
+pub use response::{NoResponse, Response, R1, R2, R3, R6, R7};
+#[cfg(feature = "rpmb")]
+pub use rpmb::{RpmbKey, RPMB_DATA_SIZE};
+#[cfg(feature = "embedded-sdmmc")]
+pub use sdmmc::SdmmcBlockDevice;
+pub use utils::{MmioSdIo, SDIo, SdioInstance, SleepOps};
 
+#[cfg(feature = "async")]
+mod async_io;
+mod card;
 mod cmd;
+mod dma;
+#[cfg(feature = "fatfs")]
+mod fatfs_io;
+mod irq;
+mod partition;
 mod register;
+mod response;
+#[cfg(feature = "rpmb")]
+mod rpmb;
+#[cfg(feature = "embedded-sdmmc")]
+mod sdmmc;
+#[cfg(any(test, feature = "std"))]
+pub mod sim;
 mod utils;
 
 enum DataTransType<'a> {
     None,
     Read(&'a mut [u8]),
     Write(&'a [u8]),
+    /// The IDMAC moves the given number of bytes
+    Dma(usize),
 }
 
-fn wait_ms_util_can_send_cmd<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
+fn wait_ms_util_can_send_cmd<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
     let f = || {
         let cmd_reg = CmdReg::from(read_reg(io, CMD_REG));
         !cmd_reg.start_cmd()
     };
     S::sleep_ms_until(1, f);
-    f()
+    if f() {
+        Ok(())
+    } else {
+        Err(Vf2SdDriverError::TimeoutError)
+    }
+}
+
+fn wait_ms_util_can_send_data<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
+    wait_ms_util_not_busy::<_, S>(io, 100)
 }
 
-fn wait_ms_util_can_send_data<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
+/// Wait until the card releases DAT0, e.g. after a R1b command
+fn wait_ms_util_not_busy<T: SDIo, S: SleepOps>(io: &mut T, ms: usize) -> Result<()> {
     let f = || {
         let status_reg = StatusReg::from(read_reg(io, STATUS_REG));
         !status_reg.data_busy()
     };
-    S::sleep_ms_until(100, f);
-    f()
+    S::sleep_ms_until(ms, f);
+    if f() {
+        Ok(())
+    } else {
+        Err(Vf2SdDriverError::TimeoutError)
+    }
 }
 
-fn wait_ms_util_response<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
+fn wait_ms_util_response<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
     let f = || {
         let raw_int_status_reg = RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
         let int = raw_int_status_reg.int_status();
@@ -48,7 +93,11 @@ fn wait_ms_util_response<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
         raw_int_status.command_done()
     };
     S::sleep_ms_until(1, f);
-    f()
+    if f() {
+        Ok(())
+    } else {
+        Err(Vf2SdDriverError::TimeoutError)
+    }
 }
 
 fn fifo_filled_cnt<T: SDIo>(io: &mut T) -> usize {
@@ -56,56 +105,77 @@ fn fifo_filled_cnt<T: SDIo>(io: &mut T) -> usize {
     status.fifo_count() as usize
 }
 
+/// Move the data in the FIFO to `buffer` starting at `offset`
+fn drain_fifo<T: SDIo>(io: &mut T, buffer: &mut [u8], offset: &mut usize) {
+    // every address of the FIFO window accesses the same FIFO
+    while fifo_filled_cnt(io) >= 2 && *offset < buffer.len() {
+        let data = read_fifo(io, FIFO_DATA_REG);
+        for i in 0..size_of::<u64>() {
+            buffer[*offset] = (data >> (i * 8)) as u8;
+            *offset += 1;
+        }
+    }
+}
+
+/// Fill the FIFO from `buffer` starting at `offset`
+fn fill_fifo<T: SDIo>(io: &mut T, buffer: &[u8], offset: &mut usize) {
+    // Hard coded FIFO depth
+    while fifo_filled_cnt(io) < 120 && *offset < buffer.len() {
+        let mut data: u64 = 0;
+        for i in 0..8 {
+            data |= (buffer[*offset] as u64) << (i * 8);
+            *offset += 1;
+        }
+        write_fifo(io, FIFO_DATA_REG, data);
+    }
+}
+
 fn send_cmd<T: SDIo, S: SleepOps>(
     io: &mut T,
     cmd_type: Cmd,
     cmd: CmdReg,
     arg: CmdArg,
     data_trans_type: DataTransType,
-) -> Option<[u32; 4]> {
-    let res = wait_ms_util_can_send_cmd::<_, S>(io);
-    assert!(res);
+) -> Result<[u32; 4]> {
+    if cmd.data_expected() && matches!(data_trans_type, DataTransType::None) {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
+    wait_ms_util_can_send_cmd::<_, S>(io)?;
     if cmd.data_expected() {
-        let res = wait_ms_util_can_send_data::<_, S>(io);
-        assert!(res)
+        wait_ms_util_can_send_data::<_, S>(io)?;
     }
     info!("send cmd type:{:?}, value:{:#?}", cmd_type, cmd);
     // write arg
     write_reg(io, ARG_REG, arg.into());
     write_reg(io, CMD_REG, cmd.into());
     // Wait for cmd accepted
-    let command_accept = wait_ms_util_can_send_cmd::<_, S>(io);
-    info!("command accepted {}", command_accept);
+    wait_ms_util_can_send_cmd::<_, S>(io)?;
+    info!("command accepted");
 
-    if cmd.response_expect() {
-        let res = wait_ms_util_response::<_, S>(io);
-        debug!("wait_ms_util_response:{:?}", res);
+    // update clock command does not generate command done
+    if !cmd.update_clock_registers_only() {
+        wait_ms_util_response::<_, S>(io)?;
     }
 
+    let mut data_over = true;
     if cmd.data_expected() {
-        let mut fifo_addr = FIFO_DATA_REG;
         match data_trans_type {
             DataTransType::Read(buffer) => {
                 trace!("data_expected read....");
                 let mut buf_offset = 0;
-                S::sleep_ms_until(250, || {
+                S::sleep_ms_until(data_timeout_ms(buffer.len()), || {
                     let raw_int_status_reg =
                         RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
                     let int = raw_int_status_reg.int_status();
                     let mut raw_int_status = RawInterrupt::from(int);
-                    if raw_int_status.rxdr() {
+                    // the tail of the data below the RX watermark only comes with DTO
+                    if raw_int_status.rxdr() || raw_int_status.dto() {
                         debug!("RXDR....");
-                        while fifo_filled_cnt(io) >= 2 {
-                            let data = read_fifo(io, fifo_addr);
-                            for i in 0..8 {
-                                buffer[buf_offset] = (data >> (i * 8)) as u8;
-                                buf_offset += 1;
-                            }
-                            fifo_addr += size_of::<u64>();
-                        }
+                        drain_fifo(io, buffer, &mut buf_offset);
                     }
                     raw_int_status.dto() || raw_int_status.have_error()
                 });
+                data_over = is_data_over(io);
                 info!(
                     "buf_offset:{}, receive {} bytes",
                     buf_offset,
@@ -114,35 +184,34 @@ fn send_cmd<T: SDIo, S: SleepOps>(
             }
             DataTransType::Write(buffer) => {
                 let mut buf_offset = 0;
-                S::sleep_ms_until(250, || {
+                S::sleep_ms_until(data_timeout_ms(buffer.len()), || {
                     let raw_int_status = read_reg(io, RAW_INT_STATUS_REG);
                     let mut raw_int_status = RawInterrupt::from(raw_int_status as u16);
                     if raw_int_status.txdr() {
                         debug!("TXDR....");
-                        // Hard coded FIFO depth
-                        while fifo_filled_cnt(io) < 120 && buf_offset < buffer.len() {
-                            let mut data: u64 = 0;
-                            for i in 0..8 {
-                                data |= (buffer[buf_offset] as u64) << (i * 8);
-                                buf_offset += 1;
-                            }
-                            write_fifo(io, fifo_addr, data);
-                            fifo_addr += size_of::<u64>();
-                        }
+                        fill_fifo(io, buffer, &mut buf_offset);
                     }
                     raw_int_status.dto() || raw_int_status.have_error()
                 });
+                data_over = is_data_over(io);
                 info!("buf_offset:{}, send {} bytes", buf_offset, buf_offset * 8);
             }
-            _ => {
-                panic!("Not implemented")
+            DataTransType::Dma(len) => {
+                // the data phase stalls if the IDMAC stopped on a bus error
+                S::sleep_ms_until(data_timeout_ms(len), || {
+                    is_data_over(io) || IdmacStatusReg::from(read_reg(io, IDSTS_REG)).have_error()
+                });
+                data_over = is_data_over(io);
+                info!("dma transfer {} bytes", len);
             }
+            // rejected before the command was sent
+            DataTransType::None => {}
         }
         debug!("Current FIFO count: {}", fifo_filled_cnt(io));
     }
-    // Clear interrupt by writing 1
+    // Clear interrupt by writing 1, the card detect is left to Vf2SdDriver::take_card_event
     let raw_int_status = read_reg(io, RAW_INT_STATUS_REG);
-    write_reg(io, RAW_INT_STATUS_REG, raw_int_status);
+    write_reg(io, RAW_INT_STATUS_REG, raw_int_status & !INT_CARD_DETECT);
     // check error
     let raw_int_status = RawInterruptStatusReg::from(raw_int_status);
     let mut raw_int_status = RawInterrupt::from(raw_int_status.int_status());
@@ -156,32 +225,122 @@ fn send_cmd<T: SDIo, S: SleepOps>(
         error!("card has error {:#?}", raw_int_status);
         error!("cmd {:#?}", cmd);
         error!("resp {:x?}", resp[0]);
-        return None;
+        return Err(interrupt_error(cmd_type, &mut raw_int_status));
+    }
+    if !data_over {
+        error!("data transfer of cmd {:?} not finished", cmd_type);
+        return Err(Vf2SdDriverError::TimeoutError);
+    }
+    if cmd_type.response_is_card_status() && resp[0] & CARD_STATUS_ERROR_MASK != 0 {
+        error!("cmd {:?} card status {:#x}", cmd_type, resp[0]);
+        return Err(Vf2SdDriverError::CardStatus(cmd_type, resp[0]));
+    }
+    Ok(resp)
+}
+
+/// Send `cmd` without data and decode its response as `R`
+fn send_cmd_typed<T: SDIo, S: SleepOps, R: Response>(io: &mut T, cmd: Cmd, arg: u32) -> Result<R> {
+    if !R::matches(cmd.response_type()) || cmd.data_dir() != DataDir::None {
+        error!("{:?} is not answered with the requested response", cmd);
+        return Err(Vf2SdDriverError::InvalidResponse(cmd));
+    }
+    let resp = send_cmd::<_, S>(
+        io,
+        cmd,
+        CmdReg::from(cmd),
+        CmdArg::new(arg),
+        DataTransType::None,
+    )?;
+    R::decode(cmd, arg, resp)
+}
+
+/// Pick the most significant error bit of the raw interrupt status
+fn interrupt_error(cmd: Cmd, raw_int_status: &mut RawInterrupt) -> Vf2SdDriverError {
+    if raw_int_status.rto() {
+        Vf2SdDriverError::ResponseTimeout(cmd)
+    } else if raw_int_status.rcrc() {
+        Vf2SdDriverError::ResponseCrc(cmd)
+    } else if raw_int_status.response_err() {
+        Vf2SdDriverError::ResponseError(cmd)
+    } else if raw_int_status.drto() {
+        Vf2SdDriverError::DataReadTimeout(cmd)
+    } else if raw_int_status.dcrc() {
+        Vf2SdDriverError::DataCrc(cmd)
+    } else if raw_int_status.sbe() {
+        Vf2SdDriverError::StartBitError(cmd)
+    } else if raw_int_status.ebe() {
+        Vf2SdDriverError::EndBitError(cmd)
+    } else if raw_int_status.frun() {
+        Vf2SdDriverError::FifoOverrun(cmd)
+    } else if raw_int_status.hle() {
+        Vf2SdDriverError::HardwareLocked(cmd)
+    } else {
+        Vf2SdDriverError::UnknownError
     }
-    Some(resp)
 }
 
-fn reset_clock<T: SDIo, S: SleepOps>(io: &mut T) {
+/// Time allowed for the data phase of one block
+const BLOCK_TIMEOUT_MS: usize = 250;
+
+fn data_timeout_ms(len: usize) -> usize {
+    BLOCK_TIMEOUT_MS * len.div_ceil(BLOCK_SIZE).max(1)
+}
+
+fn is_data_over<T: SDIo>(io: &mut T) -> bool {
+    let raw_int_status_reg = RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
+    let mut raw_int_status = RawInterrupt::from(raw_int_status_reg.int_status());
+    raw_int_status.dto() || raw_int_status.have_error()
+}
+
+/// Input clock of the card interface unit if not given by [`Vf2SdDriver::with_input_clock`]
+pub const DEFAULT_INPUT_CLOCK_HZ: usize = 50_000_000;
+/// Card clock during identification
+pub const IDENT_CLOCK_HZ: usize = 400_000;
+/// Card clock in default speed mode
+pub const DEFAULT_SPEED_CLOCK_HZ: usize = 25_000_000;
+/// Card clock in high speed mode
+pub const HIGH_SPEED_CLOCK_HZ: usize = 50_000_000;
+/// Card clock of MMC in HS26 mode
+pub const MMC_HS26_CLOCK_HZ: usize = 26_000_000;
+/// Card clock of MMC in HS52 mode
+pub const MMC_HS52_CLOCK_HZ: usize = 52_000_000;
+
+/// The divider producing the fastest card clock not above `hz`
+///
+/// The card clock is `input_hz / (2 * divider)`, 0 bypasses the divider.
+fn clock_divider(input_hz: usize, hz: usize) -> u8 {
+    if hz >= input_hz {
+        return 0;
+    }
+    input_hz.div_ceil(2 * hz.max(1)).min(u8::MAX as usize) as u8
+}
+
+fn clock_rate(input_hz: usize, divider: u8) -> usize {
+    if divider == 0 {
+        input_hz
+    } else {
+        input_hz / (2 * divider as usize)
+    }
+}
+
+/// Change the card clock to at most `hz`, returning the actual frequency
+fn set_clock<T: SDIo, S: SleepOps>(io: &mut T, input_hz: usize, hz: usize) -> Result<usize> {
     // disable clock
     let mut clock_enable = ClockEnableReg::from(0);
     // write to CLOCK_ENABLE_REG
     write_reg(io, CLOCK_ENABLE_REG, clock_enable.into());
     // send reset clock command
-    let clock_cmd = CmdReg::from(0)
-        .with_start_cmd(true)
-        .with_wait_prvdata_complete(true)
-        .with_update_clock_registers_only(true);
+    let clock_cmd = CmdReg::from(Cmd::ResetClock);
     send_cmd::<_, S>(
         io,
         Cmd::ResetClock,
         clock_cmd,
         CmdArg::new(0),
         DataTransType::None,
-    );
-    // set clock divider to 400kHz (low)
-    let clock_divider = ClockDividerReg::new().with_clk_divider0(4);
+    )?;
+    let divider = clock_divider(input_hz, hz);
+    let clock_divider = ClockDividerReg::new().with_clk_divider0(divider);
     write_reg(io, CLK_DIVIDER_REG, clock_divider.into());
-    // send_cmd(Cmd::ResetClock,clock_disable_cmd,CmdArg::new(0));
     // enable clock
     clock_enable.set_clk_enable(1);
     write_reg(io, CLOCK_ENABLE_REG, clock_enable.into());
@@ -192,12 +351,31 @@ fn reset_clock<T: SDIo, S: SleepOps>(io: &mut T) {
         clock_cmd,
         CmdArg::new(0),
         DataTransType::None,
-    );
+    )?;
     info!(
         "now clk enable {:#?}",
         ClockEnableReg::from(read_reg(io, CLOCK_ENABLE_REG))
     );
+    let rate = clock_rate(input_hz, divider);
+    pprintln!("card clock: {}Hz", rate);
+    Ok(rate)
+}
+
+/// Time the card detect input has to be stable before a change is reported
+pub const CARD_DETECT_DEBOUNCE_MS: usize = 25;
+/// Card detect (CD) bit of the interrupt registers
+const INT_CARD_DETECT: u32 = 1 << 0;
+
+fn set_debounce<T: SDIo>(io: &mut T, input_hz: usize) {
+    // DEBNCE counts 24 bits of the input clock
+    let count = (input_hz / 1000 * CARD_DETECT_DEBOUNCE_MS).min(0xff_ffff);
+    write_reg(io, DEBNCE_REG, count as u32);
+}
+
+fn reset_clock<T: SDIo, S: SleepOps>(io: &mut T, input_hz: usize) -> Result<()> {
+    set_clock::<_, S>(io, input_hz, IDENT_CLOCK_HZ)?;
     pprintln!("reset clock success");
+    Ok(())
 }
 
 fn reset_fifo<T: SDIo>(io: &mut T) {
@@ -228,7 +406,7 @@ fn set_transaction_size<T: SDIo>(io: &mut T, blk_size: u32, byte_count: u32) {
     write_reg(io, BYTE_CNT_REG, byte_count.into());
 }
 
-fn test_read<T: SDIo, S: SleepOps>(io: &mut T) {
+fn test_read<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
     pprintln!("test read, try read 0 block");
     set_transaction_size(io, 512, 512);
     let cmd17 = CmdReg::from(Cmd::ReadSingleBlock);
@@ -240,16 +418,16 @@ fn test_read<T: SDIo, S: SleepOps>(io: &mut T) {
         cmd17,
         arg,
         DataTransType::Read(&mut buffer),
-    )
-    .unwrap();
+    )?;
     info!("Current FIFO count: {}", fifo_filled_cnt(io));
     let byte_slice = buffer.as_slice();
     pprintln!("sd header 16bytes: {:x?}", &byte_slice[..2]);
+    Ok(())
 }
 
 /// for test driver
 #[allow(unused)]
-fn test_write_read<T: SDIo, S: SleepOps>(io: &mut T) {
+fn test_write_read<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
     set_transaction_size(io, 512, 512);
     // write a block data
     let cmd24 = CmdReg::from(Cmd::WriteSingleBlock);
@@ -262,8 +440,7 @@ fn test_write_read<T: SDIo, S: SleepOps>(io: &mut T) {
         cmd24,
         arg,
         DataTransType::Write(&buffer),
-    )
-    .unwrap();
+    )?;
     // info!("resp csr: {:#?}",resp[0]); //csr reg
     info!("Current FIFO count: {}", fifo_filled_cnt(io));
     // read a block data
@@ -276,141 +453,217 @@ fn test_write_read<T: SDIo, S: SleepOps>(io: &mut T) {
         cmd17,
         arg,
         DataTransType::Read(&mut buffer),
-    )
-    .unwrap();
+    )?;
     // info!("resp csr: {:#?}",resp[0]); //csr reg
     info!("Current FIFO count: {}", fifo_filled_cnt(io));
     let byte_slice = buffer.as_slice();
     debug!("Head 16 bytes: {:#x?}", &byte_slice[..2]);
+    Ok(())
+}
+
+fn app_cmd<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<()> {
+    send_cmd_typed::<_, S, R1>(io, Cmd::AppCmd, rca << 16)?;
+    Ok(())
 }
 
-// send acmd51 to read csr reg
-fn check_bus_width<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> usize {
-    let cmd55 = CmdReg::from(Cmd::AppCmd);
-    let cmd_arg = CmdArg::new(rca << 16);
-    let _resp = send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, cmd_arg, DataTransType::None).unwrap();
+// send acmd51 to read scr reg
+fn check_scr<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<Scr> {
+    app_cmd::<_, S>(io, rca)?;
     // send acmd51
     // 1. set transact size
     set_transaction_size(io, 8, 8);
     // 2. send command
     let acmd51 = CmdReg::from(Cmd::SendScr);
-    let mut buffer: [u8; 512] = [0; 512]; // 512B
+    let mut buffer: [u8; 8] = [0; 8];
     send_cmd::<_, S>(
         io,
         Cmd::SendScr,
         acmd51,
         CmdArg::new(0),
         DataTransType::Read(&mut buffer),
-    );
-    info!("Current FIFO count: {}", fifo_filled_cnt(io)); //2
-    let resp = u64::from_be(read_fifo(io, FIFO_DATA_REG));
-    pprintln!("Bus width supported: {:b}", (resp >> 48) & 0xF);
+    )?;
     info!("Current FIFO count: {}", fifo_filled_cnt(io)); //0
-    0
+    let scr = Scr::new(u64::from_be_bytes(buffer));
+    pprintln!("Bus width supported: {:b}", scr.bus_widths());
+    Ok(scr)
 }
 
-fn check_csd<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) {
-    let cmd = CmdReg::from(Cmd::SendCsd);
-    let resp = send_cmd::<_, S>(
+// send acmd6 to switch the bus width of both the card and the controller
+fn set_bus_width<T: SDIo, S: SleepOps>(io: &mut T, rca: u32, width: BusWidth) -> Result<()> {
+    app_cmd::<_, S>(io, rca)?;
+    let acmd6 = CmdReg::from(Cmd::SetBusWidth);
+    let arg = if width == BusWidth::Bit1 { 0 } else { 2 };
+    send_cmd::<_, S>(
         io,
-        Cmd::SendCsd,
-        cmd,
-        CmdArg::new(rca << 16),
+        Cmd::SetBusWidth,
+        acmd6,
+        CmdArg::new(arg),
         DataTransType::None,
-    )
-    .unwrap();
-    let status = resp[0];
-    pprintln!("status: {:b}", status);
+    )?;
+    set_controller_bus_width(io, width);
+    Ok(())
 }
 
-fn select_card<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) {
-    let cmd7 = CmdReg::from(Cmd::SelectCard);
-    let cmd_arg = CmdArg::new(rca << 16);
-    let resp = send_cmd::<_, S>(io, Cmd::SelectCard, cmd7, cmd_arg, DataTransType::None).unwrap();
-    let r1 = resp[0];
-    info!("status: {:b}", r1);
+fn set_controller_bus_width<T: SDIo>(io: &mut T, width: BusWidth) {
+    let ctype = CardTypeReg::from(0)
+        .with_card_width4_1((width == BusWidth::Bit4) as u16)
+        .with_card_width8((width == BusWidth::Bit8) as u16);
+    write_reg(io, CTYPE_REG, ctype.into());
+    pprintln!("bus width: {:?}", width);
 }
 
-fn check_rca<T: SDIo, S: SleepOps>(io: &mut T) -> u32 {
-    let cmd3 = CmdReg::from(Cmd::SendRelativeAddr);
-    let resp = send_cmd::<_, S>(
+/// Size of the switch function status returned by CMD6
+const SWITCH_STATUS_SIZE: usize = 64;
+
+// send cmd6 in check (mode 0) or switch (mode 1) mode for function group 1
+fn switch_func<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    switch: bool,
+    func: u8,
+) -> Result<[u8; SWITCH_STATUS_SIZE]> {
+    set_transaction_size(io, SWITCH_STATUS_SIZE as u32, SWITCH_STATUS_SIZE as u32);
+    let cmd6 = CmdReg::from(Cmd::SwitchFunc);
+    // keep the other function groups unchanged
+    let arg = ((switch as u32) << 31) | 0x00ff_fff0 | (func as u32 & 0xf);
+    let mut status = [0; SWITCH_STATUS_SIZE];
+    send_cmd::<_, S>(
         io,
-        Cmd::SendRelativeAddr,
-        cmd3,
-        CmdArg::new(0),
-        DataTransType::None,
-    )
-    .unwrap();
-    let rca = resp[0] >> 16;
-    info!("rca: {:#x}", rca);
-    info!("card status: {:b}", resp[0] & 0xffff);
-    rca
+        Cmd::SwitchFunc,
+        cmd6,
+        CmdArg::new(arg),
+        DataTransType::Read(&mut status),
+    )?;
+    Ok(status)
 }
 
-fn check_cid<T: SDIo, S: SleepOps>(io: &mut T) {
-    let cmd2 = CmdReg::from(Cmd::AllSendCid);
-    let resp = send_cmd::<_, S>(
-        io,
-        Cmd::AllSendCid,
-        cmd2,
-        CmdArg::new(0),
-        DataTransType::None,
-    );
-    if let Some(resp) = resp {
-        // to 128 bit
-        let resp = resp[0] as u128
-            | ((resp[1] as u128) << 32)
-            | ((resp[2] as u128) << 64)
-            | ((resp[3] as u128) << 96);
-        let cid = Cid::new(resp);
-        #[cfg(feature = "alloc")]
-        pprintln!("cid: {}", cid.fmt());
-        #[cfg(not(feature = "alloc"))]
-        pprintln!("cid: {:?}", cid);
+/// Access mode function of group 1 for SDR25 / high speed
+const FUNC_HIGH_SPEED: u8 = 1;
+
+// switch the card to high speed mode if the card supports it
+fn check_high_speed<T: SDIo, S: SleepOps>(io: &mut T, scr: &Scr) -> Result<bool> {
+    // CMD6 is supported since SD 1.10
+    if scr.sd_spec() < 1 {
+        return Ok(false);
+    }
+    let status = switch_func::<_, S>(io, false, FUNC_HIGH_SPEED)?;
+    let status = SwitchStatus::new(status);
+    if !status.group1_support(FUNC_HIGH_SPEED) {
+        pprintln!("card does not support high speed");
+        return Ok(false);
     }
+    let status = switch_func::<_, S>(io, true, FUNC_HIGH_SPEED)?;
+    let status = SwitchStatus::new(status);
+    if status.group1_selected() != FUNC_HIGH_SPEED {
+        error!(
+            "switch to high speed failed: {:#x}",
+            status.group1_selected()
+        );
+        return Ok(false);
+    }
+    pprintln!("card is in high speed mode");
+    Ok(true)
+}
+
+fn check_csd<T: SDIo, S: SleepOps>(io: &mut T, rca: u32, mmc: bool) -> Result<Csd> {
+    let csd = send_cmd_typed::<_, S, R2>(io, Cmd::SendCsd, rca << 16)?.csd();
+    let capacity = if mmc {
+        csd.mmc_capacity_bytes()
+    } else {
+        csd.capacity_bytes()
+    };
+    pprintln!(
+        "csd version: {}, capacity: {} blocks",
+        csd.structure() + 1,
+        capacity / BLOCK_SIZE as u64
+    );
+    Ok(csd)
+}
+
+fn set_block_len<T: SDIo, S: SleepOps>(io: &mut T, len: u32) -> Result<()> {
+    send_cmd_typed::<_, S, R1>(io, Cmd::SetBlockLen, len)?;
+    Ok(())
+}
+
+fn select_card<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<()> {
+    let R1(status) = send_cmd_typed::<_, S, R1>(io, Cmd::SelectCard, rca << 16)?;
+    info!("status: {:?}", status);
+    Ok(())
 }
 
-fn check_version<T: SDIo, S: SleepOps>(io: &mut T) -> u8 {
+fn check_rca<T: SDIo, S: SleepOps>(io: &mut T) -> Result<u32> {
+    let r6 = send_cmd_typed::<_, S, R6>(io, Cmd::SendRelativeAddr, 0)?;
+    info!("rca: {:#x}", r6.rca);
+    info!("card status: {:?}", r6.card_status());
+    Ok(r6.rca as u32)
+}
+
+fn check_cid<T: SDIo, S: SleepOps>(io: &mut T) -> Result<Cid> {
+    let cid = send_cmd_typed::<_, S, R2>(io, Cmd::AllSendCid, 0)?.cid();
+    #[cfg(feature = "alloc")]
+    pprintln!("cid: {}", cid.fmt());
+    #[cfg(not(feature = "alloc"))]
+    pprintln!("cid: {:?}", cid);
+    Ok(cid)
+}
+
+fn check_version<T: SDIo, S: SleepOps>(io: &mut T) -> Result<u8> {
     // check voltage
-    let cmd8 = CmdReg::from(Cmd::SendIfCond);
-    let cmd8_arg = CmdArg::new(0x1aa);
-    let resp = send_cmd::<_, S>(io, Cmd::SendIfCond, cmd8, cmd8_arg, DataTransType::None).unwrap();
-    if (resp[0] & 0xaa) == 0 {
-        error!("card {} unusable", 0);
-        pprintln!("card version: 1.0");
-        return 1;
-    }
-    pprintln!("card voltage: {:#x?}", resp[0]);
+    // 2.7-3.6V with the check pattern 0xaa
+    let r7 = match send_cmd_typed::<_, S, R7>(io, Cmd::SendIfCond, 0x1aa) {
+        Ok(r7) => r7,
+        // v1.x cards do not know CMD8 and stay silent
+        Err(Vf2SdDriverError::ResponseTimeout(_)) => {
+            pprintln!("card version: 1.x");
+            return Ok(1);
+        }
+        Err(e) => {
+            error!("card unusable, cmd8 failed: {:?}", e);
+            return Err(e);
+        }
+    };
+    pprintln!("card voltage: {:#x?}", r7.voltage);
     pprintln!("card version: 2.0");
-    2
+    Ok(2)
 }
 
-fn check_big_support<T: SDIo, S: SleepOps>(io: &mut T) -> bool {
-    loop {
+/// The card should leave the busy state within one second after the first ACMD41
+const OP_COND_RETRY: usize = 100;
+
+fn check_big_support<T: SDIo, S: SleepOps>(io: &mut T, version: u8) -> Result<u32> {
+    // only v2.0 cards accept the HCS bit
+    let hcs = if version >= 2 { 1 << 30 } else { 0 };
+    for _ in 0..OP_COND_RETRY {
         // send cmd55
-        let cmd55 = CmdReg::from(Cmd::AppCmd);
-        send_cmd::<_, S>(io, Cmd::AppCmd, cmd55, CmdArg::new(0), DataTransType::None);
-        let cmd41 = CmdReg::from(Cmd::SdSendOpCond);
-        let cmd41_arg = CmdArg::new((1 << 30) | (1 << 24) | 0xFF8000);
-        let resp =
-            send_cmd::<_, S>(io, Cmd::SdSendOpCond, cmd41, cmd41_arg, DataTransType::None).unwrap();
-        info!("ocr: {:#x?}", resp[0]);
-        let ocr = resp[0];
-        if ocr.get_bit(31) {
+        app_cmd::<_, S>(io, 0)?;
+        let cmd41_arg = hcs | (1 << 24) | 0xFF8000;
+        let ocr = send_cmd_typed::<_, S, R3>(io, Cmd::SdSendOpCond, cmd41_arg)?;
+        info!("ocr: {:#x?}", ocr.0);
+        if ocr.is_ready() {
             pprintln!("card is ready");
-            if ocr.get_bit(30) {
+            if ocr.is_high_capacity() {
                 pprintln!("card is high capacity");
             } else {
                 pprintln!("card is standard capacity");
             }
-            break;
+            return Ok(ocr.0);
         }
         S::sleep_ms(10);
     }
-    true
+    error!("card is still busy after {} ACMD41", OP_COND_RETRY);
+    Err(Vf2SdDriverError::InitError)
 }
 
-fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T) {
+fn go_idle<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
+    send_cmd_typed::<_, S, NoResponse>(io, Cmd::GoIdleState, 0)?;
+    pprintln!("card is in idle state");
+    Ok(())
+}
+
+fn init_sdcard<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    input_hz: usize,
+    max_bus_width: BusWidth,
+) -> Result<Card> {
     // read DETECT_REG
     let detect = read_reg(io, CDETECT_REG);
     info!("detect: {:#?}", CDetectReg::new(detect));
@@ -439,8 +692,8 @@ fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T) {
     let clock_divider = read_reg(io, CLK_DIVIDER_REG);
     info!("clock_divider: {:#?}", ClockDividerReg::from(clock_divider));
 
-    // reset card clock to 400Mhz
-    reset_clock::<_, S>(io);
+    // reset card clock to 400kHz
+    reset_clock::<_, S>(io, input_hz)?;
     // reset fifo
     reset_fifo(io);
 
@@ -454,59 +707,396 @@ fn init_sdcard<T: SDIo, S: SleepOps>(io: &mut T) {
     let ctrl = ControlReg::from(read_reg(io, CTRL_REG));
     info!("ctrl: {:#?}", ctrl);
 
-    // go idle state
-    let cmd0 = CmdReg::from(Cmd::GoIdleState);
-    // cmd0.response_expect().set(u1!(0));
-    send_cmd::<_, S>(
-        io,
-        Cmd::GoIdleState,
-        cmd0,
-        CmdArg::new(0),
-        DataTransType::None,
-    );
-    pprintln!("card is in idle state");
+    go_idle::<_, S>(io)?;
 
-    check_version::<_, S>(io);
+    let version = check_version::<_, S>(io)?;
 
-    check_big_support::<T, S>(io);
+    let ocr = match check_big_support::<T, S>(io, version) {
+        Ok(ocr) => ocr,
+        // MMC answers neither CMD8 nor CMD55
+        Err(e @ Vf2SdDriverError::ResponseTimeout(Cmd::AppCmd)) if version == 1 => {
+            pprintln!("no SD card answers, try MMC");
+            return match init_mmc::<_, S>(io, input_hz, max_bus_width) {
+                // no card at all, report the SD failure
+                Err(Vf2SdDriverError::ResponseTimeout(Cmd::SendOpCond)) => Err(e),
+                res => res,
+            };
+        }
+        Err(e) => return Err(e),
+    };
 
-    check_cid::<_, S>(io);
-    let rca = check_rca::<_, S>(io);
+    let cid = check_cid::<_, S>(io)?;
+    let rca = check_rca::<_, S>(io)?;
     pprintln!("rca: {:#x?}", rca);
-    check_csd::<_, S>(io, rca);
+    let csd = check_csd::<_, S>(io, rca, false)?;
 
     // let raw_int_status = RawInterruptStatusReg::from(read_reg(io,RAW_INT_STATUS_REG));
     // pprintln!("RAW_INT_STATUS_REG: {:#?}", raw_int_status);
 
     S::sleep_ms(1);
 
-    select_card::<_, S>(io, rca);
+    select_card::<_, S>(io, rca)?;
+
+    // SDHC/SDXC cards always use 512 bytes blocks
+    if !ocr.get_bit(30) {
+        set_block_len::<_, S>(io, BLOCK_SIZE as u32)?;
+    }
 
     let status = StatusReg::from(read_reg(io, STATUS_REG));
     info!("Now FIFO Count is {}", status.fifo_count());
 
     // check bus width
-    check_bus_width::<_, S>(io, rca);
+    let scr = check_scr::<_, S>(io, rca)?;
+    let bus_width = if scr.support_4bit() && max_bus_width >= BusWidth::Bit4 {
+        BusWidth::Bit4
+    } else {
+        BusWidth::Bit1
+    };
+    if bus_width != BusWidth::Bit1 {
+        set_bus_width::<_, S>(io, rca, bus_width)?;
+    }
+    // leave the identification clock
+    let high_speed = check_high_speed::<_, S>(io, &scr)?;
+    let hz = if high_speed {
+        HIGH_SPEED_CLOCK_HZ
+    } else {
+        DEFAULT_SPEED_CLOCK_HZ
+    };
+    let clock = set_clock::<_, S>(io, input_hz, hz)?;
     // try read a block data
-    test_read::<_, S>(io);
+    test_read::<_, S>(io)?;
     // test_write_read();
 
     info!("CTRL_REG: {:#?}", ControlReg::from(read_reg(io, CTRL_REG)));
     let raw_int_status = RawInterruptStatusReg::from(read_reg(io, RAW_INT_STATUS_REG));
     info!("RAW_INT_STATUS_REG: {:#?}", raw_int_status);
     // Clear interrupt by writing 1
-    write_reg(io, RAW_INT_STATUS_REG, raw_int_status.into());
+    write_reg(
+        io,
+        RAW_INT_STATUS_REG,
+        u32::from(raw_int_status) & !INT_CARD_DETECT,
+    );
 
     pprintln!("init sd success");
+    Ok(Card {
+        info: Some(CardInfo::new(ocr, cid, csd, scr, rca)),
+        bus_width,
+        clock,
+        high_speed,
+        partition_config: 0,
+    })
 }
 
-#[derive(Debug, Copy, Clone)]
+/// RCA the host assigns to a MMC with CMD3
+const MMC_RCA: u32 = 1;
+
+fn check_mmc_op_cond<T: SDIo, S: SleepOps>(io: &mut T) -> Result<u32> {
+    for _ in 0..OP_COND_RETRY {
+        // sector access mode, 2.7-3.6V
+        let cmd1_arg = (1 << 30) | 0xff8080;
+        let ocr = send_cmd_typed::<_, S, R3>(io, Cmd::SendOpCond, cmd1_arg)?;
+        info!("ocr: {:#x?}", ocr.0);
+        if ocr.is_ready() {
+            pprintln!("mmc is ready");
+            if ocr.is_high_capacity() {
+                pprintln!("mmc is sector addressed");
+            } else {
+                pprintln!("mmc is byte addressed");
+            }
+            return Ok(ocr.0);
+        }
+        S::sleep_ms(10);
+    }
+    error!("mmc is still busy after {} CMD1", OP_COND_RETRY);
+    Err(Vf2SdDriverError::InitError)
+}
+
+fn set_rca<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<()> {
+    send_cmd_typed::<_, S, R1>(io, Cmd::SetRelativeAddr, rca << 16)?;
+    info!("rca: {:#x}", rca);
+    Ok(())
+}
+
+fn check_ext_csd<T: SDIo, S: SleepOps>(io: &mut T) -> Result<ExtCsd> {
+    set_transaction_size(io, 512, 512);
+    let cmd8 = CmdReg::from(Cmd::SendExtCsd);
+    let mut buffer = [0u8; 512];
+    send_cmd::<_, S>(
+        io,
+        Cmd::SendExtCsd,
+        cmd8,
+        CmdArg::new(0),
+        DataTransType::Read(&mut buffer),
+    )?;
+    let ext_csd = ExtCsd::new(buffer);
+    pprintln!("ext_csd: {:?}", ext_csd);
+    Ok(ext_csd)
+}
+
+// send cmd13 to read the card status
+fn card_status<T: SDIo, S: SleepOps>(io: &mut T, rca: u32) -> Result<CardStatus> {
+    let R1(status) = send_cmd_typed::<_, S, R1>(io, Cmd::SendStatus, rca << 16)?;
+    Ok(status)
+}
+
+/// Poll the card status until the card is back in the transfer state and ready for data
+///
+/// After writes and erases the card stays in the programming state until the data is stored.
+fn wait_transfer_state<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    rca: u32,
+    ms: usize,
+) -> Result<CardStatus> {
+    let mut status = Err(Vf2SdDriverError::TimeoutError);
+    S::sleep_ms_until(ms, || {
+        status = card_status::<_, S>(io, rca);
+        !matches!(status, Ok(s) if !s.is_transfer_ready())
+    });
+    let status = status?;
+    if !status.is_transfer_ready() {
+        error!("card is still in state {:?}", status.state());
+        return Err(Vf2SdDriverError::TimeoutError);
+    }
+    Ok(status)
+}
+
+// send cmd6 to write one byte of the EXT_CSD
+fn mmc_switch<T: SDIo, S: SleepOps>(io: &mut T, rca: u32, index: u8, value: u8) -> Result<()> {
+    let cmd6 = CmdReg::from(Cmd::Switch);
+    // access mode 0b11: write byte
+    let arg = (0b11 << 24) | ((index as u32) << 16) | ((value as u32) << 8);
+    send_cmd::<_, S>(io, Cmd::Switch, cmd6, CmdArg::new(arg), DataTransType::None)?;
+    // R1b, the card is busy while switching
+    wait_ms_util_can_send_data::<_, S>(io)?;
+    let status = card_status::<_, S>(io, rca)?;
+    if status.switch_error() {
+        error!("switch EXT_CSD[{}] to {} failed", index, value);
+        return Err(Vf2SdDriverError::CardStatus(Cmd::Switch, status.into()));
+    }
+    Ok(())
+}
+
+fn init_mmc<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    input_hz: usize,
+    max_bus_width: BusWidth,
+) -> Result<Card> {
+    go_idle::<_, S>(io)?;
+    let ocr = check_mmc_op_cond::<_, S>(io)?;
+    let cid = check_cid::<_, S>(io)?;
+    let rca = MMC_RCA;
+    set_rca::<_, S>(io, rca)?;
+    let csd = check_csd::<_, S>(io, rca, true)?;
+    select_card::<_, S>(io, rca)?;
+    let ext_csd = check_ext_csd::<_, S>(io)?;
+    // byte addressed cards may use another block length
+    if !ocr.get_bit(30) {
+        set_block_len::<_, S>(io, BLOCK_SIZE as u32)?;
+    }
+    if max_bus_width != BusWidth::Bit1 {
+        let value = if max_bus_width == BusWidth::Bit8 {
+            2
+        } else {
+            1
+        };
+        mmc_switch::<_, S>(io, rca, EXT_CSD_BUS_WIDTH, value)?;
+        set_controller_bus_width(io, max_bus_width);
+    }
+    let high_speed = ext_csd.support_hs26() || ext_csd.support_hs52();
+    if high_speed {
+        mmc_switch::<_, S>(io, rca, EXT_CSD_HS_TIMING, 1)?;
+        pprintln!("mmc is in high speed mode");
+    }
+    let hz = if ext_csd.support_hs52() {
+        MMC_HS52_CLOCK_HZ
+    } else if high_speed {
+        MMC_HS26_CLOCK_HZ
+    } else {
+        DEFAULT_SPEED_CLOCK_HZ
+    };
+    let clock = set_clock::<_, S>(io, input_hz, hz)?;
+    test_read::<_, S>(io)?;
+    pprintln!("init mmc success");
+    Ok(Card {
+        info: Some(CardInfo::new_mmc(ocr, cid, csd, ext_csd, rca)),
+        bus_width: max_bus_width,
+        clock,
+        high_speed,
+        partition_config: ext_csd.partition_config(),
+    })
+}
+
+/// Erase timeout of a SD card per started allocation unit
+const SD_ERASE_TIMEOUT_MS: usize = 250;
+/// The 4 MiB allocation unit the SD erase timeout is scaled with
+const SD_AU_BLOCKS: usize = 8192;
+/// Unit of ERASE_TIMEOUT_MULT and TRIM_MULT of MMC
+const MMC_ERASE_TIMEOUT_UNIT_MS: usize = 300;
+/// Lower bound of the erase timeout
+const MIN_ERASE_TIMEOUT_MS: usize = 1000;
+
+/// How [`Vf2SdDriver`] releases a range of blocks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EraseKind {
+    /// The blocks read as [`CardInfo::erased_byte`], aligned to [`CardInfo::erase_unit_blocks`]
+    Erase,
+    /// Erase single write blocks of a MMC
+    Trim,
+    /// Mark single write blocks of a MMC as unused, they may keep their content
+    Discard,
+}
+
+impl EraseKind {
+    /// Argument of CMD38 of MMC
+    fn mmc_arg(&self) -> u32 {
+        match self {
+            EraseKind::Erase => 0,
+            EraseKind::Trim => 1,
+            EraseKind::Discard => 3,
+        }
+    }
+}
+
+/// Time allowed for the busy phase after erasing the blocks `first..=last`
+fn erase_timeout_ms(info: &CardInfo, kind: EraseKind, first: usize, last: usize) -> usize {
+    let ms = match info.ext_csd() {
+        Some(ext_csd) => {
+            let group = info.erase_unit_blocks().max(1) as usize;
+            let mult = if kind == EraseKind::Erase {
+                ext_csd.erase_timeout_mult()
+            } else {
+                ext_csd.trim_mult()
+            };
+            MMC_ERASE_TIMEOUT_UNIT_MS * mult.max(1) as usize * (last / group - first / group + 1)
+        }
+        None => SD_ERASE_TIMEOUT_MS * (last / SD_AU_BLOCKS - first / SD_AU_BLOCKS + 1),
+    };
+    ms.max(MIN_ERASE_TIMEOUT_MS)
+}
+
+// send cmd32/cmd33 (SD) or cmd35/cmd36 (MMC) to set the range, then cmd38 to erase it
+fn erase_blocks<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    rca: u32,
+    mmc: bool,
+    range: (u32, u32),
+    arg: u32,
+    timeout_ms: usize,
+) -> Result<()> {
+    let (start_cmd, end_cmd) = if mmc {
+        (Cmd::EraseGroupStart, Cmd::EraseGroupEnd)
+    } else {
+        (Cmd::EraseWrBlkStart, Cmd::EraseWrBlkEnd)
+    };
+    for (cmd, addr) in [(start_cmd, range.0), (end_cmd, range.1)] {
+        send_cmd::<_, S>(
+            io,
+            cmd,
+            CmdReg::from(cmd),
+            CmdArg::new(addr),
+            DataTransType::None,
+        )?;
+    }
+    let cmd38 = CmdReg::from(Cmd::Erase);
+    send_cmd::<_, S>(io, Cmd::Erase, cmd38, CmdArg::new(arg), DataTransType::None)?;
+    // R1b, the card is busy until the blocks are erased
+    wait_ms_util_not_busy::<_, S>(io, timeout_ms)?;
+    wait_transfer_state::<_, S>(io, rca, timeout_ms)?;
+    Ok(())
+}
+
+/// Error bits of the R1 card status
+///
+/// OUT_OF_RANGE, ADDRESS_ERROR, BLOCK_LEN_ERROR, ERASE_SEQ_ERROR, ERASE_PARAM, WP_VIOLATION,
+/// LOCK_UNLOCK_FAILED, COM_CRC_ERROR, ILLEGAL_COMMAND, CARD_ECC_FAILED, CC_ERROR, ERROR,
+/// CSD_OVERWRITE, WP_ERASE_SKIP and AKE_SEQ_ERROR
+pub const CARD_STATUS_ERROR_MASK: u32 = 0xfdf9_8008;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Vf2SdDriverError {
+    /// The card is not initialized: [`Vf2SdDriver::init`] was not called or failed, or another
+    /// card was inserted since. The card is usable again once `init` succeeded.
     InitError,
     ReadError,
     WriteError,
+    /// The controller did not finish the operation in time
     TimeoutError,
+    BufferSizeError,
     UnknownError,
+    /// Response timeout (RTO), the card did not answer the command
+    ResponseTimeout(Cmd),
+    /// Response CRC error (RCRC)
+    ResponseCrc(Cmd),
+    /// Response error (RE)
+    ResponseError(Cmd),
+    /// Data read timeout (DRTO)
+    DataReadTimeout(Cmd),
+    /// Data CRC error (DCRC)
+    DataCrc(Cmd),
+    /// Start-bit error (SBE)
+    StartBitError(Cmd),
+    /// End-bit error (read) / write no CRC (EBE)
+    EndBitError(Cmd),
+    /// FIFO underrun/overrun error (FRUN)
+    FifoOverrun(Cmd),
+    /// Hardware locked write error (HLE)
+    HardwareLocked(Cmd),
+    /// The R1 response of the command has error bits set, see [`CARD_STATUS_ERROR_MASK`]
+    CardStatus(Cmd, u32),
+    /// The internal DMA controller stopped with the given IDSTS value
+    DmaError(u32),
+    /// The block number can not be addressed by the card
+    OutOfRange(usize),
+    /// Neither a valid GPT nor an MBR was found on the card
+    NoPartitionTable,
+    /// The card does not support the operation, e.g. hardware partitions of SD cards
+    Unsupported,
+    /// The RPMB request failed with the given operation result
+    Rpmb(u16),
+    /// The RPMB response has an unexpected type, nonce, write counter or MAC
+    RpmbAuthentication,
+    /// The erase range starting at the block is not aligned to the erase unit of the card
+    Misaligned(usize),
+    /// An interrupt driven transfer is in flight
+    Busy,
+    /// The response does not match the command, e.g. a wrong CMD8 check pattern or RCA 0
+    InvalidResponse(Cmd),
+    /// The card slot is empty or the card was removed since [`Vf2SdDriver::init`], `init`
+    /// has to be called again once a card is inserted
+    NoCard,
+}
+
+impl Vf2SdDriverError {
+    /// The command which failed, if the error comes from the card or the controller
+    pub fn cmd(&self) -> Option<Cmd> {
+        match *self {
+            Vf2SdDriverError::ResponseTimeout(cmd)
+            | Vf2SdDriverError::ResponseCrc(cmd)
+            | Vf2SdDriverError::ResponseError(cmd)
+            | Vf2SdDriverError::DataReadTimeout(cmd)
+            | Vf2SdDriverError::DataCrc(cmd)
+            | Vf2SdDriverError::StartBitError(cmd)
+            | Vf2SdDriverError::EndBitError(cmd)
+            | Vf2SdDriverError::FifoOverrun(cmd)
+            | Vf2SdDriverError::HardwareLocked(cmd)
+            | Vf2SdDriverError::CardStatus(cmd, _)
+            | Vf2SdDriverError::InvalidResponse(cmd) => Some(cmd),
+            _ => None,
+        }
+    }
+
+    /// Whether the error is caused by signal integrity and the operation is worth retrying
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            Vf2SdDriverError::ResponseCrc(_)
+            | Vf2SdDriverError::DataCrc(_)
+            | Vf2SdDriverError::StartBitError(_)
+            | Vf2SdDriverError::EndBitError(_)
+            | Vf2SdDriverError::FifoOverrun(_) => true,
+            Vf2SdDriverError::CardStatus(_, status) => CardStatus::from(status).com_crc_error(),
+            _ => false,
+        }
+    }
 }
 
 impl Display for Vf2SdDriverError {
@@ -516,78 +1106,1341 @@ impl Display for Vf2SdDriverError {
             Vf2SdDriverError::ReadError => write!(f, "read error"),
             Vf2SdDriverError::WriteError => write!(f, "write error"),
             Vf2SdDriverError::TimeoutError => write!(f, "timeout error"),
+            Vf2SdDriverError::BufferSizeError => write!(f, "buffer size error"),
             Vf2SdDriverError::UnknownError => write!(f, "unknown error"),
+            Vf2SdDriverError::ResponseTimeout(cmd) => write!(f, "{:?} response timeout", cmd),
+            Vf2SdDriverError::ResponseCrc(cmd) => write!(f, "{:?} response crc error", cmd),
+            Vf2SdDriverError::ResponseError(cmd) => write!(f, "{:?} response error", cmd),
+            Vf2SdDriverError::DataReadTimeout(cmd) => write!(f, "{:?} data read timeout", cmd),
+            Vf2SdDriverError::DataCrc(cmd) => write!(f, "{:?} data crc error", cmd),
+            Vf2SdDriverError::StartBitError(cmd) => write!(f, "{:?} start bit error", cmd),
+            Vf2SdDriverError::EndBitError(cmd) => write!(f, "{:?} end bit error", cmd),
+            Vf2SdDriverError::FifoOverrun(cmd) => write!(f, "{:?} fifo underrun/overrun", cmd),
+            Vf2SdDriverError::HardwareLocked(cmd) => {
+                write!(f, "{:?} hardware locked write error", cmd)
+            }
+            Vf2SdDriverError::CardStatus(cmd, status) => {
+                write!(f, "{:?} card status error {:#x}", cmd, status)
+            }
+            Vf2SdDriverError::DmaError(status) => write!(f, "idmac error {:#x}", status),
+            Vf2SdDriverError::OutOfRange(block) => write!(f, "block {} out of range", block),
+            Vf2SdDriverError::NoPartitionTable => write!(f, "no partition table"),
+            Vf2SdDriverError::Unsupported => write!(f, "unsupported by the card"),
+            Vf2SdDriverError::Rpmb(result) => write!(f, "rpmb operation result {:#x}", result),
+            Vf2SdDriverError::RpmbAuthentication => write!(f, "rpmb response not authentic"),
+            Vf2SdDriverError::Busy => write!(f, "transfer in flight"),
+            Vf2SdDriverError::NoCard => write!(f, "no card"),
+            Vf2SdDriverError::InvalidResponse(cmd) => write!(f, "{:?} invalid response", cmd),
+            Vf2SdDriverError::Misaligned(block) => {
+                write!(f, "block {} not aligned to the erase unit", block)
+            }
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for Vf2SdDriverError {}
+
 pub type Result<T> = core::result::Result<T, Vf2SdDriverError>;
 
-fn read_block<T: SDIo, S: SleepOps>(io: &mut T, block: usize, buf: &mut [u8]) -> Result<usize> {
-    assert_eq!(buf.len(), 512);
+/// Data bus width between the controller and the card
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BusWidth {
+    Bit1,
+    Bit4,
+    /// MMC only
+    Bit8,
+}
+
+/// The state of the card negotiated during initialization
+#[derive(Debug, Copy, Clone)]
+struct Card {
+    info: Option<CardInfo>,
+    bus_width: BusWidth,
+    /// Card clock in Hz
+    clock: usize,
+    high_speed: bool,
+    /// PARTITION_CONFIG of MMC
+    partition_config: u8,
+}
+
+impl Default for Card {
+    fn default() -> Self {
+        Self {
+            info: None,
+            bus_width: BusWidth::Bit1,
+            clock: 0,
+            high_speed: false,
+            partition_config: 0,
+        }
+    }
+}
+
+/// Size of a data block in bytes
+pub const BLOCK_SIZE: usize = 512;
+
+fn read_block<T: SDIo, S: SleepOps>(io: &mut T, addr: u32, buf: &mut [u8]) -> Result<usize> {
+    if buf.len() != 512 {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
     set_transaction_size(io, 512, 512);
     let cmd17 = CmdReg::from(Cmd::ReadSingleBlock);
-    let arg = CmdArg::new(block as u32);
+    let arg = CmdArg::new(addr);
     let _resp = send_cmd::<_, S>(
         io,
         Cmd::ReadSingleBlock,
         cmd17,
         arg,
         DataTransType::Read(buf),
-    )
-    .unwrap();
+    )?;
     info!("Current FIFO count: {}", fifo_filled_cnt(io));
     Ok(buf.len())
 }
 
-fn write_block<T: SDIo, S: SleepOps>(io: &mut T, block: usize, buf: &[u8]) -> Result<usize> {
-    assert_eq!(buf.len(), 512);
+fn write_block<T: SDIo, S: SleepOps>(io: &mut T, addr: u32, buf: &[u8]) -> Result<usize> {
+    if buf.len() != 512 {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
     set_transaction_size(io, 512, 512);
     let cmd24 = CmdReg::from(Cmd::WriteSingleBlock);
-    let arg = CmdArg::new(block as u32);
+    let arg = CmdArg::new(addr);
     let _resp = send_cmd::<_, S>(
         io,
         Cmd::WriteSingleBlock,
         cmd24,
         arg,
         DataTransType::Write(buf),
-    )
-    .unwrap();
+    )?;
+    info!("Current FIFO count: {}", fifo_filled_cnt(io));
+    Ok(buf.len())
+}
+
+fn stop_transmission<T: SDIo, S: SleepOps>(io: &mut T) -> Result<()> {
+    let cmd12 = CmdReg::from(Cmd::StopTransmission);
+    send_cmd::<_, S>(
+        io,
+        Cmd::StopTransmission,
+        cmd12,
+        CmdArg::new(0),
+        DataTransType::None,
+    )?;
+    Ok(())
+}
+
+fn read_blocks<T: SDIo, S: SleepOps>(io: &mut T, addr: u32, buf: &mut [u8]) -> Result<usize> {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
+    if buf.len() == BLOCK_SIZE {
+        return read_block::<_, S>(io, addr, buf);
+    }
+    set_transaction_size(io, BLOCK_SIZE as u32, buf.len() as u32);
+    // the controller sends CMD12 after the last block
+    let cmd18 = CmdReg::from(Cmd::ReadMultipleBlock);
+    let arg = CmdArg::new(addr);
+    let res = send_cmd::<_, S>(
+        io,
+        Cmd::ReadMultipleBlock,
+        cmd18,
+        arg,
+        DataTransType::Read(buf),
+    );
+    if let Err(e) = res {
+        // bring the card back to transfer state
+        let _ = stop_transmission::<_, S>(io);
+        return Err(e);
+    }
+    info!("Current FIFO count: {}", fifo_filled_cnt(io));
+    Ok(buf.len())
+}
+
+fn write_blocks<T: SDIo, S: SleepOps>(io: &mut T, addr: u32, buf: &[u8]) -> Result<usize> {
+    if buf.is_empty() || buf.len() % BLOCK_SIZE != 0 {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
+    if buf.len() == BLOCK_SIZE {
+        return write_block::<_, S>(io, addr, buf);
+    }
+    set_transaction_size(io, BLOCK_SIZE as u32, buf.len() as u32);
+    // the controller sends CMD12 after the last block
+    let cmd25 = CmdReg::from(Cmd::WriteMultipleBlock);
+    let arg = CmdArg::new(addr);
+    let res = send_cmd::<_, S>(
+        io,
+        Cmd::WriteMultipleBlock,
+        cmd25,
+        arg,
+        DataTransType::Write(buf),
+    );
+    if let Err(e) = res {
+        // bring the card back to transfer state
+        let _ = stop_transmission::<_, S>(io);
+        return Err(e);
+    }
     info!("Current FIFO count: {}", fifo_filled_cnt(io));
     Ok(buf.len())
 }
 
+fn dma_start<T: SDIo, S: SleepOps>(io: &mut T, desc_addr: usize) -> Result<()> {
+    // reset the DMA interface in case the FIFO was used by the CPU before
+    let ctrl = ControlReg::from(read_reg(io, CTRL_REG))
+        .with_dma_reset(true)
+        .with_use_internal_dmac(true);
+    write_reg(io, CTRL_REG, ctrl.into());
+    let bus_mode = BusModeReg::from(read_reg(io, BUS_MODE_REG)).with_swr(true);
+    write_reg(io, BUS_MODE_REG, bus_mode.into());
+    let f = || {
+        let ctrl = ControlReg::from(read_reg(io, CTRL_REG));
+        let bus_mode = BusModeReg::from(read_reg(io, BUS_MODE_REG));
+        !ctrl.dma_reset() && !bus_mode.swr()
+    };
+    S::sleep_ms_until(1, f);
+    if !f() {
+        return Err(Vf2SdDriverError::TimeoutError);
+    }
+    // clear the stale status, the transfer polls it with the IDMAC interrupts masked
+    write_reg(io, IDSTS_REG, read_reg(io, IDSTS_REG));
+    write_reg(io, IDINTEN_REG, 0);
+    write_reg(io, DBADDRL_REG, desc_addr as u32);
+    write_reg(io, DBADDRU_REG, (desc_addr as u64 >> 32) as u32);
+    let bus_mode = BusModeReg::from(read_reg(io, BUS_MODE_REG))
+        .with_de(true)
+        .with_fd(true);
+    write_reg(io, BUS_MODE_REG, bus_mode.into());
+    // let the IDMAC fetch the first descriptor
+    write_reg(io, PLDMND_REG, 1);
+    Ok(())
+}
+
+fn dma_stop<T: SDIo>(io: &mut T) -> IdmacStatusReg {
+    let status = IdmacStatusReg::from(read_reg(io, IDSTS_REG));
+    write_reg(io, IDSTS_REG, status.into());
+    let bus_mode = BusModeReg::from(read_reg(io, BUS_MODE_REG))
+        .with_de(false)
+        .with_fd(false);
+    write_reg(io, BUS_MODE_REG, bus_mode.into());
+    let ctrl = ControlReg::from(read_reg(io, CTRL_REG))
+        .with_dma_reset(true)
+        .with_use_internal_dmac(false);
+    write_reg(io, CTRL_REG, ctrl.into());
+    info!("idmac status: {:#?}", status);
+    status
+}
+
+fn dma_transfer<T: SDIo, S: SleepOps>(
+    io: &mut T,
+    write: bool,
+    addr: u32,
+    ring: &mut IdmacRing,
+    segments: &[DmaSegment],
+) -> Result<usize> {
+    let len = ring.build(segments)?;
+    if len % BLOCK_SIZE != 0 {
+        return Err(Vf2SdDriverError::BufferSizeError);
+    }
+    let cmd_type = match (write, len == BLOCK_SIZE) {
+        (false, true) => Cmd::ReadSingleBlock,
+        (false, false) => Cmd::ReadMultipleBlock,
+        (true, true) => Cmd::WriteSingleBlock,
+        (true, false) => Cmd::WriteMultipleBlock,
+    };
+    set_transaction_size(io, BLOCK_SIZE as u32, len as u32);
+    dma_start::<_, S>(io, ring.phys_addr())?;
+    let cmd = CmdReg::from(cmd_type);
+    let arg = CmdArg::new(addr);
+    let res = send_cmd::<_, S>(io, cmd_type, cmd, arg, DataTransType::Dma(len));
+    let status = dma_stop(io);
+    if status.fbe() || status.du() {
+        error!("idmac stopped: {:#?}", status);
+        // the data phase can not complete without the IDMAC, abort it
+        let _ = stop_transmission::<_, S>(io);
+        return Err(Vf2SdDriverError::DmaError(status.into()));
+    }
+    if let Err(e) = res {
+        if len > BLOCK_SIZE {
+            // bring the card back to transfer state
+            let _ = stop_transmission::<_, S>(io);
+        }
+        return Err(e);
+    }
+    if status.have_error() {
+        error!("idmac error: {:#?}", status);
+        return Err(Vf2SdDriverError::DmaError(status.into()));
+    }
+    Ok(len)
+}
+
 /// Vf2SdDriver
 ///
 /// # Example
-/// ```rust no run
-/// fn sleep(ms:usize){}
-/// use visionfive2_sd::Vf2SdDriver;
-/// let driver = Vf2SdDriver::new(sleep);
-/// driver.init();
+/// ```rust no_run
+/// use visionfive2_sd::{SDIo, SleepOps, Vf2SdDriver};
+/// struct SdIoImpl;
+/// impl SDIo for SdIoImpl {
+///     fn read_reg_at(&self, _offset: usize) -> u32 { 0 }
+///     fn write_reg_at(&mut self, _offset: usize, _val: u32) {}
+///     fn read_data_at(&self, _offset: usize) -> u64 { 0 }
+///     fn write_data_at(&mut self, _offset: usize, _val: u64) {}
+/// }
+/// struct SleepOpsImpl;
+/// impl SleepOps for SleepOpsImpl {
+///     fn sleep_ms(_ms: usize) {}
+///     fn sleep_ms_until(_ms: usize, _f: impl FnMut() -> bool) {}
+/// }
+/// let mut driver = Vf2SdDriver::<_, SleepOpsImpl>::new(SdIoImpl);
+/// let info = driver.init().unwrap();
+/// assert_eq!(info.capacity_blocks(), driver.capacity_blocks());
 /// let mut buf = [0u8;512];
-/// driver.read_block(0,&mut buf);
-/// driver.write_block(0,&buf);
+/// driver.read_block(0,&mut buf).unwrap();
+/// driver.write_block(0,&buf).unwrap();
 /// ```
 pub struct Vf2SdDriver<T, S> {
     io: T,
+    /// Input clock of the card interface unit in Hz
+    input_clock: usize,
+    /// Widest data bus wired to the card
+    max_bus_width: BusWidth,
+    card: Card,
+    /// Whether the card detect input of the slot is wired
+    card_detect: bool,
+    /// Card change not taken by [`Vf2SdDriver::take_card_event`] yet
+    card_event: Option<CardEvent>,
+    irq: irq::IrqState,
     _sleep: core::marker::PhantomData<S>,
 }
 
+impl<S: SleepOps> Vf2SdDriver<MmioSdIo, S> {
+    /// Driver for one of the identity mapped JH7110 controllers
+    ///
+    /// # Safety
+    ///
+    /// See [`MmioSdIo::new`], each instance may only be driven by one driver at a time.
+    pub unsafe fn from_instance(instance: SdioInstance) -> Self {
+        let width = match instance {
+            // the eMMC socket has all 8 data lines, the SD card slot only 4
+            SdioInstance::Sdio0 => BusWidth::Bit8,
+            SdioInstance::Sdio1 => BusWidth::Bit4,
+        };
+        Self::new(MmioSdIo::from_instance(instance)).with_max_bus_width(width)
+    }
+}
+
 impl<T: SDIo, S: SleepOps> Vf2SdDriver<T, S> {
     pub fn new(io: T) -> Self {
         Self {
             io,
+            input_clock: DEFAULT_INPUT_CLOCK_HZ,
+            max_bus_width: BusWidth::Bit4,
+            card: Card::default(),
+            card_detect: true,
+            card_event: None,
+            irq: irq::IrqState::default(),
             _sleep: core::marker::PhantomData,
         }
     }
-    pub fn init(&mut self) {
-        init_sdcard::<T, S>(&mut self.io);
+    /// Set the frequency of the clock feeding the controller, the card clock is divided from it
+    pub fn with_input_clock(mut self, hz: usize) -> Self {
+        self.input_clock = hz;
+        self
+    }
+    /// Limit the data bus to the lines wired to the card, 4-bit by default
+    ///
+    /// SD cards use at most 4 lines. MMC is switched to `width` without probing the bus, so
+    /// 8-bit may only be set for a slot with all 8 data lines wired.
+    pub fn with_max_bus_width(mut self, width: BusWidth) -> Self {
+        self.max_bus_width = width;
+        self
+    }
+    /// Whether the slot has a card detect input, enabled by default
+    ///
+    /// Without it the card is assumed to be present, e.g. for an eMMC or a slot marked
+    /// `broken-cd` in the device tree.
+    pub fn with_card_detect(mut self, enabled: bool) -> Self {
+        self.card_detect = enabled;
+        self
+    }
+    /// Identify and initialize the card, returning its registers
+    pub fn init(&mut self) -> Result<CardInfo> {
+        self.check_idle()?;
+        set_debounce(&mut self.io, self.input_clock);
+        // the card found now is initialized, earlier changes are of no interest
+        write_reg(&mut self.io, RAW_INT_STATUS_REG, INT_CARD_DETECT);
+        self.card_event = None;
+        // a failed init leaves the card uninitialized
+        self.card = Card::default();
+        if !self.is_card_present() {
+            return Err(Vf2SdDriverError::NoCard);
+        }
+        self.card = init_sdcard::<T, S>(&mut self.io, self.input_clock, self.max_bus_width)?;
+        Ok(self.card.info.unwrap())
+    }
+    /// Whether the card detect input of the slot reports a card
+    pub fn is_card_present(&self) -> bool {
+        !self.card_detect || CDetectReg::new(read_reg(&self.io, CDETECT_REG)).card_present()
+    }
+    /// The card change reported since the last call, if any
+    ///
+    /// Any change drops the state of the card, the I/O fails with
+    /// [`Vf2SdDriverError::NoCard`] while the slot is empty and with
+    /// [`Vf2SdDriverError::InitError`] until the card is initialized again.
+    pub fn take_card_event(&mut self) -> Option<CardEvent> {
+        self.update_card_detect();
+        irq::unmask_idle_interrupts(self);
+        self.card_event.take()
+    }
+    /// Acknowledge a pending card detect interrupt and forget the card
+    pub(crate) fn update_card_detect(&mut self) {
+        if !self.card_detect || read_reg(&self.io, RAW_INT_STATUS_REG) & INT_CARD_DETECT == 0 {
+            return;
+        }
+        write_reg(&mut self.io, RAW_INT_STATUS_REG, INT_CARD_DETECT);
+        let event = if self.is_card_present() {
+            CardEvent::Inserted
+        } else {
+            CardEvent::Removed
+        };
+        pprintln!("card event: {:?}", event);
+        self.card = Card::default();
+        self.card_event = Some(event);
+    }
+    /// Fail if a transfer is in flight or the card is not initialized, e.g. it changed since
+    pub(crate) fn check_card(&mut self) -> Result<()> {
+        self.check_idle()?;
+        self.update_card_detect();
+        if !self.is_card_present() {
+            Err(Vf2SdDriverError::NoCard)
+        } else if self.card.info.is_none() {
+            Err(Vf2SdDriverError::InitError)
+        } else {
+            Ok(())
+        }
+    }
+    /// The registers read by the last successful [`Vf2SdDriver::init`]
+    pub fn card_info(&self) -> Option<&CardInfo> {
+        self.card.info.as_ref()
+    }
+    /// The data bus width negotiated during [`Vf2SdDriver::init`]
+    pub fn bus_width(&self) -> BusWidth {
+        self.card.bus_width
+    }
+    /// The current card clock in Hz
+    pub fn clock(&self) -> usize {
+        self.card.clock
+    }
+    /// Whether the card was switched to high speed mode during [`Vf2SdDriver::init`]
+    pub fn is_high_speed(&self) -> bool {
+        self.card.high_speed
+    }
+    /// Number of blocks of the card, 0 before [`Vf2SdDriver::init`]
+    pub fn capacity_blocks(&self) -> u64 {
+        self.card.info.map_or(0, |info| info.capacity_blocks())
+    }
+    /// Capacity of the card in bytes, 0 before [`Vf2SdDriver::init`]
+    pub fn capacity_bytes(&self) -> u64 {
+        self.card.info.map_or(0, |info| info.capacity_bytes())
+    }
+    /// Change the card clock to at most `hz`, returning the actual frequency
+    ///
+    /// Clocks above 25 MHz require the card to be in high speed mode, without it the clock
+    /// is limited to 25 MHz.
+    pub fn set_clock(&mut self, hz: usize) -> Result<usize> {
+        self.check_idle()?;
+        let hz = if self.card.high_speed {
+            hz
+        } else {
+            hz.min(DEFAULT_SPEED_CLOCK_HZ)
+        };
+        self.card.clock = set_clock::<_, S>(&mut self.io, self.input_clock, hz)?;
+        Ok(self.card.clock)
+    }
+    /// The hardware partition the data commands access
+    pub fn partition(&self) -> HwPartition {
+        HwPartition::from_access(self.card.partition_config)
+    }
+    /// Switch the data commands to another hardware partition of a MMC
+    ///
+    /// The boot configuration bits of PARTITION_CONFIG are kept.
+    pub fn select_partition(&mut self, partition: HwPartition) -> Result<()> {
+        self.check_card()?;
+        let info = self.card.info.ok_or(Vf2SdDriverError::InitError)?;
+        if info.partition_bytes(partition) == 0 {
+            return Err(Vf2SdDriverError::Unsupported);
+        }
+        if partition == self.partition() {
+            return Ok(());
+        }
+        let config = (self.card.partition_config & !0x7) | partition.access();
+        mmc_switch::<_, S>(&mut self.io, info.rca(), EXT_CSD_PARTITION_CONFIG, config)?;
+        self.card.partition_config = config;
+        pprintln!("partition: {:?}", partition);
+        Ok(())
+    }
+    /// Read the card status with CMD13
+    pub fn status(&mut self) -> Result<CardStatus> {
+        self.check_card()?;
+        let info = self.card.info.ok_or(Vf2SdDriverError::InitError)?;
+        card_status::<_, S>(&mut self.io, info.rca())
+    }
+    /// Send `cmd` without data and decode its response as `R`
+    ///
+    /// Application specific commands are preceded by CMD55. A response type which does not
+    /// match [`Cmd::response_type`] fails with [`Vf2SdDriverError::InvalidResponse`].
+    pub fn command<R: Response>(&mut self, cmd: Cmd, arg: u32) -> Result<R> {
+        self.check_card()?;
+        if cmd.is_app_cmd() {
+            let rca = self.card.info.map_or(0, |info| info.rca());
+            app_cmd::<_, S>(&mut self.io, rca)?;
+        }
+        send_cmd_typed::<_, S, R>(&mut self.io, cmd, arg)
+    }
+    /// Send a command unknown to the driver, e.g. a vendor command, returning RESP0..RESP3
+    ///
+    /// The data phase given by [`RawCmd::data`] moves `buf` in blocks of [`BLOCK_SIZE`], or
+    /// in one shorter block which is a multiple of 8 bytes. The card is busy after a
+    /// [`ResponseType::R1b`] until [`Vf2SdDriver::status`] reports it ready again.
+    pub fn raw_cmd(&mut self, cmd: RawCmd, arg: u32, buf: &mut [u8]) -> Result<[u32; 4]> {
+        self.check_card()?;
+        let len = buf.len();
+        let data = match cmd.data {
+            DataDir::None => DataTransType::None,
+            DataDir::Read => DataTransType::Read(buf),
+            DataDir::Write => DataTransType::Write(buf),
+        };
+        if cmd.data != DataDir::None {
+            let blk_size = len.min(BLOCK_SIZE);
+            if len == 0 || len % 8 != 0 || len % blk_size != 0 {
+                return Err(Vf2SdDriverError::BufferSizeError);
+            }
+            set_transaction_size(&mut self.io, blk_size as u32, len as u32);
+        }
+        if cmd.app {
+            // the RCA is 0 before the card was identified
+            let rca = self.card.info.map_or(0, |info| info.rca());
+            app_cmd::<_, S>(&mut self.io, rca)?;
+        }
+        let cmd = Cmd::Raw(cmd);
+        send_cmd::<_, S>(&mut self.io, cmd, cmd.into(), CmdArg::new(arg), data)
+    }
+    /// Wait until the card stored the `len` bytes written last
+    pub(crate) fn wait_programmed(&mut self, len: usize) -> Result<()> {
+        // the card can only be addressed after init
+        if let Some(info) = self.card.info {
+            wait_transfer_state::<_, S>(&mut self.io, info.rca(), data_timeout_ms(len))?;
+        }
+        Ok(())
+    }
+    /// Erase `count` blocks starting at `block`
+    ///
+    /// The blocks read as [`CardInfo::erased_byte`] afterwards. The range must be aligned to
+    /// [`CardInfo::erase_unit_blocks`], use [`Vf2SdDriver::trim`] for single blocks of a MMC.
+    pub fn erase(&mut self, block: usize, count: usize) -> Result<()> {
+        self.erase_with(block, count, EraseKind::Erase)
+    }
+    /// Erase `count` write blocks of a MMC starting at `block`
+    pub fn trim(&mut self, block: usize, count: usize) -> Result<()> {
+        self.erase_with(block, count, EraseKind::Trim)
+    }
+    /// Tell a MMC that `count` blocks starting at `block` are unused
+    ///
+    /// Unlike [`Vf2SdDriver::trim`] the content of the blocks is undefined afterwards.
+    pub fn discard(&mut self, block: usize, count: usize) -> Result<()> {
+        self.erase_with(block, count, EraseKind::Discard)
+    }
+    /// Release `count` blocks starting at `block` of the current hardware partition
+    pub fn erase_with(&mut self, block: usize, count: usize, kind: EraseKind) -> Result<()> {
+        self.check_card()?;
+        let info = self.card.info.ok_or(Vf2SdDriverError::InitError)?;
+        let supported = match (info.ext_csd(), kind) {
+            (_, EraseKind::Erase) => true,
+            (Some(ext_csd), EraseKind::Trim) => ext_csd.support_trim(),
+            (Some(ext_csd), EraseKind::Discard) => ext_csd.support_discard(),
+            (None, _) => false,
+        };
+        if !supported || self.partition() == HwPartition::Rpmb {
+            return Err(Vf2SdDriverError::Unsupported);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let last = block.saturating_add(count - 1);
+        if last as u64 >= info.partition_bytes(self.partition()) / BLOCK_SIZE as u64 {
+            return Err(Vf2SdDriverError::OutOfRange(last));
+        }
+        let unit = match kind {
+            EraseKind::Erase => info.erase_unit_blocks().max(1) as usize,
+            _ => 1,
+        };
+        if block % unit != 0 || count % unit != 0 {
+            return Err(Vf2SdDriverError::Misaligned(block));
+        }
+        let range = (self.card_addr(block)?, self.card_addr(last)?);
+        let arg = if info.is_mmc() { kind.mmc_arg() } else { 0 };
+        let timeout_ms = erase_timeout_ms(&info, kind, block, last);
+        pprintln!("{:?} blocks {}..={}", kind, block, last);
+        erase_blocks::<_, S>(
+            &mut self.io,
+            info.rca(),
+            info.is_mmc(),
+            range,
+            arg,
+            timeout_ms,
+        )
+    }
+    /// The argument of the data commands for `block`
+    ///
+    /// SDHC/SDXC cards are block addressed, SDSC cards are byte addressed.
+    fn card_addr(&self, block: usize) -> Result<u32> {
+        let info = self.card.info.ok_or(Vf2SdDriverError::InitError)?;
+        let addr = if info.is_high_capacity() {
+            Some(block)
+        } else {
+            block.checked_mul(BLOCK_SIZE)
+        };
+        addr.and_then(|addr| u32::try_from(addr).ok())
+            .ok_or(Vf2SdDriverError::OutOfRange(block))
+    }
+    pub fn read_block(&mut self, block: usize, buf: &mut [u8]) -> Result<usize> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        read_block::<_, S>(&mut self.io, addr, buf)
+    }
+    pub fn write_block(&mut self, block: usize, buf: &[u8]) -> Result<usize> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        let len = write_block::<_, S>(&mut self.io, addr, buf)?;
+        self.wait_programmed(len)?;
+        Ok(len)
+    }
+    /// Read `buf.len() / BLOCK_SIZE` consecutive blocks starting at `block` in one transaction
+    pub fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<usize> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        read_blocks::<_, S>(&mut self.io, addr, buf)
+    }
+    /// Write `buf.len() / BLOCK_SIZE` consecutive blocks starting at `block` in one transaction
+    pub fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<usize> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        let len = write_blocks::<_, S>(&mut self.io, addr, buf)?;
+        self.wait_programmed(len)?;
+        Ok(len)
+    }
+    /// Read blocks starting at `block` into `segments` with the internal DMA controller
+    ///
+    /// The descriptors of `ring` are rebuilt for every transfer, the total length of
+    /// `segments` must be a multiple of [`BLOCK_SIZE`].
+    ///
+    /// # Safety
+    ///
+    /// The controller accesses `ring` and `segments` by their bus addresses:
+    /// - `ring.phys_addr()` must be the bus address of its descriptors, which must be
+    ///   mapped uncached or coherent with the controller.
+    /// - every segment must be memory the controller may write, valid and not accessed
+    ///   otherwise until this returns. The addresses and lengths must be multiples of 4.
+    /// - the IDMAC is not cache coherent: the data cache lines of the segments must not be
+    ///   dirty before the call and must be invalidated before the data is read.
+    pub unsafe fn read_blocks_dma(
+        &mut self,
+        block: usize,
+        ring: &mut IdmacRing,
+        segments: &[DmaSegment],
+    ) -> Result<usize> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        dma_transfer::<_, S>(&mut self.io, false, addr, ring, segments)
+    }
+    /// Write blocks starting at `block` from `segments` with the internal DMA controller
+    ///
+    /// # Safety
+    ///
+    /// See [`Vf2SdDriver::read_blocks_dma`], the segments are only read by the controller
+    /// and their data cache lines must be cleaned before the call.
+    pub unsafe fn write_blocks_dma(
+        &mut self,
+        block: usize,
+        ring: &mut IdmacRing,
+        segments: &[DmaSegment],
+    ) -> Result<usize> {
+        self.check_card()?;
+        let addr = self.card_addr(block)?;
+        let len = dma_transfer::<_, S>(&mut self.io, true, addr, ring, segments)?;
+        self.wait_programmed(len)?;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Fault, FaultPlan, SimCard, SimSleep, SimulatedController};
+    use std::vec;
+
+    const IMAGE_SIZE: usize = 1024 * 1024;
+
+    fn image() -> vec::Vec<u8> {
+        (0..IMAGE_SIZE).map(|i| (i / BLOCK_SIZE) as u8).collect()
+    }
+
+    fn driver(card: SimCard) -> Vf2SdDriver<SimulatedController, SimSleep> {
+        let mut driver = Vf2SdDriver::new(SimulatedController::new(card));
+        driver.init().unwrap();
+        driver
+    }
+
+    #[test]
+    fn test_clock_divider() {
+        let input = DEFAULT_INPUT_CLOCK_HZ;
+        assert_eq!(clock_divider(input, IDENT_CLOCK_HZ), 63);
+        assert!(clock_rate(input, 63) <= IDENT_CLOCK_HZ);
+        assert_eq!(clock_divider(input, DEFAULT_SPEED_CLOCK_HZ), 1);
+        assert_eq!(clock_divider(input, HIGH_SPEED_CLOCK_HZ), 0);
+        assert_eq!(clock_rate(input, 0), HIGH_SPEED_CLOCK_HZ);
+        assert_eq!(clock_divider(input, 1), u8::MAX);
+    }
+
+    #[test]
+    fn test_init() {
+        let mut driver =
+            Vf2SdDriver::<_, SimSleep>::new(SimulatedController::new(SimCard::new(image())));
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            driver.read_block(0, &mut buf),
+            Err(Vf2SdDriverError::InitError)
+        );
+        let info = driver.init().unwrap();
+        assert!(info.is_high_capacity());
+        assert_eq!(info.rca(), sim::SIM_RCA);
+        assert_eq!(info.product_name(), "SIM01");
+        assert_eq!(info.spec_version(), (3, 0));
+        assert_eq!(driver.capacity_blocks(), (IMAGE_SIZE / BLOCK_SIZE) as u64);
+        assert_eq!(driver.bus_width(), BusWidth::Bit4);
+        assert!(driver.is_high_speed());
+        assert_eq!(driver.clock(), HIGH_SPEED_CLOCK_HZ);
+        assert!(driver.io.with_card(|card| card.unwrap().is_bus_width4()));
+        // a failed init does not leave the previous card state behind
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(2, Fault::ResponseTimeout));
+        assert!(driver.init().is_err());
+        assert!(driver.card_info().is_none());
+        assert_eq!(
+            driver.read_block(0, &mut buf),
+            Err(Vf2SdDriverError::InitError)
+        );
+        driver.init().unwrap();
+        driver.read_block(0, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_read_write_block() {
+        let mut driver = driver(SimCard::new(image()));
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(5, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 5));
+        driver.write_block(7, &[0xa5; BLOCK_SIZE]).unwrap();
+        driver.read_block(7, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xa5));
+        driver.io.with_card(|card| {
+            let image = card.unwrap().image();
+            assert!(image[7 * BLOCK_SIZE..8 * BLOCK_SIZE]
+                .iter()
+                .all(|&b| b == 0xa5));
+            assert!(image[8 * BLOCK_SIZE..9 * BLOCK_SIZE]
+                .iter()
+                .all(|&b| b == 8));
+        });
+    }
+
+    #[test]
+    fn test_read_write_blocks() {
+        let mut driver = driver(SimCard::new(image()));
+        let mut buf = vec![0u8; 8 * BLOCK_SIZE];
+        driver.read_blocks(16, &mut buf).unwrap();
+        for (i, block) in buf.chunks(BLOCK_SIZE).enumerate() {
+            assert!(block.iter().all(|&b| b == 16 + i as u8));
+        }
+        let data: vec::Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| i as u8).collect();
+        driver.write_blocks(100, &data).unwrap();
+        let mut buf = vec![0u8; 4 * BLOCK_SIZE];
+        driver.read_blocks(100, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_standard_capacity() {
+        let mut driver = driver(SimCard::new(image()).with_standard_capacity());
+        assert!(!driver.card_info().unwrap().is_high_capacity());
+        assert_eq!(driver.capacity_blocks(), (IMAGE_SIZE / BLOCK_SIZE) as u64);
+        driver.write_block(3, &[0x5a; BLOCK_SIZE]).unwrap();
+        driver.io.with_card(|card| {
+            let image = card.unwrap().image();
+            assert!(image[3 * BLOCK_SIZE..4 * BLOCK_SIZE]
+                .iter()
+                .all(|&b| b == 0x5a));
+        });
+    }
+
+    #[test]
+    fn test_version1() {
+        let mut driver = driver(SimCard::new(image()).with_version1());
+        assert_eq!(driver.card_info().unwrap().spec_version(), (1, 0));
+        assert!(!driver.is_high_speed());
+        assert_eq!(driver.clock(), DEFAULT_SPEED_CLOCK_HZ);
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(9, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 9));
+    }
+
+    #[test]
+    fn test_mmc() {
+        let mut driver = Vf2SdDriver::<_, SimSleep>::new(SimulatedController::new(
+            SimCard::new(image()).with_mmc(),
+        ))
+        .with_max_bus_width(BusWidth::Bit8);
+        driver.init().unwrap();
+        let info = *driver.card_info().unwrap();
+        assert!(info.is_mmc());
+        assert!(info.is_high_capacity());
+        assert_eq!(info.rca(), MMC_RCA);
+        assert_eq!(info.product_name(), "SIMMC1");
+        assert_eq!(info.manufacture_date(), (2024, 1));
+        assert_eq!(info.ext_csd().unwrap().sec_count(), 2048);
+        assert_eq!(driver.capacity_blocks(), (IMAGE_SIZE / BLOCK_SIZE) as u64);
+        assert_eq!(driver.bus_width(), BusWidth::Bit8);
+        assert!(driver.is_high_speed());
+        // 52 MHz is above the input clock
+        assert_eq!(driver.clock(), DEFAULT_INPUT_CLOCK_HZ);
+        driver.io.with_card(|card| {
+            let card = card.unwrap();
+            assert!(card.is_bus_width8() && card.is_high_speed());
+        });
+        let mut buf = [0u8; BLOCK_SIZE * 2];
+        driver.write_blocks(4, &[0x3c; BLOCK_SIZE * 2]).unwrap();
+        driver.read_blocks(3, &mut buf).unwrap();
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 3));
+        assert!(buf[BLOCK_SIZE..].iter().all(|&b| b == 0x3c));
+    }
+
+    #[test]
+    fn test_mmc_partition() {
+        let mut driver = driver(SimCard::new(image()).with_mmc());
+        let info = *driver.card_info().unwrap();
+        assert_eq!(info.partition_bytes(HwPartition::User), IMAGE_SIZE as u64);
+        assert_eq!(info.partition_bytes(HwPartition::Boot1), 128 * 1024);
+        assert_eq!(info.partition_bytes(HwPartition::General(0)), 0);
+        assert_eq!(driver.partition(), HwPartition::User);
+        assert_eq!(
+            driver.select_partition(HwPartition::General(0)),
+            Err(Vf2SdDriverError::Unsupported)
+        );
+        driver.select_partition(HwPartition::Boot2).unwrap();
+        assert_eq!(driver.partition(), HwPartition::Boot2);
+        driver.write_block(1, &[0xa5; BLOCK_SIZE]).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xa5));
+        driver.io.with_card(|card| {
+            let card = card.unwrap();
+            assert_eq!(card.ext_csd()[EXT_CSD_PARTITION_CONFIG as usize], 2);
+            assert!(card.boot_partition(1).iter().all(|&b| b == 0));
+            assert!(card.boot_partition(2)[BLOCK_SIZE..BLOCK_SIZE * 2]
+                .iter()
+                .all(|&b| b == 0xa5));
+        });
+        driver.select_partition(HwPartition::User).unwrap();
+        driver.read_block(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_sd_partition() {
+        let mut driver = driver(SimCard::new(image()));
+        assert_eq!(driver.partition(), HwPartition::User);
+        assert_eq!(
+            driver.select_partition(HwPartition::Boot1),
+            Err(Vf2SdDriverError::Unsupported)
+        );
+        driver.select_partition(HwPartition::User).unwrap();
+    }
+
+    fn block_filled(driver: &mut Vf2SdDriver<SimulatedController, SimSleep>, block: usize) -> u8 {
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(block, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == buf[0]));
+        buf[0]
+    }
+
+    #[test]
+    fn test_dma_read_write() {
+        let mut driver = driver(SimCard::new(image()));
+        // SAFETY: the descriptors and buffers below outlive the transfers
+        unsafe { driver.io.enable_dma() };
+        let mut descs = [IdmacDesc::new(); 4];
+        let desc_addr = descs.as_mut_ptr() as usize;
+        let mut ring = IdmacRing::new(&mut descs, desc_addr);
+        // more than one descriptor can hold
+        let data = vec![0x5a_u8; BLOCK_SIZE * 9];
+        let segments = [DmaSegment::new(data.as_ptr() as usize, data.len())];
+        let res = unsafe { driver.write_blocks_dma(3, &mut ring, &segments) };
+        assert_eq!(res, Ok(data.len()));
+        assert!(ring.descs().iter().all(|desc| !desc.is_owned()));
+        driver.io.with_card(|card| {
+            let image = card.unwrap().image();
+            assert!(image[BLOCK_SIZE * 3..BLOCK_SIZE * 12]
+                .iter()
+                .all(|&b| b == 0x5a));
+            assert!(image[BLOCK_SIZE * 12..BLOCK_SIZE * 13]
+                .iter()
+                .all(|&b| b == 12));
+        });
+        let mut buf = vec![0u8; BLOCK_SIZE * 3];
+        let segments = [
+            DmaSegment::new(buf.as_mut_ptr() as usize, BLOCK_SIZE),
+            DmaSegment::new(buf.as_mut_ptr() as usize + BLOCK_SIZE, BLOCK_SIZE * 2),
+        ];
+        let res = unsafe { driver.read_blocks_dma(2, &mut ring, &segments) };
+        assert_eq!(res, Ok(BLOCK_SIZE * 3));
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 2));
+        assert!(buf[BLOCK_SIZE..].iter().all(|&b| b == 0x5a));
+        let segments = [DmaSegment::new(buf.as_mut_ptr() as usize, BLOCK_SIZE)];
+        let res = unsafe { driver.read_blocks_dma(20, &mut ring, &segments) };
+        assert_eq!(res, Ok(BLOCK_SIZE));
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 20));
+    }
+
+    #[test]
+    fn test_dma_bus_error() {
+        let mut driver = driver(SimCard::new(image()));
+        let mut descs = [IdmacDesc::new(); 2];
+        let desc_addr = descs.as_mut_ptr() as usize;
+        let mut ring = IdmacRing::new(&mut descs, desc_addr);
+        let mut buf = vec![0u8; BLOCK_SIZE * 2];
+        let segments = [DmaSegment::new(buf.as_mut_ptr() as usize, buf.len())];
+        // nothing is mapped for the IDMAC yet
+        let res = unsafe { driver.read_blocks_dma(0, &mut ring, &segments) };
+        let Err(Vf2SdDriverError::DmaError(status)) = res else {
+            panic!("unexpected result {:?}", res);
+        };
+        let status = IdmacStatusReg::from(status);
+        assert!(status.fbe() && status.ais() && status.eb() == 2);
+        assert!(driver.status().unwrap().is_transfer_ready());
+
+        // SAFETY: the descriptors and buffers below outlive the transfers
+        unsafe { driver.io.enable_dma() };
+        driver
+            .io
+            .set_fault_plan(FaultPlan::new().on_cmd(25, Fault::DmaBusError));
+        buf.fill(0xee);
+        let res = unsafe { driver.write_blocks_dma(4, &mut ring, &segments) };
+        let Err(Vf2SdDriverError::DmaError(status)) = res else {
+            panic!("unexpected result {:?}", res);
+        };
+        assert_eq!(IdmacStatusReg::from(status).eb(), 1);
+        assert!(driver.status().unwrap().is_transfer_ready());
+        driver.io.with_card(|card| {
+            assert!(card.unwrap().image()[BLOCK_SIZE * 4..BLOCK_SIZE * 6]
+                .iter()
+                .all(|&b| b != 0xee));
+        });
+        let res = unsafe { driver.read_blocks_dma(4, &mut ring, &segments) };
+        assert_eq!(res, Ok(BLOCK_SIZE * 2));
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 4));
+    }
+
+    #[test]
+    fn test_erase() {
+        let mut driver = driver(SimCard::new(image()));
+        let info = *driver.card_info().unwrap();
+        assert_eq!(info.erase_unit_blocks(), 1);
+        assert_eq!(info.erased_byte(), 0xff);
+        driver.erase(8, 4).unwrap();
+        assert_eq!(block_filled(&mut driver, 7), 7);
+        assert!((8..12).all(|block| block_filled(&mut driver, block) == 0xff));
+        assert_eq!(block_filled(&mut driver, 12), 12);
+        driver.erase(8, 0).unwrap();
+        let blocks = IMAGE_SIZE / BLOCK_SIZE;
+        assert_eq!(
+            driver.erase(blocks - 1, 2),
+            Err(Vf2SdDriverError::OutOfRange(blocks))
+        );
+        assert_eq!(driver.trim(8, 1), Err(Vf2SdDriverError::Unsupported));
+        assert_eq!(driver.discard(8, 1), Err(Vf2SdDriverError::Unsupported));
+    }
+
+    #[test]
+    fn test_erase_standard_capacity() {
+        let mut driver = driver(SimCard::new(image()).with_standard_capacity());
+        driver.erase(3, 1).unwrap();
+        assert_eq!(block_filled(&mut driver, 2), 2);
+        assert_eq!(block_filled(&mut driver, 3), 0xff);
+        assert_eq!(block_filled(&mut driver, 4), 4);
+    }
+
+    #[test]
+    fn test_mmc_erase() {
+        let mut driver = driver(SimCard::new(image()).with_mmc());
+        let info = *driver.card_info().unwrap();
+        // 512 KiB high capacity erase groups
+        assert_eq!(info.erase_unit_blocks(), 1024);
+        assert_eq!(info.erased_byte(), 0);
+        assert_eq!(driver.erase(1, 1), Err(Vf2SdDriverError::Misaligned(1)));
+        driver.erase(1024, 1024).unwrap();
+        assert_eq!(block_filled(&mut driver, 1023), (1023 % 256) as u8);
+        assert!((1024..2048).all(|block| block_filled(&mut driver, block) == 0));
+        driver.trim(3, 2).unwrap();
+        assert_eq!(block_filled(&mut driver, 2), 2);
+        assert_eq!(block_filled(&mut driver, 3), 0);
+        assert_eq!(block_filled(&mut driver, 4), 0);
+        assert_eq!(block_filled(&mut driver, 5), 5);
+        driver.discard(6, 1).unwrap();
+        driver.select_partition(HwPartition::Boot1).unwrap();
+        driver.write_block(0, &[0x5a; BLOCK_SIZE]).unwrap();
+        driver.trim(0, 1).unwrap();
+        assert_eq!(block_filled(&mut driver, 0), 0);
+        assert_eq!(driver.trim(256, 1), Err(Vf2SdDriverError::OutOfRange(256)));
+    }
+
+    #[test]
+    fn test_mmc_erase_byte_addressed() {
+        let mut driver = driver(SimCard::new(image()).with_mmc().with_standard_capacity());
+        // legacy erase groups of the CSD
+        let unit = driver.card_info().unwrap().erase_unit_blocks() as usize;
+        assert_eq!(unit, 32 * 29);
+        driver.erase(unit, unit).unwrap();
+        assert_eq!(block_filled(&mut driver, unit - 1), (unit - 1) as u8);
+        assert_eq!(block_filled(&mut driver, unit), 0);
+        assert_eq!(block_filled(&mut driver, 2 * unit - 1), 0);
+        assert_eq!(block_filled(&mut driver, 2 * unit), (2 * unit) as u8);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut driver =
+            Vf2SdDriver::<_, SimSleep>::new(SimulatedController::new(SimCard::new(image())));
+        assert_eq!(driver.status(), Err(Vf2SdDriverError::InitError));
+        driver.init().unwrap();
+        let status = driver.status().unwrap();
+        assert_eq!(status.state(), CardState::Tran);
+        assert!(status.is_transfer_ready() && !status.has_error());
+    }
+
+    #[test]
+    fn test_wait_programmed() {
+        let mut driver = driver(SimCard::new(image()).with_program_polls(3));
+        driver.write_block(1, &[0x11; BLOCK_SIZE]).unwrap();
+        assert!(driver.status().unwrap().is_transfer_ready());
+        driver.write_blocks(2, &[0x22; BLOCK_SIZE * 3]).unwrap();
+        assert!(driver.status().unwrap().is_transfer_ready());
+        driver.erase(6, 2).unwrap();
+        assert!(driver.status().unwrap().is_transfer_ready());
+        assert_eq!(block_filled(&mut driver, 1), 0x11);
+        assert_eq!(block_filled(&mut driver, 4), 0x22);
+        assert_eq!(block_filled(&mut driver, 7), 0xff);
+    }
+
+    #[test]
+    fn test_wait_programmed_timeout() {
+        let mut driver = driver(SimCard::new(image()).with_program_polls(usize::MAX));
+        assert_eq!(
+            driver.write_block(1, &[0; BLOCK_SIZE]),
+            Err(Vf2SdDriverError::TimeoutError)
+        );
+        let status = driver.status().unwrap();
+        assert_eq!(status.state(), CardState::Prg);
+        assert!(!status.ready_for_data());
+    }
+
+    #[test]
+    fn test_mmc_bus_width4() {
+        let mut driver = Vf2SdDriver::<_, SimSleep>::new(SimulatedController::new(
+            SimCard::new(image())
+                .with_mmc()
+                .with_high_speed_support(false),
+        ));
+        driver.init().unwrap();
+        // 8-bit only if the slot is known to have all lines wired
+        assert_eq!(driver.bus_width(), BusWidth::Bit4);
+        assert!(!driver.is_high_speed());
+        assert_eq!(driver.clock(), DEFAULT_SPEED_CLOCK_HZ);
+        assert!(driver.io.with_card(|card| card.unwrap().is_bus_width4()));
+        assert_eq!(
+            driver.set_clock(HIGH_SPEED_CLOCK_HZ),
+            Ok(DEFAULT_SPEED_CLOCK_HZ)
+        );
+        assert_eq!(
+            driver.set_clock(IDENT_CLOCK_HZ),
+            Ok(clock_rate(DEFAULT_INPUT_CLOCK_HZ, 63))
+        );
+    }
+
+    #[test]
+    fn test_mmc_byte_addressed() {
+        let mut driver = driver(SimCard::new(image()).with_mmc().with_standard_capacity());
+        assert!(!driver.card_info().unwrap().is_high_capacity());
+        assert_eq!(driver.capacity_blocks(), (IMAGE_SIZE / BLOCK_SIZE) as u64);
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(9, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 9));
+    }
+
+    #[test]
+    fn test_sd_max_bus_width() {
+        let mut driver =
+            Vf2SdDriver::<_, SimSleep>::new(SimulatedController::new(SimCard::new(image())))
+                .with_max_bus_width(BusWidth::Bit1);
+        driver.init().unwrap();
+        assert_eq!(driver.bus_width(), BusWidth::Bit1);
+        assert!(!driver.io.with_card(|card| card.unwrap().is_bus_width4()));
+    }
+
+    #[test]
+    fn test_no_card() {
+        let mut driver = Vf2SdDriver::<_, SimSleep>::new(SimulatedController::empty());
+        assert_eq!(driver.init().unwrap_err(), Vf2SdDriverError::NoCard);
+        assert!(!driver.is_card_present());
+        let mut driver =
+            Vf2SdDriver::<_, SimSleep>::new(SimulatedController::empty()).with_card_detect(false);
+        assert!(driver.is_card_present());
+        assert_eq!(
+            driver.init().unwrap_err(),
+            Vf2SdDriverError::ResponseTimeout(Cmd::AppCmd)
+        );
+    }
+
+    #[test]
+    fn test_card_detect() {
+        let mut driver = driver(SimCard::new(image()));
+        assert!(driver.is_card_present());
+        assert_eq!(read_reg(&driver.io, DEBNCE_REG), 1_250_000);
+        assert_eq!(driver.take_card_event(), None);
+        let card = driver.io.remove_card().unwrap();
+        assert!(!driver.is_card_present());
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            driver.read_block(1, &mut buf),
+            Err(Vf2SdDriverError::NoCard)
+        );
+        assert!(driver.card_info().is_none());
+        assert_eq!(driver.take_card_event(), Some(CardEvent::Removed));
+        assert_eq!(driver.take_card_event(), None);
+        assert_eq!(driver.status(), Err(Vf2SdDriverError::NoCard));
+
+        driver.io.insert_card(card.with_mmc());
+        assert_eq!(
+            driver.write_block(1, &buf),
+            Err(Vf2SdDriverError::InitError)
+        );
+        assert_eq!(driver.take_card_event(), Some(CardEvent::Inserted));
+        assert!(driver.init().unwrap().is_mmc());
+        driver.read_block(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_command() {
+        let mut driver = driver(SimCard::new(image()));
+        let R1(status) = driver
+            .command::<R1>(Cmd::SendStatus, sim::SIM_RCA << 16)
+            .unwrap();
+        assert!(status.is_transfer_ready());
+        assert_eq!(
+            driver.command::<R3>(Cmd::SendStatus, sim::SIM_RCA << 16),
+            Err(Vf2SdDriverError::InvalidResponse(Cmd::SendStatus))
+        );
+        assert_eq!(
+            driver.command::<R1>(Cmd::ReadSingleBlock, 0),
+            Err(Vf2SdDriverError::InvalidResponse(Cmd::ReadSingleBlock))
+        );
+        // ACMD42 is not known to the card
+        assert_eq!(
+            driver.command::<R1>(Cmd::SetClrCardDetect, 0),
+            Err(Vf2SdDriverError::ResponseTimeout(Cmd::SetClrCardDetect))
+        );
+        driver.read_block(0, &mut [0u8; BLOCK_SIZE]).unwrap();
+    }
+
+    #[test]
+    fn test_raw_cmd() {
+        let mut driver = driver(SimCard::new(image()));
+        let send_status = RawCmd {
+            index: 13,
+            app: false,
+            response: ResponseType::R1,
+            data: DataDir::None,
+        };
+        let resp = driver
+            .raw_cmd(send_status, sim::SIM_RCA << 16, &mut [])
+            .unwrap();
+        assert_eq!(CardStatus::from(resp[0]).state(), CardState::Tran);
+        let send_scr = RawCmd {
+            index: 51,
+            app: true,
+            response: ResponseType::R1,
+            data: DataDir::Read,
+        };
+        let mut scr = [0u8; 8];
+        driver.raw_cmd(send_scr, 0, &mut scr).unwrap();
+        assert_eq!(
+            Scr::new(u64::from_be_bytes(scr)).bus_widths(),
+            driver.card_info().unwrap().scr().bus_widths()
+        );
+        assert_eq!(
+            driver.raw_cmd(send_scr, 0, &mut [0u8; 12]),
+            Err(Vf2SdDriverError::BufferSizeError)
+        );
+        let cmd = Cmd::ReadSingleBlock;
+        assert_eq!(
+            send_cmd::<_, SimSleep>(
+                &mut driver.io,
+                cmd,
+                cmd.into(),
+                CmdArg::new(0),
+                DataTransType::None
+            ),
+            Err(Vf2SdDriverError::BufferSizeError)
+        );
+        assert!(driver.status().unwrap().is_transfer_ready());
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut driver = driver(SimCard::new(image()));
+        let mut buf = [0u8; BLOCK_SIZE];
+        let blocks = IMAGE_SIZE / BLOCK_SIZE;
+        assert_eq!(
+            driver.read_block(blocks, &mut buf).unwrap_err(),
+            Vf2SdDriverError::DataReadTimeout(Cmd::ReadSingleBlock)
+        );
+        // the card is still usable
+        driver.read_block(blocks - 1, &mut buf).unwrap();
+    }
+
+    fn faulty_driver(plan: FaultPlan) -> Vf2SdDriver<SimulatedController, SimSleep> {
+        let mut driver = driver(SimCard::new(image()));
+        driver.io.set_fault_plan(plan);
+        driver
+    }
+
+    #[test]
+    fn test_fault_response_timeout() {
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(17, Fault::ResponseTimeout));
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            driver.read_block(1, &mut buf),
+            Err(Vf2SdDriverError::ResponseTimeout(Cmd::ReadSingleBlock))
+        );
+        // the fault only hits once
+        driver.read_block(1, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
     }
-    pub fn read_block(&mut self, block: usize, buf: &mut [u8]) {
-        read_block::<_, S>(&mut self.io, block, buf).unwrap();
+
+    #[test]
+    fn test_fault_response_crc() {
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(24, Fault::ResponseCrc));
+        let err = driver.write_block(1, &[0; BLOCK_SIZE]).unwrap_err();
+        assert_eq!(err, Vf2SdDriverError::ResponseCrc(Cmd::WriteSingleBlock));
+        assert!(err.is_transient());
     }
-    pub fn write_block(&mut self, block: usize, buf: &[u8]) {
-        write_block::<_, S>(&mut self.io, block, buf).unwrap();
+
+    #[test]
+    fn test_fault_data_crc() {
+        let plan = FaultPlan::new()
+            .on_cmd(18, Fault::DataCrc)
+            .on_cmd(25, Fault::DataCrc);
+        let mut driver = faulty_driver(plan);
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+        assert_eq!(
+            driver.read_blocks(1, &mut buf),
+            Err(Vf2SdDriverError::DataCrc(Cmd::ReadMultipleBlock))
+        );
+        assert_eq!(
+            driver.write_blocks(1, &[0xff; 2 * BLOCK_SIZE]),
+            Err(Vf2SdDriverError::DataCrc(Cmd::WriteMultipleBlock))
+        );
+        // the card did not store the data
+        driver.read_blocks(1, &mut buf).unwrap();
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_fault_fifo_underrun() {
+        let mut driver = faulty_driver(FaultPlan::new().on_nth_cmd(17, 2, Fault::FifoUnderrun));
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(1, &mut buf).unwrap();
+        assert_eq!(
+            driver.read_block(1, &mut buf),
+            Err(Vf2SdDriverError::FifoOverrun(Cmd::ReadSingleBlock))
+        );
+    }
+
+    #[test]
+    fn test_fault_remove_card() {
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(18, Fault::RemoveCard));
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+        assert_eq!(
+            driver.read_blocks(1, &mut buf),
+            Err(Vf2SdDriverError::DataReadTimeout(Cmd::ReadMultipleBlock))
+        );
+        assert!(driver.io.with_card(|card| card.is_none()));
+        assert_eq!(
+            driver.read_block(1, &mut buf[..BLOCK_SIZE]),
+            Err(Vf2SdDriverError::NoCard)
+        );
+        assert_eq!(driver.take_card_event(), Some(CardEvent::Removed));
+
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(24, Fault::RemoveCard));
+        assert_eq!(
+            driver.write_block(1, &[0; BLOCK_SIZE]),
+            Err(Vf2SdDriverError::EndBitError(Cmd::WriteSingleBlock))
+        );
+    }
+
+    #[test]
+    fn test_fault_data_busy() {
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(24, Fault::DataBusy));
+        driver.write_block(1, &[0; BLOCK_SIZE]).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            driver.read_block(1, &mut buf),
+            Err(Vf2SdDriverError::TimeoutError)
+        );
+        driver.io.clear_faults();
+        driver.read_block(1, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_fault_erase_busy() {
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(38, Fault::DataBusy));
+        assert_eq!(driver.erase(0, 1), Err(Vf2SdDriverError::TimeoutError));
+        driver.io.clear_faults();
+        driver.erase(0, 1).unwrap();
+    }
+
+    #[test]
+    fn test_fault_stuck_start_cmd() {
+        let mut driver = faulty_driver(FaultPlan::new().on_cmd(13, Fault::StuckStartCmd));
+        let cmd13 = CmdReg::with_no_data(0, 13);
+        let arg = CmdArg::new(sim::SIM_RCA << 16);
+        assert_eq!(
+            send_cmd::<_, SimSleep>(
+                &mut driver.io,
+                Cmd::SendStatus,
+                cmd13,
+                arg,
+                DataTransType::None
+            ),
+            Err(Vf2SdDriverError::TimeoutError)
+        );
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            driver.read_block(1, &mut buf),
+            Err(Vf2SdDriverError::TimeoutError)
+        );
+        assert_eq!(
+            driver.write_block(1, &buf),
+            Err(Vf2SdDriverError::TimeoutError)
+        );
+        driver.io.clear_faults();
+        driver.read_block(1, &mut buf).unwrap();
     }
 }
@@ -0,0 +1,540 @@
+//! MBR and GPT partition tables
+use crate::{Result, SDIo, SleepOps, Vf2SdDriver, Vf2SdDriverError, BLOCK_SIZE};
+use core::fmt::{Display, Formatter};
+use log::warn;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+/// Upper bound of GPT entries accepted, the spec minimum is 128
+const GPT_MAX_ENTRIES: u32 = 1024;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_PROTECTIVE: u8 = 0xee;
+const NAME_LEN: usize = 36;
+
+/// CRC32 (IEEE 802.3) used by the GPT header and entry array
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// GUID as stored on disk (first three fields little endian)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+    /// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    /// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ]);
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            le_u32(b, 0),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|x| write!(f, "{:02X}", x))
+    }
+}
+
+/// Partition type
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR system id
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+/// One entry of the partition table
+#[derive(Debug, Copy, Clone)]
+pub struct Partition {
+    index: usize,
+    ty: PartitionType,
+    unique_guid: Option<Guid>,
+    first_block: u64,
+    blocks: u64,
+    name: [u16; NAME_LEN],
+}
+
+impl Partition {
+    /// Index of the entry in the table
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        self.ty
+    }
+
+    /// Unique partition GUID, GPT only
+    pub fn unique_guid(&self) -> Option<Guid> {
+        self.unique_guid
+    }
+
+    pub fn first_block(&self) -> u64 {
+        self.first_block
+    }
+
+    /// Last block of the partition, inclusive
+    pub fn last_block(&self) -> u64 {
+        self.first_block + self.blocks - 1
+    }
+
+    pub fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// UTF-16 partition name without the trailing zeros, empty for MBR
+    pub fn name_utf16(&self) -> &[u16] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(NAME_LEN);
+        &self.name[..len]
+    }
+
+    /// Partition name, invalid UTF-16 is replaced by `U+FFFD`
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.name_utf16().iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// Whether the partition name equals `name`
+    pub fn name_eq(&self, name: &str) -> bool {
+        self.name().eq(name.chars())
+    }
+
+    /// Block access bounded to this partition
+    pub fn view<'a, T: SDIo, S: SleepOps>(
+        &self,
+        driver: &'a mut Vf2SdDriver<T, S>,
+    ) -> PartitionView<'a, T, S> {
+        PartitionView {
+            driver,
+            first_block: self.first_block,
+            blocks: self.blocks,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct MbrEntry {
+    ty: u8,
+    first_block: u32,
+    blocks: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Table {
+    Mbr([MbrEntry; 4]),
+    Gpt {
+        entries_block: u64,
+        entries: u32,
+        entry_size: u32,
+    },
+}
+
+/// Partition table of the card, entries are read on demand
+#[derive(Debug, Copy, Clone)]
+pub struct PartitionTable {
+    table: Table,
+}
+
+impl PartitionTable {
+    /// Read the primary GPT, the backup GPT if the primary one is corrupted,
+    /// or the MBR if neither is valid
+    pub fn read<T: SDIo, S: SleepOps>(driver: &mut Vf2SdDriver<T, S>) -> Result<Self> {
+        let mut mbr = [0u8; BLOCK_SIZE];
+        driver.read_block(0, &mut mbr)?;
+        let last_block = driver.capacity_blocks().saturating_sub(1);
+        for lba in [1, last_block] {
+            if let Some(table) = read_gpt(driver, lba)? {
+                return Ok(Self { table });
+            }
+        }
+        if mbr[510..] != MBR_SIGNATURE {
+            return Err(Vf2SdDriverError::NoPartitionTable);
+        }
+        let entries = core::array::from_fn(|i| {
+            let entry = &mbr[MBR_TABLE_OFFSET + i * 16..MBR_TABLE_OFFSET + (i + 1) * 16];
+            MbrEntry {
+                ty: entry[4],
+                first_block: le_u32(entry, 8),
+                blocks: le_u32(entry, 12),
+            }
+        });
+        Ok(Self {
+            table: Table::Mbr(entries),
+        })
+    }
+
+    pub fn is_gpt(&self) -> bool {
+        matches!(self.table, Table::Gpt { .. })
+    }
+
+    /// Number of entries in the table, including unused ones
+    pub fn len(&self) -> usize {
+        match self.table {
+            Table::Mbr(_) => 4,
+            Table::Gpt { entries, .. } => entries as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Entry `index` of the table, `None` if it is unused
+    pub fn get<T: SDIo, S: SleepOps>(
+        &self,
+        driver: &mut Vf2SdDriver<T, S>,
+        index: usize,
+    ) -> Result<Option<Partition>> {
+        if index >= self.len() {
+            return Ok(None);
+        }
+        let mut partition = Partition {
+            index,
+            ty: PartitionType::Mbr(0),
+            unique_guid: None,
+            first_block: 0,
+            blocks: 0,
+            name: [0; NAME_LEN],
+        };
+        match self.table {
+            Table::Mbr(entries) => {
+                let entry = entries[index];
+                if entry.ty == 0 || entry.ty == MBR_PROTECTIVE || entry.blocks == 0 {
+                    return Ok(None);
+                }
+                partition.ty = PartitionType::Mbr(entry.ty);
+                partition.first_block = entry.first_block as u64;
+                partition.blocks = entry.blocks as u64;
+            }
+            Table::Gpt {
+                entries_block,
+                entry_size,
+                ..
+            } => {
+                let offset = index as u64 * entry_size as u64;
+                let block = entries_block + offset / BLOCK_SIZE as u64;
+                let offset = (offset % BLOCK_SIZE as u64) as usize;
+                let mut buf = [0u8; BLOCK_SIZE];
+                driver.read_block(block as usize, &mut buf)?;
+                let entry = &buf[offset..offset + entry_size as usize];
+                let ty = Guid(entry[..16].try_into().unwrap());
+                let first = le_u64(entry, 32);
+                let last = le_u64(entry, 40);
+                if ty == Guid::UNUSED || last < first {
+                    return Ok(None);
+                }
+                partition.ty = PartitionType::Gpt(ty);
+                partition.unique_guid = Some(Guid(entry[16..32].try_into().unwrap()));
+                partition.first_block = first;
+                partition.blocks = last - first + 1;
+                for (i, c) in partition.name.iter_mut().enumerate() {
+                    *c = u16::from_le_bytes([entry[56 + i * 2], entry[57 + i * 2]]);
+                }
+            }
+        }
+        Ok(Some(partition))
+    }
+
+    /// Find the first used entry matching `f`
+    pub fn find<T: SDIo, S: SleepOps>(
+        &self,
+        driver: &mut Vf2SdDriver<T, S>,
+        mut f: impl FnMut(&Partition) -> bool,
+    ) -> Result<Option<Partition>> {
+        for index in 0..self.len() {
+            if let Some(partition) = self.get(driver, index)? {
+                if f(&partition) {
+                    return Ok(Some(partition));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Validate the GPT header at `lba` and its entry array
+fn read_gpt<T: SDIo, S: SleepOps>(
+    driver: &mut Vf2SdDriver<T, S>,
+    lba: u64,
+) -> Result<Option<Table>> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    driver.read_block(lba as usize, &mut buf)?;
+    if &buf[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = le_u32(&buf, 12) as usize;
+    if !(GPT_HEADER_SIZE..=BLOCK_SIZE).contains(&header_size) {
+        return Ok(None);
+    }
+    let header_crc = le_u32(&buf, 16);
+    buf[16..20].fill(0);
+    if crc32_update(0, &buf[..header_size]) != header_crc || le_u64(&buf, 24) != lba {
+        warn!("invalid gpt header at block {}", lba);
+        return Ok(None);
+    }
+    let entries_block = le_u64(&buf, 72);
+    let entries = le_u32(&buf, 80);
+    let entry_size = le_u32(&buf, 84);
+    let entries_crc = le_u32(&buf, 88);
+    // entries never straddle two blocks
+    if !entry_size.is_power_of_two()
+        || !(128..=BLOCK_SIZE as u32).contains(&entry_size)
+        || entries > GPT_MAX_ENTRIES
+    {
+        return Ok(None);
+    }
+    let mut remain = entries as usize * entry_size as usize;
+    let mut crc = 0;
+    let mut block = entries_block;
+    while remain > 0 {
+        driver.read_block(block as usize, &mut buf)?;
+        let len = remain.min(BLOCK_SIZE);
+        crc = crc32_update(crc, &buf[..len]);
+        remain -= len;
+        block += 1;
+    }
+    if crc != entries_crc {
+        warn!("invalid gpt entries at block {}", entries_block);
+        return Ok(None);
+    }
+    Ok(Some(Table::Gpt {
+        entries_block,
+        entries,
+        entry_size,
+    }))
+}
+
+/// Block access relative to the start of a partition
+///
+/// Requests which do not fit in the partition fail with [`Vf2SdDriverError::OutOfRange`].
+pub struct PartitionView<'a, T, S> {
+    driver: &'a mut Vf2SdDriver<T, S>,
+    first_block: u64,
+    blocks: u64,
+}
+
+impl<T: SDIo, S: SleepOps> PartitionView<'_, T, S> {
+    pub fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn card_block(&self, block: usize, len: usize) -> Result<usize> {
+        let count = len.div_ceil(BLOCK_SIZE).max(1) as u64;
+        match (block as u64).checked_add(count) {
+            Some(end) if end <= self.blocks => Ok((self.first_block + block as u64) as usize),
+            _ => Err(Vf2SdDriverError::OutOfRange(block)),
+        }
+    }
+
+    pub fn read_block(&mut self, block: usize, buf: &mut [u8]) -> Result<usize> {
+        let block = self.card_block(block, BLOCK_SIZE)?;
+        self.driver.read_block(block, buf)
+    }
+    pub fn write_block(&mut self, block: usize, buf: &[u8]) -> Result<usize> {
+        let block = self.card_block(block, BLOCK_SIZE)?;
+        self.driver.write_block(block, buf)
+    }
+    pub fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<usize> {
+        let block = self.card_block(block, buf.len())?;
+        self.driver.read_blocks(block, buf)
+    }
+    pub fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<usize> {
+        let block = self.card_block(block, buf.len())?;
+        self.driver.write_blocks(block, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimCard, SimSleep, SimulatedController};
+    use std::vec;
+    use std::vec::Vec;
+
+    const IMAGE_BLOCKS: usize = 2048;
+
+    fn put_gpt(image: &mut [u8], header_lba: u64, entries_lba: u64) {
+        let mut entries = vec![0u8; 128 * 128];
+        let parts: [(Guid, u64, u64, &str); 2] = [
+            (Guid::EFI_SYSTEM, 64, 127, "boot"),
+            (Guid::LINUX_FILESYSTEM, 128, 1983, "rootfs"),
+        ];
+        for (i, (ty, first, last, name)) in parts.iter().enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[..16].copy_from_slice(&ty.0);
+            entry[16] = i as u8 + 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let mut header = [0u8; GPT_HEADER_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32_update(0, &entries).to_le_bytes());
+        let crc = crc32_update(0, &header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        let at = header_lba as usize * BLOCK_SIZE;
+        image[at..at + GPT_HEADER_SIZE].copy_from_slice(&header);
+        let at = entries_lba as usize * BLOCK_SIZE;
+        image[at..at + entries.len()].copy_from_slice(&entries);
+    }
+
+    fn put_mbr(image: &mut [u8], entries: &[(u8, u32, u32)]) {
+        for (i, (ty, first, blocks)) in entries.iter().enumerate() {
+            let entry = &mut image[MBR_TABLE_OFFSET + i * 16..MBR_TABLE_OFFSET + (i + 1) * 16];
+            entry[4] = *ty;
+            entry[8..12].copy_from_slice(&first.to_le_bytes());
+            entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+        }
+        image[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn gpt_image() -> Vec<u8> {
+        let mut image = vec![0u8; IMAGE_BLOCKS * BLOCK_SIZE];
+        put_mbr(&mut image, &[(MBR_PROTECTIVE, 1, IMAGE_BLOCKS as u32 - 1)]);
+        put_gpt(&mut image, 1, 2);
+        put_gpt(
+            &mut image,
+            IMAGE_BLOCKS as u64 - 1,
+            IMAGE_BLOCKS as u64 - 33,
+        );
+        image
+    }
+
+    fn driver(image: Vec<u8>) -> Vf2SdDriver<SimulatedController, SimSleep> {
+        let mut driver = Vf2SdDriver::new(SimulatedController::new(SimCard::new(image)));
+        driver.init().unwrap();
+        driver
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32_update(crc32_update(0, b"1234"), b"56789"),
+            0xcbf4_3926
+        );
+    }
+
+    #[test]
+    fn test_guid_display() {
+        let s = std::format!("{}", Guid::EFI_SYSTEM);
+        assert_eq!(s, "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+    }
+
+    #[test]
+    fn test_gpt() {
+        let mut driver = driver(gpt_image());
+        let table = PartitionTable::read(&mut driver).unwrap();
+        assert!(table.is_gpt());
+        assert_eq!(table.len(), 128);
+        let boot = table.get(&mut driver, 0).unwrap().unwrap();
+        assert_eq!(boot.partition_type(), PartitionType::Gpt(Guid::EFI_SYSTEM));
+        assert_eq!((boot.first_block(), boot.last_block()), (64, 127));
+        assert!(boot.name_eq("boot"));
+        assert!(table.get(&mut driver, 2).unwrap().is_none());
+        let root = table
+            .find(&mut driver, |p| p.name_eq("rootfs"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.index(), 1);
+        assert_eq!(root.num_blocks(), 1856);
+    }
+
+    #[test]
+    fn test_gpt_backup() {
+        let mut image = gpt_image();
+        // corrupt the primary entry array
+        image[2 * BLOCK_SIZE] ^= 1;
+        let mut driver = driver(image);
+        let table = PartitionTable::read(&mut driver).unwrap();
+        assert!(table.is_gpt());
+        let boot = table.get(&mut driver, 0).unwrap().unwrap();
+        assert_eq!(boot.first_block(), 64);
+    }
+
+    #[test]
+    fn test_mbr() {
+        let mut image = vec![0u8; IMAGE_BLOCKS * BLOCK_SIZE];
+        put_mbr(&mut image, &[(0x0c, 2048 / 4, 512), (0x83, 1024, 1024)]);
+        let mut driver = driver(image);
+        let table = PartitionTable::read(&mut driver).unwrap();
+        assert!(!table.is_gpt());
+        let p = table.get(&mut driver, 1).unwrap().unwrap();
+        assert_eq!(p.partition_type(), PartitionType::Mbr(0x83));
+        assert_eq!((p.first_block(), p.num_blocks()), (1024, 1024));
+        assert_eq!(p.name().count(), 0);
+        assert!(table.get(&mut driver, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_no_table() {
+        let mut driver = driver(vec![0u8; IMAGE_BLOCKS * BLOCK_SIZE]);
+        assert_eq!(
+            PartitionTable::read(&mut driver).unwrap_err(),
+            Vf2SdDriverError::NoPartitionTable
+        );
+    }
+
+    #[test]
+    fn test_view() {
+        let mut driver = driver(gpt_image());
+        let table = PartitionTable::read(&mut driver).unwrap();
+        let boot = table.get(&mut driver, 0).unwrap().unwrap();
+        let mut view = boot.view(&mut driver);
+        assert_eq!(view.num_blocks(), 64);
+        view.write_blocks(62, &[0x5a; BLOCK_SIZE * 2]).unwrap();
+        assert_eq!(
+            view.write_blocks(63, &[0; BLOCK_SIZE * 2]),
+            Err(Vf2SdDriverError::OutOfRange(63))
+        );
+        assert_eq!(
+            view.read_block(64, &mut [0; BLOCK_SIZE]),
+            Err(Vf2SdDriverError::OutOfRange(64))
+        );
+        let mut buf = [0u8; BLOCK_SIZE];
+        driver.read_block(127, &mut buf).unwrap();
+        assert_eq!(buf, [0x5a; BLOCK_SIZE]);
+        driver.read_block(128, &mut buf).unwrap();
+        assert_eq!(buf, [0; BLOCK_SIZE]);
+    }
+}
@@ -1,28 +1,33 @@
-use crate::cmd::Cmd;
+use crate::cmd::{Cmd, DataDir, ResponseType};
 use crate::utils::GetBit;
 use bitfield_struct::bitfield;
 
-pub const SDIO_BASE: usize = 0x16020000;
-pub const CTRL_REG: usize = SDIO_BASE;
-pub const POWER_REG: usize = SDIO_BASE + 0x04;
-pub const BLK_SIZE_REG: usize = SDIO_BASE + 0x1c;
-pub const BYTE_CNT_REG: usize = SDIO_BASE + 0x20;
-pub const CMD_REG: usize = SDIO_BASE + 0x2c;
-pub const ARG_REG: usize = SDIO_BASE + 0x28;
-pub const RESP0_REG: usize = SDIO_BASE + 0x30;
-pub const RESP1_REG: usize = SDIO_BASE + 0x34;
-pub const RESP2_REG: usize = SDIO_BASE + 0x38;
-pub const RESP3_REG: usize = SDIO_BASE + 0x3c;
-pub const STATUS_REG: usize = SDIO_BASE + 0x48;
-pub const CDETECT_REG: usize = SDIO_BASE + 0x50;
-pub const BUS_MODE_REG: usize = SDIO_BASE + 0x80;
-pub const CTYPE_REG: usize = SDIO_BASE + 0x18;
-pub const CLOCK_ENABLE_REG: usize = SDIO_BASE + 0x10;
-pub const DBADDRL_REG: usize = SDIO_BASE + 0x88; // DMA DES Address Lower
-pub const DBADDRU_REG: usize = SDIO_BASE + 0x8c; // DMA DES Address Upper
-pub const CLK_DIVIDER_REG: usize = SDIO_BASE + 0x08;
-pub const RAW_INT_STATUS_REG: usize = SDIO_BASE + 0x44;
-pub const FIFO_DATA_REG: usize = SDIO_BASE + 0x600;
+pub const CTRL_REG: usize = 0x00;
+pub const POWER_REG: usize = 0x04;
+pub const BLK_SIZE_REG: usize = 0x1c;
+pub const BYTE_CNT_REG: usize = 0x20;
+pub const CMD_REG: usize = 0x2c;
+pub const ARG_REG: usize = 0x28;
+pub const RESP0_REG: usize = 0x30;
+pub const RESP1_REG: usize = 0x34;
+pub const RESP2_REG: usize = 0x38;
+pub const RESP3_REG: usize = 0x3c;
+pub const STATUS_REG: usize = 0x48;
+pub const CDETECT_REG: usize = 0x50;
+pub const DEBNCE_REG: usize = 0x64; // Card Detect Debounce
+pub const BUS_MODE_REG: usize = 0x80;
+pub const CTYPE_REG: usize = 0x18;
+pub const CLOCK_ENABLE_REG: usize = 0x10;
+pub const PLDMND_REG: usize = 0x84; // Poll Demand
+pub const DBADDRL_REG: usize = 0x88; // DMA DES Address Lower
+pub const DBADDRU_REG: usize = 0x8c; // DMA DES Address Upper
+pub const IDSTS_REG: usize = 0x90; // Internal DMAC Status
+pub const IDINTEN_REG: usize = 0x94; // Internal DMAC Interrupt Enable
+pub const CLK_DIVIDER_REG: usize = 0x08;
+pub const INT_MASK_REG: usize = 0x24;
+pub const MASKED_INT_STATUS_REG: usize = 0x40;
+pub const RAW_INT_STATUS_REG: usize = 0x44;
+pub const FIFO_DATA_REG: usize = 0x600;
 
 macro_rules! impl_into_u32 {
     ($name:ident) => {
@@ -118,7 +123,7 @@ pub struct CmdReg {
     ///
     /// Don’t care if no data expected from card.
     pub transfer_dir: bool,
-    /// 	0 - No data transfer expected (read/write) 1 - Data transfer expected (read/write)
+    /// 0 - No data transfer expected (read/write) 1 - Data transfer expected (read/write)
     pub data_expected: bool,
     /// 0 - Do not check response CRC
     ///
@@ -216,7 +221,7 @@ pub struct BusModeReg {
     /// IDMAC Enable. When set, the IDMAC is enabled.
     /// DE is read/write.
     pub de: bool,
-    /// 	Descriptor Skip Length. Specifies the number of HWord/Word/Dword (depending on 16/32/64-bit bus)
+    /// Descriptor Skip Length. Specifies the number of HWord/Word/Dword (depending on 16/32/64-bit bus)
     /// to skip between two unchained descriptors. This is applicable only for dual buffer structure.
     /// DSL is read/write.
     #[bits(5)]
@@ -228,6 +233,42 @@ pub struct BusModeReg {
     pub swr: bool,
 }
 
+#[bitfield(u32,order = Msb)]
+pub struct IdmacStatusReg {
+    #[bits(15)]
+    reserved: u16,
+    /// DMAC FSM present state
+    #[bits(4)]
+    pub fsm: u8,
+    /// Error Bits. Indicates the type of error that caused a Bus Error. Valid only with
+    /// Fatal Bus Error bit (IDSTS[2]) set.
+    ///
+    /// 001 - Host Abort received during transmission
+    ///
+    /// 010 - Host Abort received during reception
+    #[bits(3)]
+    pub eb: u8,
+    /// Abnormal Interrupt Summary. Logical OR of FBE, DU and CES.
+    pub ais: bool,
+    /// Normal Interrupt Summary. Logical OR of TI and RI.
+    pub nis: bool,
+    #[bits(2)]
+    reserved1: u8,
+    /// Card Error Summary. Indicates the status of the transaction to/from the card.
+    pub ces: bool,
+    /// Descriptor Unavailable Interrupt. This bit is set when the descriptor is unavailable
+    /// due to OWN bit = 0 (DES0[31] =0).
+    pub du: bool,
+    reserved2: bool,
+    /// Fatal Bus Error Interrupt. Indicates that a Bus Error occurred (IDSTS[12:10]).
+    /// When this bit is set, the DMA disables all its bus accesses.
+    pub fbe: bool,
+    /// Receive Interrupt. Indicates the completion of data reception for a descriptor.
+    pub ri: bool,
+    /// Transmit Interrupt. Indicates that data transmission is finished for a descriptor.
+    pub ti: bool,
+}
+
 #[bitfield(u32,order = Msb)]
 pub struct StatusReg {
     /// DMA request signal state; either dw_dma_req or ge_dma_req, depending on DW-DMA or Generic-DMA selection.
@@ -250,7 +291,7 @@ pub struct StatusReg {
     pub data_3_status: bool,
     #[bits(4)]
     pub command_fsm_states: u8,
-    ///  	FIFO is full status
+    /// FIFO is full status
     pub fifo_full: bool,
     pub fifo_empty: bool,
     /// FIFO reached Transmit watermark level; not qualified with data
@@ -410,7 +451,8 @@ pub struct RawInterrupt {
 // crc:u7,
 // zero:u1,
 
-#[derive(Debug)]
+/// Card Identification register, bits[127:0] of the R2 response to CMD2
+#[derive(Debug, Copy, Clone)]
 pub struct Cid(u128);
 
 #[allow(dead_code)]
@@ -478,6 +520,26 @@ impl Cid {
     pub fn psn(&self) -> u32 {
         self.0.get_bits(24, 55) as u32
     }
+    /// OID as 2 ASCII characters
+    pub fn oid_bytes(&self) -> [u8; 2] {
+        (self.0.get_bits(104, 119) as u16).to_be_bytes()
+    }
+    /// PNM as 5 ASCII characters
+    pub fn pnm_bytes(&self) -> [u8; 5] {
+        let pnm = (self.0.get_bits(64, 103) as u64).to_be_bytes();
+        [pnm[3], pnm[4], pnm[5], pnm[6], pnm[7]]
+    }
+    /// PRV as (major, minor)
+    pub fn prv_bytes(&self) -> (u8, u8) {
+        (self.0.get_bits(60, 63) as u8, self.0.get_bits(56, 59) as u8)
+    }
+    /// MDT as (year, month)
+    pub fn mdt_bytes(&self) -> (u16, u8) {
+        (
+            self.0.get_bits(12, 19) as u16 + 2000,
+            self.0.get_bits(8, 11) as u8,
+        )
+    }
     #[cfg(feature = "alloc")]
     pub fn mdt(&self) -> alloc::string::String {
         let year = self.0.get_bits(12, 19) as u8; //
@@ -485,11 +547,529 @@ impl Cid {
         let mdt = alloc::format!("{}-{}", year as usize + 2000, month);
         mdt
     }
+    /// OID of a MMC CID, bits[111:104]
+    pub fn mmc_oid(&self) -> u8 {
+        self.0.get_bits(104, 111) as u8
+    }
+    /// PNM of a MMC CID as 6 ASCII characters
+    pub fn mmc_pnm_bytes(&self) -> [u8; 6] {
+        let pnm = (self.0.get_bits(56, 103) as u64).to_be_bytes();
+        [pnm[2], pnm[3], pnm[4], pnm[5], pnm[6], pnm[7]]
+    }
+    /// PRV of a MMC CID as (major, minor)
+    pub fn mmc_prv_bytes(&self) -> (u8, u8) {
+        (self.0.get_bits(52, 55) as u8, self.0.get_bits(48, 51) as u8)
+    }
+    /// PSN of a MMC CID
+    pub fn mmc_psn(&self) -> u32 {
+        self.0.get_bits(16, 47) as u32
+    }
+    /// MDT of a MMC CID as (year, month)
+    ///
+    /// The year counts from 1997. When EXT_CSD_REV is above 4, the codes 0 to 12 are
+    /// 2013 to 2025 and the codes 13 to 15 stay 2010 to 2012.
+    pub fn mmc_mdt_bytes(&self, ext_csd_rev: u8) -> (u16, u8) {
+        let year = self.0.get_bits(8, 11) as u16;
+        let base = if ext_csd_rev > 4 && year <= 12 {
+            2013
+        } else {
+            1997
+        };
+        (year + base, self.0.get_bits(12, 15) as u8)
+    }
+}
+
+/// Card-Specific Data register, bits[127:0] of the R2 response to CMD9
+#[derive(Debug, Copy, Clone)]
+pub struct Csd(u128);
+
+/// Transfer rate multipliers of TRAN_SPEED, times 10
+const TRAN_SPEED_VALUE: [u32; 16] = [
+    0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+];
+
+#[allow(dead_code)]
+impl Csd {
+    pub fn new(value: u128) -> Self {
+        Csd(value)
+    }
+
+    /// CSD_STRUCTURE, 0 for version 1.0, 1 for version 2.0 and 2 for version 3.0
+    pub fn structure(&self) -> u8 {
+        self.0.get_bits(126, 127) as u8
+    }
+
+    /// TRAN_SPEED, the raw max data transfer rate
+    pub fn tran_speed(&self) -> u8 {
+        self.0.get_bits(96, 103) as u8
+    }
+
+    /// The max data transfer rate in bit/s decoded from TRAN_SPEED
+    pub fn max_transfer_rate(&self) -> u32 {
+        let tran_speed = self.tran_speed();
+        let unit = 10u32.pow((tran_speed & 0x7) as u32);
+        let value = TRAN_SPEED_VALUE[(tran_speed >> 3) as usize & 0xf];
+        10_000 * value * unit
+    }
+
+    /// CCC, one bit per supported command class
+    pub fn ccc(&self) -> u16 {
+        self.0.get_bits(84, 95) as u16
+    }
+
+    /// READ_BL_LEN, the max read block length is 2^READ_BL_LEN bytes
+    pub fn read_bl_len(&self) -> u8 {
+        self.0.get_bits(80, 83) as u8
+    }
+
+    /// C_SIZE, 12 bits in version 1.0, 22 bits in version 2.0 and 28 bits in version 3.0
+    pub fn c_size(&self) -> u32 {
+        match self.structure() {
+            0 => self.0.get_bits(62, 73) as u32,
+            1 => self.0.get_bits(48, 69) as u32,
+            _ => self.0.get_bits(48, 75) as u32,
+        }
+    }
+
+    /// C_SIZE_MULT, only meaningful in version 1.0
+    pub fn c_size_mult(&self) -> u8 {
+        self.0.get_bits(47, 49) as u8
+    }
+
+    /// User data area capacity in bytes of a SD card
+    ///
+    /// The CSD_STRUCTURE of a MMC is not the SD version, use [`Csd::mmc_capacity_bytes`].
+    pub fn capacity_bytes(&self) -> u64 {
+        match self.structure() {
+            0 => self.mmc_capacity_bytes(),
+            _ => (self.c_size() as u64 + 1) * 512 * 1024,
+        }
+    }
+
+    /// Capacity in bytes of a MMC, which always uses the layout of SD version 1.0
+    ///
+    /// Cards above 2 GB report the maximal C_SIZE, their capacity is SEC_COUNT of the EXT_CSD.
+    pub fn mmc_capacity_bytes(&self) -> u64 {
+        let c_size = self.0.get_bits(62, 73) as u64;
+        (c_size + 1) << (self.c_size_mult() + 2 + self.read_bl_len())
+    }
+
+    /// Capacity in blocks of 512 bytes of a SD card
+    pub fn capacity_blocks(&self) -> u64 {
+        self.capacity_bytes() / 512
+    }
+
+    /// ERASE_BLK_EN, the card can erase single write blocks
+    pub fn erase_blk_en(&self) -> bool {
+        self.0.get_bit(46)
+    }
+
+    /// SECTOR_SIZE + 1, the erase sector size in write blocks
+    pub fn sector_size(&self) -> u8 {
+        self.0.get_bits(39, 45) as u8 + 1
+    }
+
+    /// (ERASE_GRP_SIZE + 1) * (ERASE_GRP_MULT + 1), the erase group size of a MMC in write blocks
+    pub fn mmc_erase_grp_blocks(&self) -> u32 {
+        (self.0.get_bits(42, 46) as u32 + 1) * (self.0.get_bits(37, 41) as u32 + 1)
+    }
+
+    /// WP_GRP_SIZE + 1, the write protect group size in erase sectors
+    pub fn wp_grp_size(&self) -> u8 {
+        self.0.get_bits(32, 38) as u8 + 1
+    }
+
+    /// WP_GRP_ENABLE
+    pub fn wp_grp_enable(&self) -> bool {
+        self.0.get_bit(31)
+    }
+
+    /// WRITE_BL_LEN, the max write block length is 2^WRITE_BL_LEN bytes
+    pub fn write_bl_len(&self) -> u8 {
+        self.0.get_bits(22, 25) as u8
+    }
+
+    /// COPY
+    pub fn copy(&self) -> bool {
+        self.0.get_bit(14)
+    }
+
+    /// PERM_WRITE_PROTECT
+    pub fn perm_write_protect(&self) -> bool {
+        self.0.get_bit(13)
+    }
+
+    /// TMP_WRITE_PROTECT
+    pub fn tmp_write_protect(&self) -> bool {
+        self.0.get_bit(12)
+    }
+}
+
+/// State of the card reported in CURRENT_STATE of the card status
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardState {
+    Idle,
+    Ready,
+    Ident,
+    Stby,
+    Tran,
+    Data,
+    Rcv,
+    /// Programming, the card stores the received data or erases blocks
+    Prg,
+    Dis,
+    /// Bus test of MMC
+    Btst,
+    /// Sleep of MMC
+    Slp,
+    Reserved(u8),
+}
+
+impl From<u8> for CardState {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => CardState::Idle,
+            1 => CardState::Ready,
+            2 => CardState::Ident,
+            3 => CardState::Stby,
+            4 => CardState::Tran,
+            5 => CardState::Data,
+            6 => CardState::Rcv,
+            7 => CardState::Prg,
+            8 => CardState::Dis,
+            9 => CardState::Btst,
+            10 => CardState::Slp,
+            v => CardState::Reserved(v),
+        }
+    }
+}
+
+/// Card status of the R1/R1b responses and of CMD13
+#[bitfield(u32)]
+#[derive(PartialEq, Eq)]
+pub struct CardStatus {
+    #[bits(3)]
+    reserved0: u8,
+    pub ake_seq_error: bool,
+    reserved1: bool,
+    pub app_cmd: bool,
+    /// FX_EVENT of SD, EXCEPTION_EVENT of MMC
+    pub event: bool,
+    /// SWITCH_ERROR of MMC, CMD6 was refused
+    pub switch_error: bool,
+    pub ready_for_data: bool,
+    /// CURRENT_STATE, see [`CardStatus::state`]
+    #[bits(4)]
+    pub current_state: u8,
+    pub erase_reset: bool,
+    pub card_ecc_disabled: bool,
+    pub wp_erase_skip: bool,
+    pub csd_overwrite: bool,
+    #[bits(2)]
+    reserved2: u8,
+    pub error: bool,
+    pub cc_error: bool,
+    pub card_ecc_failed: bool,
+    pub illegal_command: bool,
+    pub com_crc_error: bool,
+    pub lock_unlock_failed: bool,
+    pub card_is_locked: bool,
+    pub wp_violation: bool,
+    pub erase_param: bool,
+    pub erase_seq_error: bool,
+    pub block_len_error: bool,
+    pub address_error: bool,
+    pub out_of_range: bool,
+}
+
+impl CardStatus {
+    pub fn state(&self) -> CardState {
+        CardState::from(self.current_state())
+    }
+
+    /// Any of the bits of [`crate::CARD_STATUS_ERROR_MASK`] is set
+    pub fn has_error(&self) -> bool {
+        self.0 & crate::CARD_STATUS_ERROR_MASK != 0
+    }
+
+    /// The card is in the transfer state and can accept the next data command
+    pub fn is_transfer_ready(&self) -> bool {
+        self.state() == CardState::Tran && self.ready_for_data()
+    }
+}
+
+/// SD Configuration Register, as the 64 bits read by ACMD51
+#[derive(Debug, Copy, Clone)]
+pub struct Scr(u64);
+
+#[allow(dead_code)]
+impl Scr {
+    pub fn new(value: u64) -> Self {
+        Scr(value)
+    }
+
+    /// SCR_STRUCTURE
+    pub fn structure(&self) -> u8 {
+        (self.0 >> 60) as u8 & 0xf
+    }
+
+    /// SD_SPEC
+    pub fn sd_spec(&self) -> u8 {
+        (self.0 >> 56) as u8 & 0xf
+    }
+
+    /// SD_SPEC3
+    pub fn sd_spec3(&self) -> bool {
+        (self.0 >> 47) & 1 != 0
+    }
+
+    /// SD_SPEC4
+    pub fn sd_spec4(&self) -> bool {
+        (self.0 >> 42) & 1 != 0
+    }
+
+    /// SD_SPECX
+    pub fn sd_specx(&self) -> u8 {
+        (self.0 >> 38) as u8 & 0xf
+    }
+
+    /// Physical layer specification version as (major, minor)
+    pub fn spec_version(&self) -> (u8, u8) {
+        match (
+            self.sd_spec(),
+            self.sd_spec3(),
+            self.sd_spec4(),
+            self.sd_specx(),
+        ) {
+            (0, ..) => (1, 0),
+            (1, ..) => (1, 10),
+            (2, false, ..) => (2, 0),
+            (2, true, _, specx) if specx > 0 => (specx + 4, 0),
+            (2, true, true, _) => (4, 0),
+            (2, true, false, _) => (3, 0),
+            _ => (0, 0),
+        }
+    }
+
+    /// DATA_STAT_AFTER_ERASE, the erased blocks read as 0xff instead of 0x00
+    pub fn data_stat_after_erase(&self) -> bool {
+        (self.0 >> 55) & 1 != 0
+    }
+
+    /// SD_BUS_WIDTHS, bit 0 is 1-bit and bit 2 is 4-bit
+    pub fn bus_widths(&self) -> u8 {
+        (self.0 >> 48) as u8 & 0xf
+    }
+
+    pub fn support_4bit(&self) -> bool {
+        self.bus_widths() & 0b100 != 0
+    }
+}
+
+/// The 512 bits switch function status returned by CMD6, most significant byte first
+#[derive(Debug, Copy, Clone)]
+pub struct SwitchStatus([u8; 64]);
+
+#[allow(dead_code)]
+impl SwitchStatus {
+    pub fn new(value: [u8; 64]) -> Self {
+        SwitchStatus(value)
+    }
+
+    /// Maximum current consumption in mA, bits[511:496]
+    pub fn max_current(&self) -> u16 {
+        u16::from_be_bytes([self.0[0], self.0[1]])
+    }
+
+    /// Supported functions of group 1, bits[415:400]
+    pub fn group1_support_bits(&self) -> u16 {
+        u16::from_be_bytes([self.0[12], self.0[13]])
+    }
+
+    pub fn group1_support(&self, func: u8) -> bool {
+        self.group1_support_bits() & (1 << func) != 0
+    }
+
+    /// Function selected in group 1, bits[379:376], 0xf if the switch failed
+    pub fn group1_selected(&self) -> u8 {
+        self.0[16] & 0xf
+    }
+}
+
+/// EXT_CSD byte of the boot configuration and partition access
+pub const EXT_CSD_PARTITION_CONFIG: u8 = 179;
+/// EXT_CSD byte of the bus width
+pub const EXT_CSD_BUS_WIDTH: u8 = 183;
+/// EXT_CSD byte of the high speed interface timing
+pub const EXT_CSD_HS_TIMING: u8 = 185;
+
+/// The 512 bytes Extended CSD register of MMC read by CMD8, byte `n` is EXT_CSD[n]
+#[derive(Copy, Clone)]
+pub struct ExtCsd([u8; 512]);
+
+impl core::fmt::Debug for ExtCsd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExtCsd")
+            .field("ext_csd_rev", &self.ext_csd_rev())
+            .field("card_type", &self.card_type())
+            .field("sec_count", &self.sec_count())
+            .field("bus_width", &self.bus_width())
+            .field("hs_timing", &self.hs_timing())
+            .finish()
+    }
+}
+
+#[allow(dead_code)]
+impl ExtCsd {
+    pub fn new(value: [u8; 512]) -> Self {
+        ExtCsd(value)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 512] {
+        &self.0
+    }
+
+    /// EXT_CSD_REV [192]
+    pub fn ext_csd_rev(&self) -> u8 {
+        self.0[192]
+    }
+
+    /// DEVICE_TYPE [196], bit 0 is HS26 and bit 1 is HS52
+    pub fn card_type(&self) -> u8 {
+        self.0[196]
+    }
+
+    pub fn support_hs26(&self) -> bool {
+        self.card_type() & 1 != 0
+    }
+
+    pub fn support_hs52(&self) -> bool {
+        self.card_type() & 2 != 0
+    }
+
+    /// SEC_COUNT [215:212], the capacity in 512 byte sectors of cards above 2 GB
+    pub fn sec_count(&self) -> u32 {
+        u32::from_le_bytes([self.0[212], self.0[213], self.0[214], self.0[215]])
+    }
+
+    /// BUS_WIDTH [183], 0 for 1-bit, 1 for 4-bit and 2 for 8-bit
+    pub fn bus_width(&self) -> u8 {
+        self.0[EXT_CSD_BUS_WIDTH as usize]
+    }
+
+    /// HS_TIMING [185]
+    pub fn hs_timing(&self) -> u8 {
+        self.0[EXT_CSD_HS_TIMING as usize]
+    }
+
+    /// PARTITION_CONFIG [179], bits[2:0] are the partition accessed by the data commands
+    pub fn partition_config(&self) -> u8 {
+        self.0[EXT_CSD_PARTITION_CONFIG as usize]
+    }
+
+    /// ERASE_GROUP_DEF [175], bit 0 selects the high capacity erase group size
+    pub fn erase_group_def(&self) -> u8 {
+        self.0[175]
+    }
+
+    /// ERASED_MEM_CONT [181], the erased memory reads as 0xff instead of 0x00
+    pub fn erased_mem_cont(&self) -> u8 {
+        self.0[181]
+    }
+
+    /// ERASE_TIMEOUT_MULT [223], the timeout of erasing one high capacity erase group in 300 ms
+    pub fn erase_timeout_mult(&self) -> u8 {
+        self.0[223]
+    }
+
+    /// SEC_FEATURE_SUPPORT [231]
+    pub fn sec_feature_support(&self) -> u8 {
+        self.0[231]
+    }
+
+    /// TRIM_MULT [232], the timeout of trimming one erase group in 300 ms
+    pub fn trim_mult(&self) -> u8 {
+        self.0[232]
+    }
+
+    /// SEC_GB_CL_EN of SEC_FEATURE_SUPPORT, the device supports TRIM
+    pub fn support_trim(&self) -> bool {
+        self.sec_feature_support() & (1 << 4) != 0
+    }
+
+    /// DISCARD was introduced with eMMC 4.5 (EXT_CSD_REV 6)
+    pub fn support_discard(&self) -> bool {
+        self.ext_csd_rev() >= 6
+    }
+
+    /// BOOT_SIZE_MULT [226], the size of each boot partition in 128 KiB
+    pub fn boot_size_mult(&self) -> u8 {
+        self.0[226]
+    }
+
+    /// RPMB_SIZE_MULT [168], the size of the RPMB partition in 128 KiB
+    pub fn rpmb_size_mult(&self) -> u8 {
+        self.0[168]
+    }
+
+    /// HC_WP_GRP_SIZE [221], in high capacity erase groups
+    pub fn hc_wp_grp_size(&self) -> u8 {
+        self.0[221]
+    }
+
+    /// HC_ERASE_GRP_SIZE [224], in 512 KiB
+    pub fn hc_erase_grp_size(&self) -> u8 {
+        self.0[224]
+    }
+
+    /// GP_SIZE_MULT_n [145:143] + 3n of general purpose partition `n` (0..4)
+    pub fn gp_size_mult(&self, n: usize) -> u32 {
+        let i = 143 + 3 * n;
+        u32::from_le_bytes([self.0[i], self.0[i + 1], self.0[i + 2], 0])
+    }
+
+    pub fn boot_partition_bytes(&self) -> u64 {
+        self.boot_size_mult() as u64 * 128 * 1024
+    }
+
+    pub fn rpmb_partition_bytes(&self) -> u64 {
+        self.rpmb_size_mult() as u64 * 128 * 1024
+    }
+
+    /// Size of general purpose partition `n` (0..4)
+    pub fn gp_partition_bytes(&self, n: usize) -> u64 {
+        self.gp_size_mult(n) as u64
+            * self.hc_wp_grp_size() as u64
+            * self.hc_erase_grp_size() as u64
+            * 512
+            * 1024
+    }
 }
 
 impl RawInterrupt {
     pub fn have_error(&mut self) -> bool {
-        self.rto() || self.dcrc() || self.response_err() || self.drto() || self.sbe() || self.ebe()
+        self.rto()
+            || self.rcrc()
+            || self.dcrc()
+            || self.response_err()
+            || self.drto()
+            || self.sbe()
+            || self.ebe()
+            || self.frun()
+            || self.hle()
+    }
+}
+
+impl CDetectReg {
+    /// card_detect_n of card 0 is active low
+    pub fn card_present(&self) -> bool {
+        self.0 & 1 == 0
+    }
+}
+
+impl IdmacStatusReg {
+    pub fn have_error(&self) -> bool {
+        self.fbe() || self.du() || self.ces()
     }
 }
 
@@ -515,25 +1095,212 @@ impl CmdReg {
 
 impl From<Cmd> for CmdReg {
     fn from(value: Cmd) -> Self {
+        if value == Cmd::ResetClock {
+            return CmdReg::new()
+                .with_start_cmd(true)
+                .with_wait_prvdata_complete(true)
+                .with_update_clock_registers_only(true);
+        }
+        let response = value.response_type();
+        let data = value.data_dir();
+        let reg = match data {
+            DataDir::None => CmdReg::with_no_data(0, value.into()),
+            DataDir::Read => CmdReg::with_data(0, value.into()),
+            DataDir::Write => CmdReg::with_data(0, value.into()).with_transfer_dir(true),
+        };
+        let reg = reg
+            .with_response_expect(response != ResponseType::None)
+            .with_response_length(response == ResponseType::R2)
+            .with_check_response_crc(response.has_crc())
+            .with_send_auto_stop(matches!(
+                value,
+                Cmd::ReadMultipleBlock | Cmd::WriteMultipleBlock
+            ));
         match value {
-            Cmd::GoIdleState => {
-                CmdReg::with_no_data(0, value.into()).with_send_initialization(true)
-            }
-            Cmd::SendIfCond | Cmd::AppCmd | Cmd::SendRelativeAddr | Cmd::SelectCard => {
-                CmdReg::with_no_data(0, value.into())
-            }
-            Cmd::SdSendOpCond => {
-                CmdReg::with_no_data(0, value.into()).with_check_response_crc(false)
-            }
-            Cmd::SendCsd => CmdReg::with_no_data(0, value.into()).with_check_response_crc(false),
-            Cmd::AllSendCid => CmdReg::with_no_data(0, value.into())
-                .with_check_response_crc(false)
-                .with_response_length(true),
-            Cmd::SendScr | Cmd::ReadSingleBlock => CmdReg::with_data(0, value.into()),
-            Cmd::WriteSingleBlock => CmdReg::with_data(0, value.into()).with_transfer_dir(true),
-            _ => {
-                panic!("Not implemented")
-            }
+            Cmd::GoIdleState => reg.with_send_initialization(true),
+            Cmd::StopTransmission => reg
+                .with_stop_abort_cmd(true)
+                .with_wait_prvdata_complete(false),
+            _ => reg,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::RawCmd;
+
+    #[test]
+    fn test_scr_bus_widths() {
+        // SD 3.0 card with 1-bit and 4-bit support
+        let scr = Scr::new(0x0235_8003_0000_0000);
+        assert_eq!(scr.structure(), 0);
+        assert_eq!(scr.sd_spec(), 2);
+        assert_eq!(scr.bus_widths(), 0b0101);
+        assert!(scr.support_4bit());
+        assert_eq!(scr.spec_version(), (3, 0));
+        assert_eq!(Scr::new(0x0235_8403_0000_0000).spec_version(), (4, 0));
+        assert_eq!(Scr::new(0x0235_8483_0000_0000).spec_version(), (6, 0));
+        assert!(!Scr::new(0x0231_0000_0000_0000).support_4bit());
+        assert!(!scr.data_stat_after_erase());
+        assert!(Scr::new(0x02b5_8000_0000_0000).data_stat_after_erase());
+    }
+
+    #[test]
+    fn test_cmd_reg() {
+        let cmd0 = CmdReg::from(Cmd::GoIdleState);
+        assert!(cmd0.send_initialization() && !cmd0.response_expect());
+        let cmd2 = CmdReg::from(Cmd::AllSendCid);
+        assert!(cmd2.response_length() && !cmd2.check_response_crc());
+        assert_eq!(CmdReg::from(Cmd::SendCid).cmd_index(), 10);
+        let acmd41 = CmdReg::from(Cmd::SdSendOpCond);
+        assert!(acmd41.response_expect() && !acmd41.check_response_crc());
+        let cmd12 = CmdReg::from(Cmd::StopTransmission);
+        assert!(cmd12.stop_abort_cmd() && !cmd12.wait_prvdata_complete());
+        let cmd25 = CmdReg::from(Cmd::WriteMultipleBlock);
+        assert!(cmd25.data_expected() && cmd25.transfer_dir() && cmd25.send_auto_stop());
+        let acmd13 = CmdReg::from(Cmd::SdStatus);
+        assert!(acmd13.data_expected() && !acmd13.transfer_dir() && !acmd13.send_auto_stop());
+        assert!(Cmd::SdStatus.is_app_cmd() && !Cmd::SendStatus.is_app_cmd());
+        assert!(!CmdReg::from(Cmd::GoInactiveState).response_expect());
+        assert!(Cmd::Erase.expects_busy() && !Cmd::EraseWrBlkStart.expects_busy());
+        assert!(CmdReg::from(Cmd::ResetClock).update_clock_registers_only());
+        let raw = Cmd::Raw(RawCmd {
+            index: 60,
+            app: false,
+            response: ResponseType::R1b,
+            data: DataDir::Write,
+        });
+        let reg = CmdReg::from(raw);
+        assert_eq!(reg.cmd_index(), 60);
+        assert!(reg.data_expected() && reg.transfer_dir() && reg.check_response_crc());
+        assert!(raw.response_is_card_status() && raw.expects_busy());
+    }
+
+    #[test]
+    fn test_cid() {
+        let cid = Cid::new(0x0353_4453_4533_3247_8012_3456_7801_6701);
+        assert_eq!(cid.mid(), 3);
+        assert_eq!(&cid.oid_bytes(), b"SD");
+        assert_eq!(&cid.pnm_bytes(), b"SE32G");
+        assert_eq!(cid.prv_bytes(), (8, 0));
+        assert_eq!(cid.psn(), 0x1234_5678);
+        assert_eq!(cid.mdt_bytes(), (2022, 7));
+    }
+
+    #[test]
+    fn test_csd_v2() {
+        let csd = Csd::new(0x400e_0032_5b59_0000_edc8_7f80_0a40_4000);
+        assert_eq!(csd.structure(), 1);
+        assert_eq!(csd.c_size(), 60872);
+        assert_eq!(csd.capacity_bytes(), 31_914_983_424);
+        assert_eq!(csd.capacity_blocks(), 62_333_952);
+        assert_eq!(csd.ccc(), 0x5b5);
+        assert_eq!(csd.max_transfer_rate(), 25_000_000);
+        assert_eq!(csd.read_bl_len(), 9);
+        assert!(csd.erase_blk_en());
+        assert_eq!(csd.sector_size(), 128);
+        assert!(!csd.perm_write_protect());
+        assert!(!csd.tmp_write_protect());
+    }
+
+    #[test]
+    fn test_csd_v1() {
+        let csd = Csd::new(0x005e_0032_5f5a_83c4_f6db_bf8f_1680_0000);
+        assert_eq!(csd.structure(), 0);
+        assert_eq!(csd.c_size(), 3859);
+        assert_eq!(csd.c_size_mult(), 7);
+        assert_eq!(csd.read_bl_len(), 10);
+        assert_eq!(csd.capacity_bytes(), 2_023_751_680);
+        assert!(!csd.erase_blk_en());
+    }
+
+    #[test]
+    fn test_mmc_cid() {
+        let cid = Cid::new(0x1501_0153_494d_4d43_3110_1234_5678_1b01);
+        assert_eq!(cid.mid(), 0x15);
+        assert_eq!(cid.mmc_oid(), 1);
+        assert_eq!(&cid.mmc_pnm_bytes(), b"SIMMC1");
+        assert_eq!(cid.mmc_prv_bytes(), (1, 0));
+        assert_eq!(cid.mmc_psn(), 0x1234_5678);
+        assert_eq!(cid.mmc_mdt_bytes(8), (2024, 1));
+        assert_eq!(cid.mmc_mdt_bytes(4), (2008, 1));
+        // the codes past 2025 wrap to 2010
+        let cid = Cid::new(0x1501_0153_494d_4d43_3110_1234_5678_1d01);
+        assert_eq!(cid.mmc_mdt_bytes(8), (2010, 1));
+        assert_eq!(cid.mmc_mdt_bytes(4), (2010, 1));
+        let cid = Cid::new(0x1501_0153_494d_4d43_3110_1234_5678_1c01);
+        assert_eq!(cid.mmc_mdt_bytes(8), (2025, 1));
+    }
+
+    #[test]
+    fn test_ext_csd() {
+        let mut raw = [0u8; 512];
+        raw[192] = 8;
+        raw[196] = 0x03;
+        raw[212..216].copy_from_slice(&0x00e9_0000u32.to_le_bytes());
+        raw[EXT_CSD_BUS_WIDTH as usize] = 2;
+        let ext_csd = ExtCsd::new(raw);
+        assert_eq!(ext_csd.ext_csd_rev(), 8);
+        assert!(ext_csd.support_hs26() && ext_csd.support_hs52());
+        assert_eq!(ext_csd.sec_count(), 0x00e9_0000);
+        assert_eq!(ext_csd.bus_width(), 2);
+        assert_eq!(ext_csd.hs_timing(), 0);
+        assert_eq!(ext_csd.boot_partition_bytes(), 0);
+        raw[226] = 32;
+        raw[168] = 4;
+        raw[221] = 1;
+        raw[224] = 2;
+        raw[146..149].copy_from_slice(&[0x00, 0x01, 0x00]);
+        let ext_csd = ExtCsd::new(raw);
+        assert_eq!(ext_csd.boot_partition_bytes(), 4 * 1024 * 1024);
+        assert_eq!(ext_csd.rpmb_partition_bytes(), 512 * 1024);
+        assert_eq!(ext_csd.gp_partition_bytes(0), 0);
+        assert_eq!(ext_csd.gp_partition_bytes(1), 256 * 1024 * 1024);
+        assert!(ext_csd.support_discard() && !ext_csd.support_trim());
+        raw[231] = 0x55;
+        raw[232] = 3;
+        assert!(ExtCsd::new(raw).support_trim());
+        assert_eq!(ExtCsd::new(raw).trim_mult(), 3);
+    }
+
+    #[test]
+    fn test_card_status() {
+        // transfer state, ready for data
+        let status = CardStatus::from(0x0000_0900);
+        assert_eq!(status.state(), CardState::Tran);
+        assert!(status.ready_for_data() && status.is_transfer_ready());
+        assert!(!status.has_error());
+        // programming state, WP_VIOLATION and CC_ERROR
+        let status = CardStatus::from(0x0410_0e00);
+        assert_eq!(status.state(), CardState::Prg);
+        assert!(!status.is_transfer_ready());
+        assert!(status.wp_violation() && status.cc_error());
+        assert!(!status.address_error());
+        assert!(status.has_error());
+        let status = CardStatus::new()
+            .with_address_error(true)
+            .with_app_cmd(true);
+        assert_eq!(u32::from(status), 0x4000_0020);
+        assert_eq!(
+            CardStatus::from(0x0000_1600).state(),
+            CardState::Reserved(11)
+        );
+    }
+
+    #[test]
+    fn test_switch_status() {
+        let mut status = [0u8; 64];
+        status[1] = 0x64;
+        // default speed and high speed supported, high speed selected
+        status[13] = 0x03;
+        status[16] = 0x01;
+        let status = SwitchStatus::new(status);
+        assert_eq!(status.max_current(), 100);
+        assert!(status.group1_support(0));
+        assert!(status.group1_support(1));
+        assert!(!status.group1_support(2));
+        assert_eq!(status.group1_selected(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+//! Responses decoded from RESP0..RESP3
+//!
+//! Each type checks that it matches the response type of the command it is decoded for, so a
+//! status can not be read from an OCR by accident.
+use crate::cmd::{Cmd, ResponseType};
+use crate::register::{CardStatus, Cid, Csd};
+use crate::utils::GetBit;
+use crate::{Result, Vf2SdDriverError, CARD_STATUS_ERROR_MASK};
+
+/// A response decoded from the response registers of the controller
+pub trait Response: Sized {
+    /// Whether responses of the given type are decoded as this type
+    fn matches(response: ResponseType) -> bool;
+    /// Decode and validate the response of `cmd` sent with `arg`
+    fn decode(cmd: Cmd, arg: u32, resp: [u32; 4]) -> Result<Self>;
+}
+
+/// No response, e.g. CMD0
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NoResponse;
+
+impl Response for NoResponse {
+    fn matches(response: ResponseType) -> bool {
+        response == ResponseType::None
+    }
+    fn decode(_cmd: Cmd, _arg: u32, _resp: [u32; 4]) -> Result<Self> {
+        Ok(NoResponse)
+    }
+}
+
+/// Card status of R1 and R1b
+///
+/// The error bits are already reported as [`Vf2SdDriverError::CardStatus`] by the command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct R1(pub CardStatus);
+
+impl Response for R1 {
+    fn matches(response: ResponseType) -> bool {
+        matches!(response, ResponseType::R1 | ResponseType::R1b)
+    }
+    fn decode(_cmd: Cmd, _arg: u32, resp: [u32; 4]) -> Result<Self> {
+        Ok(R1(CardStatus::from(resp[0])))
+    }
+}
+
+/// CID or CSD, bits [127:0] of the 136 bit response
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct R2(u128);
+
+impl R2 {
+    pub fn new(resp: [u32; 4]) -> Self {
+        R2(resp[0] as u128
+            | ((resp[1] as u128) << 32)
+            | ((resp[2] as u128) << 64)
+            | ((resp[3] as u128) << 96))
+    }
+    /// The register with the CRC7 and the end bit cleared
+    pub fn register(&self) -> u128 {
+        self.0 & !0xff
+    }
+    /// CRC7 of the register, already checked by the card interface
+    pub fn crc(&self) -> u8 {
+        self.0.get_bits(1, 7) as u8
+    }
+    pub fn cid(&self) -> Cid {
+        Cid::new(self.register())
+    }
+    pub fn csd(&self) -> Csd {
+        Csd::new(self.register())
+    }
+}
+
+impl Response for R2 {
+    fn matches(response: ResponseType) -> bool {
+        response == ResponseType::R2
+    }
+    fn decode(_cmd: Cmd, _arg: u32, resp: [u32; 4]) -> Result<Self> {
+        Ok(R2::new(resp))
+    }
+}
+
+/// OCR returned by ACMD41 and CMD1
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct R3(pub u32);
+
+impl R3 {
+    /// Power up status, the card finished its initialization
+    pub fn is_ready(&self) -> bool {
+        self.0.get_bit(31)
+    }
+    /// CCS of SD cards, sector access mode of MMC
+    pub fn is_high_capacity(&self) -> bool {
+        self.0.get_bit(30)
+    }
+}
+
+impl Response for R3 {
+    fn matches(response: ResponseType) -> bool {
+        response == ResponseType::R3
+    }
+    fn decode(_cmd: Cmd, _arg: u32, resp: [u32; 4]) -> Result<Self> {
+        Ok(R3(resp[0]))
+    }
+}
+
+/// Published RCA of a SD card with the short card status
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct R6 {
+    pub rca: u16,
+    status: u16,
+}
+
+impl R6 {
+    /// The card status bits of the response at their R1 positions
+    ///
+    /// Bits 15, 14 and 13 of the response are COM_CRC_ERROR, ILLEGAL_COMMAND and ERROR.
+    pub fn card_status(&self) -> CardStatus {
+        let status = self.status as u32;
+        let expanded = (status & 0x1fff)
+            | ((status.get_bit(15) as u32) << 23)
+            | ((status.get_bit(14) as u32) << 22)
+            | ((status.get_bit(13) as u32) << 19);
+        CardStatus::from(expanded)
+    }
+}
+
+impl Response for R6 {
+    fn matches(response: ResponseType) -> bool {
+        response == ResponseType::R6
+    }
+    fn decode(cmd: Cmd, _arg: u32, resp: [u32; 4]) -> Result<Self> {
+        let r6 = R6 {
+            rca: (resp[0] >> 16) as u16,
+            status: resp[0] as u16,
+        };
+        let status = u32::from(r6.card_status());
+        if status & CARD_STATUS_ERROR_MASK != 0 {
+            return Err(Vf2SdDriverError::CardStatus(cmd, status));
+        }
+        if r6.rca == 0 {
+            return Err(Vf2SdDriverError::InvalidResponse(cmd));
+        }
+        Ok(r6)
+    }
+}
+
+/// Card interface condition returned by CMD8
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct R7 {
+    /// Accepted voltage range, 1 for 2.7-3.6V
+    pub voltage: u8,
+    pub check_pattern: u8,
+}
+
+impl Response for R7 {
+    fn matches(response: ResponseType) -> bool {
+        response == ResponseType::R7
+    }
+    /// The card echoes the voltage range and the check pattern of the argument
+    fn decode(cmd: Cmd, arg: u32, resp: [u32; 4]) -> Result<Self> {
+        let r7 = R7 {
+            voltage: resp[0].get_bits(8, 11) as u8,
+            check_pattern: resp[0] as u8,
+        };
+        if resp[0] & 0xfff != arg & 0xfff {
+            return Err(Vf2SdDriverError::InvalidResponse(cmd));
+        }
+        Ok(r7)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::CardState;
+
+    #[test]
+    fn test_r2() {
+        let cid = 0x0353_4453_494d_3031_1012_3456_7801_8101_u128;
+        let r2 = R2::new([
+            cid as u32,
+            (cid >> 32) as u32,
+            (cid >> 64) as u32,
+            (cid >> 96) as u32,
+        ]);
+        assert_eq!(r2.register(), cid & !0xff);
+        assert_eq!(r2.crc(), 0);
+        assert_eq!(r2.cid().mid(), 3);
+        let r2 = R2::new([0xa5, 0, 0, 0]);
+        assert_eq!(r2.crc(), 0x52);
+        assert_eq!(r2.register(), 0);
+    }
+
+    #[test]
+    fn test_r6() {
+        let r6 = R6::decode(Cmd::SendRelativeAddr, 0, [0x1234_0700, 0, 0, 0]).unwrap();
+        assert_eq!(r6.rca, 0x1234);
+        assert_eq!(r6.card_status().state(), CardState::Stby);
+        assert!(r6.card_status().ready_for_data());
+        assert_eq!(
+            R6::decode(Cmd::SendRelativeAddr, 0, [0x1234_4700, 0, 0, 0]),
+            Err(Vf2SdDriverError::CardStatus(
+                Cmd::SendRelativeAddr,
+                (1 << 22) | 0x700
+            ))
+        );
+        assert_eq!(
+            R6::decode(Cmd::SendRelativeAddr, 0, [0x0000_0700, 0, 0, 0]),
+            Err(Vf2SdDriverError::InvalidResponse(Cmd::SendRelativeAddr))
+        );
+    }
+
+    #[test]
+    fn test_r7() {
+        let r7 = R7::decode(Cmd::SendIfCond, 0x1aa, [0x1aa, 0, 0, 0]).unwrap();
+        assert_eq!(r7.voltage, 1);
+        assert_eq!(r7.check_pattern, 0xaa);
+        assert_eq!(
+            R7::decode(Cmd::SendIfCond, 0x1aa, [0x1a5, 0, 0, 0]),
+            Err(Vf2SdDriverError::InvalidResponse(Cmd::SendIfCond))
+        );
+        assert_eq!(
+            R7::decode(Cmd::SendIfCond, 0x1aa, [0x0aa, 0, 0, 0]),
+            Err(Vf2SdDriverError::InvalidResponse(Cmd::SendIfCond))
+        );
+    }
+
+    #[test]
+    fn test_r3() {
+        let r3 = R3::decode(Cmd::SdSendOpCond, 0, [0xc0ff_8000, 0, 0, 0]).unwrap();
+        assert!(r3.is_ready() && r3.is_high_capacity());
+        assert!(!R3(0x00ff_8000).is_ready());
+    }
+}
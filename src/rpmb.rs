@@ -0,0 +1,334 @@
+//! Authenticated access to the replay protected memory block of eMMC
+//!
+//! Every request and response is a 512 bytes frame, the MAC is a HMAC-SHA256 with the
+//! key programmed into the device over bytes [228..512] of all frames of a message.
+use crate::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Size of the data of one RPMB frame, RPMB addresses count in this unit
+pub const RPMB_DATA_SIZE: usize = 256;
+/// The HMAC-SHA256 authentication key of the RPMB partition
+pub type RpmbKey = [u8; 32];
+
+/// Byte offsets of the fields of a frame, multi-byte fields are big endian
+pub(crate) const FRAME_SIZE: usize = 512;
+pub(crate) const KEY_MAC: usize = 196;
+pub(crate) const DATA: usize = 228;
+pub(crate) const NONCE: usize = 484;
+pub(crate) const WRITE_COUNTER: usize = 500;
+pub(crate) const ADDRESS: usize = 504;
+pub(crate) const BLOCK_COUNT: usize = 506;
+pub(crate) const RESULT: usize = 508;
+pub(crate) const REQ_RESP: usize = 510;
+
+/// Request types, the response type is the request type shifted left by 8
+pub(crate) const REQ_PROGRAM_KEY: u16 = 0x0001;
+pub(crate) const REQ_WRITE_COUNTER: u16 = 0x0002;
+pub(crate) const REQ_AUTH_WRITE: u16 = 0x0003;
+pub(crate) const REQ_AUTH_READ: u16 = 0x0004;
+pub(crate) const REQ_RESULT_READ: u16 = 0x0005;
+
+/// Operation result bits[6:0] of a response, bit 7 is set once the write counter expired
+const RESULT_OK: u16 = 0;
+
+pub(crate) fn put_u16(frame: &mut [u8], at: usize, val: u16) {
+    frame[at..at + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+pub(crate) fn get_u16(frame: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([frame[at], frame[at + 1]])
+}
+
+pub(crate) fn put_u32(frame: &mut [u8], at: usize, val: u32) {
+    frame[at..at + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+pub(crate) fn get_u32(frame: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([frame[at], frame[at + 1], frame[at + 2], frame[at + 3]])
+}
+
+/// The MAC over bytes [228..512] of every frame of `frames`
+pub(crate) fn rpmb_mac(key: &RpmbKey, frames: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    frames
+        .chunks(FRAME_SIZE)
+        .for_each(|frame| mac.update(&frame[DATA..]));
+    mac.finalize().into_bytes().into()
+}
+
+/// Check the response type, result, nonce and MAC of a response frame
+fn check_response(
+    frame: &[u8],
+    resp: u16,
+    key: Option<&RpmbKey>,
+    nonce: Option<&[u8; 16]>,
+) -> Result<()> {
+    if get_u16(frame, REQ_RESP) != resp << 8 {
+        error!("rpmb response type {:#x}", get_u16(frame, REQ_RESP));
+        return Err(Vf2SdDriverError::RpmbAuthentication);
+    }
+    let result = get_u16(frame, RESULT);
+    if result & 0x7f != RESULT_OK {
+        error!("rpmb request {:#x} failed: {:#x}", resp, result);
+        return Err(Vf2SdDriverError::Rpmb(result));
+    }
+    if nonce.is_some_and(|nonce| frame[NONCE..NONCE + 16] != *nonce) {
+        return Err(Vf2SdDriverError::RpmbAuthentication);
+    }
+    if key.is_some_and(|key| frame[KEY_MAC..DATA] != rpmb_mac(key, frame)) {
+        error!("rpmb response {:#x} has a wrong MAC", resp);
+        return Err(Vf2SdDriverError::RpmbAuthentication);
+    }
+    Ok(())
+}
+
+impl<T: SDIo, S: SleepOps> Vf2SdDriver<T, S> {
+    fn set_block_count(&mut self, count: usize, reliable: bool) -> Result<()> {
+        let cmd23 = CmdReg::from(Cmd::SetBlockCount);
+        let arg = ((reliable as u32) << 31) | count as u32;
+        send_cmd::<_, S>(
+            &mut self.io,
+            Cmd::SetBlockCount,
+            cmd23,
+            CmdArg::new(arg),
+            DataTransType::None,
+        )?;
+        Ok(())
+    }
+
+    /// Write request frames, RPMB only accepts transfers with a block count set by CMD23
+    fn rpmb_send(&mut self, frames: &[u8], reliable: bool) -> Result<()> {
+        self.set_block_count(frames.len() / FRAME_SIZE, reliable)?;
+        set_transaction_size(&mut self.io, FRAME_SIZE as u32, frames.len() as u32);
+        let cmd25 = CmdReg::from(Cmd::WriteMultipleBlock).with_send_auto_stop(false);
+        send_cmd::<_, S>(
+            &mut self.io,
+            Cmd::WriteMultipleBlock,
+            cmd25,
+            CmdArg::new(0),
+            DataTransType::Write(frames),
+        )?;
+        self.wait_programmed(frames.len())
+    }
+
+    fn rpmb_recv(&mut self, frames: &mut [u8]) -> Result<()> {
+        self.set_block_count(frames.len() / FRAME_SIZE, false)?;
+        set_transaction_size(&mut self.io, FRAME_SIZE as u32, frames.len() as u32);
+        let cmd18 = CmdReg::from(Cmd::ReadMultipleBlock).with_send_auto_stop(false);
+        send_cmd::<_, S>(
+            &mut self.io,
+            Cmd::ReadMultipleBlock,
+            cmd18,
+            CmdArg::new(0),
+            DataTransType::Read(frames),
+        )?;
+        Ok(())
+    }
+
+    /// Read the result of the last program key or authenticated write request
+    fn rpmb_result(&mut self) -> Result<[u8; FRAME_SIZE]> {
+        let mut frame = [0u8; FRAME_SIZE];
+        put_u16(&mut frame, REQ_RESP, REQ_RESULT_READ);
+        self.rpmb_send(&frame, false)?;
+        self.rpmb_recv(&mut frame)?;
+        Ok(frame)
+    }
+
+    /// Run `f` with the RPMB partition selected and switch back to the previous partition
+    fn with_rpmb<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R>) -> Result<R> {
+        let previous = self.partition();
+        self.select_partition(HwPartition::Rpmb)?;
+        let res = f(self);
+        let restore = self.select_partition(previous);
+        let res = res?;
+        restore.map(|_| res)
+    }
+
+    /// Program the authentication key, this can be done only once in the device lifetime
+    pub fn rpmb_program_key(&mut self, key: &RpmbKey) -> Result<()> {
+        self.with_rpmb(|driver| {
+            let mut frame = [0u8; FRAME_SIZE];
+            frame[KEY_MAC..DATA].copy_from_slice(key);
+            put_u16(&mut frame, REQ_RESP, REQ_PROGRAM_KEY);
+            driver.rpmb_send(&frame, true)?;
+            let frame = driver.rpmb_result()?;
+            check_response(&frame, REQ_PROGRAM_KEY, None, None)
+        })
+    }
+
+    fn rpmb_read_counter(&mut self, key: &RpmbKey, nonce: &[u8; 16]) -> Result<u32> {
+        let mut frame = [0u8; FRAME_SIZE];
+        frame[NONCE..NONCE + 16].copy_from_slice(nonce);
+        put_u16(&mut frame, REQ_RESP, REQ_WRITE_COUNTER);
+        self.rpmb_send(&frame, false)?;
+        self.rpmb_recv(&mut frame)?;
+        check_response(&frame, REQ_WRITE_COUNTER, Some(key), Some(nonce))?;
+        Ok(get_u32(&frame, WRITE_COUNTER))
+    }
+
+    /// Read the write counter, `nonce` should be random to detect replayed responses
+    pub fn rpmb_write_counter(&mut self, key: &RpmbKey, nonce: &[u8; 16]) -> Result<u32> {
+        self.with_rpmb(|driver| driver.rpmb_read_counter(key, nonce))
+    }
+
+    /// Authenticated read of `buf.len() / RPMB_DATA_SIZE` half sectors starting at `address`
+    pub fn rpmb_read(
+        &mut self,
+        key: &RpmbKey,
+        nonce: &[u8; 16],
+        address: u16,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        if buf.is_empty() || buf.len() % RPMB_DATA_SIZE != 0 {
+            return Err(Vf2SdDriverError::BufferSizeError);
+        }
+        self.with_rpmb(|driver| {
+            for (i, data) in buf.chunks_mut(RPMB_DATA_SIZE).enumerate() {
+                let address = address
+                    .checked_add(i as u16)
+                    .ok_or(Vf2SdDriverError::OutOfRange(address as usize + i))?;
+                let mut frame = [0u8; FRAME_SIZE];
+                frame[NONCE..NONCE + 16].copy_from_slice(nonce);
+                put_u16(&mut frame, ADDRESS, address);
+                put_u16(&mut frame, REQ_RESP, REQ_AUTH_READ);
+                driver.rpmb_send(&frame, false)?;
+                driver.rpmb_recv(&mut frame)?;
+                check_response(&frame, REQ_AUTH_READ, Some(key), Some(nonce))?;
+                data.copy_from_slice(&frame[DATA..NONCE]);
+            }
+            Ok(())
+        })
+    }
+
+    /// Authenticated write of `data` starting at `address`, returning the new write counter
+    ///
+    /// The write counter is read with `nonce` first, every half sector is written with
+    /// its own reliable write request.
+    pub fn rpmb_write(
+        &mut self,
+        key: &RpmbKey,
+        nonce: &[u8; 16],
+        address: u16,
+        data: &[u8],
+    ) -> Result<u32> {
+        if data.is_empty() || data.len() % RPMB_DATA_SIZE != 0 {
+            return Err(Vf2SdDriverError::BufferSizeError);
+        }
+        self.with_rpmb(|driver| {
+            let mut counter = driver.rpmb_read_counter(key, nonce)?;
+            for (i, data) in data.chunks(RPMB_DATA_SIZE).enumerate() {
+                let address = address
+                    .checked_add(i as u16)
+                    .ok_or(Vf2SdDriverError::OutOfRange(address as usize + i))?;
+                let mut frame = [0u8; FRAME_SIZE];
+                frame[DATA..NONCE].copy_from_slice(data);
+                put_u32(&mut frame, WRITE_COUNTER, counter);
+                put_u16(&mut frame, ADDRESS, address);
+                put_u16(&mut frame, BLOCK_COUNT, 1);
+                put_u16(&mut frame, REQ_RESP, REQ_AUTH_WRITE);
+                let mac = rpmb_mac(key, &frame);
+                frame[KEY_MAC..DATA].copy_from_slice(&mac);
+                driver.rpmb_send(&frame, true)?;
+                let frame = driver.rpmb_result()?;
+                check_response(&frame, REQ_AUTH_WRITE, Some(key), None)?;
+                if get_u32(&frame, WRITE_COUNTER) != counter.wrapping_add(1)
+                    || get_u16(&frame, ADDRESS) != address
+                {
+                    return Err(Vf2SdDriverError::RpmbAuthentication);
+                }
+                counter += 1;
+            }
+            Ok(counter)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimCard, SimSleep, SimulatedController};
+    use std::vec;
+
+    const KEY: RpmbKey = [0x42; 32];
+    const NONCE: [u8; 16] = [7; 16];
+
+    fn driver() -> Vf2SdDriver<SimulatedController, SimSleep> {
+        let image = vec![0u8; 1024 * 1024];
+        let mut driver = Vf2SdDriver::new(SimulatedController::new(SimCard::new(image).with_mmc()));
+        driver.init().unwrap();
+        driver
+    }
+
+    #[test]
+    fn test_hmac() {
+        // RFC 4231 test case 2
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        assert_eq!(mac.finalize().into_bytes()[..4], [0x5b, 0xdc, 0xc1, 0x46]);
+    }
+
+    #[test]
+    fn test_rpmb() {
+        let mut driver = driver();
+        assert_eq!(
+            driver.rpmb_write_counter(&KEY, &NONCE),
+            Err(Vf2SdDriverError::Rpmb(7))
+        );
+        driver.rpmb_program_key(&KEY).unwrap();
+        assert_eq!(
+            driver.rpmb_program_key(&KEY),
+            Err(Vf2SdDriverError::Rpmb(5))
+        );
+        assert_eq!(driver.rpmb_write_counter(&KEY, &NONCE).unwrap(), 0);
+        let data: vec::Vec<u8> = (0..RPMB_DATA_SIZE * 2).map(|i| i as u8).collect();
+        assert_eq!(driver.rpmb_write(&KEY, &NONCE, 3, &data).unwrap(), 2);
+        let mut buf = [0u8; RPMB_DATA_SIZE * 3];
+        driver.rpmb_read(&KEY, &NONCE, 2, &mut buf).unwrap();
+        assert!(buf[..RPMB_DATA_SIZE].iter().all(|&b| b == 0));
+        assert_eq!(&buf[RPMB_DATA_SIZE..], &data[..]);
+        // the user area is selected again
+        assert_eq!(driver.partition(), HwPartition::User);
+    }
+
+    #[test]
+    fn test_rpmb_wrong_key() {
+        let mut driver = driver();
+        driver.rpmb_program_key(&KEY).unwrap();
+        let key = [0x24; 32];
+        assert_eq!(
+            driver.rpmb_write_counter(&key, &NONCE),
+            Err(Vf2SdDriverError::RpmbAuthentication)
+        );
+        assert_eq!(
+            driver.rpmb_write(&key, &NONCE, 0, &[0; RPMB_DATA_SIZE]),
+            Err(Vf2SdDriverError::RpmbAuthentication)
+        );
+        // the card rejects the write MAC
+        let res = driver.with_rpmb(|driver| {
+            let mut frame = [0u8; FRAME_SIZE];
+            put_u16(&mut frame, BLOCK_COUNT, 1);
+            put_u16(&mut frame, REQ_RESP, REQ_AUTH_WRITE);
+            let mac = rpmb_mac(&key, &frame);
+            frame[KEY_MAC..DATA].copy_from_slice(&mac);
+            driver.rpmb_send(&frame, true)?;
+            let frame = driver.rpmb_result()?;
+            check_response(&frame, REQ_AUTH_WRITE, None, None)
+        });
+        assert_eq!(res, Err(Vf2SdDriverError::Rpmb(2)));
+        assert_eq!(driver.rpmb_write_counter(&KEY, &NONCE).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rpmb_out_of_range() {
+        let mut driver = driver();
+        driver.rpmb_program_key(&KEY).unwrap();
+        // 128 KiB are 512 half sectors
+        let mut buf = [0u8; RPMB_DATA_SIZE];
+        assert_eq!(
+            driver.rpmb_read(&KEY, &NONCE, 512, &mut buf),
+            Err(Vf2SdDriverError::Rpmb(4))
+        );
+        driver.rpmb_read(&KEY, &NONCE, 511, &mut buf).unwrap();
+    }
+}
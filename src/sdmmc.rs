@@ -0,0 +1,116 @@
+//! [`embedded_sdmmc::BlockDevice`] for the driver
+use crate::{Result, SDIo, SleepOps, Vf2SdDriver, Vf2SdDriverError, BLOCK_SIZE};
+use core::cell::RefCell;
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+/// Blocks moved by one multi-block transfer
+const BATCH_BLOCKS: usize = 8;
+
+/// An initialized [`Vf2SdDriver`] usable as an [`embedded_sdmmc::BlockDevice`]
+///
+/// `BlockDevice` takes `&self`, so the driver is kept in a `RefCell`.
+pub struct SdmmcBlockDevice<T, S> {
+    driver: RefCell<Vf2SdDriver<T, S>>,
+}
+
+impl<T: SDIo, S: SleepOps> SdmmcBlockDevice<T, S> {
+    pub fn new(driver: Vf2SdDriver<T, S>) -> Self {
+        Self {
+            driver: RefCell::new(driver),
+        }
+    }
+
+    pub fn into_inner(self) -> Vf2SdDriver<T, S> {
+        self.driver.into_inner()
+    }
+}
+
+impl<T: SDIo, S: SleepOps> BlockDevice for SdmmcBlockDevice<T, S> {
+    type Error = Vf2SdDriverError;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<()> {
+        let mut driver = self.driver.borrow_mut();
+        let mut block = start_block_idx.0 as usize;
+        if let [one] = blocks {
+            driver.read_block(block, &mut one.contents)?;
+            return Ok(());
+        }
+        // `Block` has no layout guarantee, bounce through a contiguous buffer
+        let mut buf = [0u8; BATCH_BLOCKS * BLOCK_SIZE];
+        for chunk in blocks.chunks_mut(BATCH_BLOCKS) {
+            let buf = &mut buf[..chunk.len() * BLOCK_SIZE];
+            driver.read_blocks(block, buf)?;
+            for (dst, src) in chunk.iter_mut().zip(buf.chunks(BLOCK_SIZE)) {
+                dst.contents.copy_from_slice(src);
+            }
+            block += chunk.len();
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<()> {
+        let mut driver = self.driver.borrow_mut();
+        let mut block = start_block_idx.0 as usize;
+        if let [one] = blocks {
+            driver.write_block(block, &one.contents)?;
+            return Ok(());
+        }
+        let mut buf = [0u8; BATCH_BLOCKS * BLOCK_SIZE];
+        for chunk in blocks.chunks(BATCH_BLOCKS) {
+            let buf = &mut buf[..chunk.len() * BLOCK_SIZE];
+            for (dst, src) in buf.chunks_mut(BLOCK_SIZE).zip(chunk) {
+                dst.copy_from_slice(&src.contents);
+            }
+            driver.write_blocks(block, buf)?;
+            block += chunk.len();
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount> {
+        let blocks = self.driver.borrow().capacity_blocks();
+        Ok(BlockCount(blocks.min(u32::MAX as u64) as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimCard, SimSleep, SimulatedController};
+    use std::vec;
+    use std::vec::Vec;
+
+    fn device() -> SdmmcBlockDevice<SimulatedController, SimSleep> {
+        let image = (0..1024 * 1024).map(|i| (i / BLOCK_SIZE) as u8).collect();
+        let controller = SimulatedController::new(SimCard::new(image));
+        let mut driver = Vf2SdDriver::new(controller);
+        driver.init().unwrap();
+        SdmmcBlockDevice::new(driver)
+    }
+
+    #[test]
+    fn test_block_device() {
+        let device = device();
+        assert_eq!(device.num_blocks().unwrap(), BlockCount(2048));
+        let mut blocks = vec![Block::new(); 11];
+        device.read(&mut blocks, BlockIdx(20)).unwrap();
+        for (i, block) in blocks.iter().enumerate() {
+            assert!(block.iter().all(|&b| b == 20 + i as u8));
+        }
+        let blocks: Vec<Block> = (0..10u8)
+            .map(|i| Block {
+                contents: [0x80 | i; BLOCK_SIZE],
+            })
+            .collect();
+        device.write(&blocks, BlockIdx(100)).unwrap();
+        device.write(&blocks[..1], BlockIdx(200)).unwrap();
+        let mut read = vec![Block::new(); 10];
+        device.read(&mut read, BlockIdx(100)).unwrap();
+        assert!(read
+            .iter()
+            .zip(&blocks)
+            .all(|(a, b)| a.contents == b.contents));
+        device.read(&mut read[..1], BlockIdx(200)).unwrap();
+        assert_eq!(read[0].contents, [0x80; BLOCK_SIZE]);
+    }
+}
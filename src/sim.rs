@@ -0,0 +1,1501 @@
+//! A host-side model of the DesignWare mobile storage host controller with an SD card
+//! attached, so the driver can be exercised without a VisionFive 2 board.
+//!
+//! The controller executes a command as soon as `CMD_REG` is written with `start_cmd`
+//! set and moves data between the card and the FIFO whenever a register is accessed.
+//! With the internal DMA controller enabled the whole data phase is moved at once, bus
+//! addresses are host addresses once [`SimulatedController::enable_dma`] allowed it.
+//!
+//! Errors are injected with a [`FaultPlan`]:
+//! ```rust
+//! use visionfive2_sd::sim::{Fault, FaultPlan, SimCard, SimSleep, SimulatedController};
+//! use visionfive2_sd::{Cmd, Vf2SdDriver, Vf2SdDriverError};
+//! let mut controller = SimulatedController::new(SimCard::new(vec![0; 1024 * 1024]));
+//! // init reads block 0 with the first CMD17
+//! controller.set_fault_plan(FaultPlan::new().on_nth_cmd(17, 2, Fault::DataCrc));
+//! let mut driver = Vf2SdDriver::<_, SimSleep>::new(controller);
+//! driver.init().unwrap();
+//! let mut buf = [0u8; 512];
+//! assert_eq!(
+//!     driver.read_block(1, &mut buf),
+//!     Err(Vf2SdDriverError::DataCrc(Cmd::ReadSingleBlock))
+//! );
+//! ```
+use crate::dma::{DES0_LD, DES0_OWN};
+use crate::register::*;
+use crate::utils::SDIo;
+use crate::SleepOps;
+use core::cell::RefCell;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::rc::Rc;
+use std::vec::Vec;
+
+/// FIFO depth in 32-bit words
+const FIFO_DEPTH: usize = 128;
+/// TXDR is raised while the FIFO holds at most this many words
+const TX_WATERMARK: usize = 64;
+
+/// RCA published by the simulated card
+pub const SIM_RCA: u32 = 0xaaaa;
+
+/// Raw interrupt bits, see [`RawInterrupt`]
+const INT_CARD_DETECT: u32 = 1 << 0;
+const INT_CD: u32 = 1 << 2;
+const INT_DTO: u32 = 1 << 3;
+const INT_TXDR: u32 = 1 << 4;
+const INT_RXDR: u32 = 1 << 5;
+const INT_RCRC: u32 = 1 << 6;
+const INT_DCRC: u32 = 1 << 7;
+const INT_RTO: u32 = 1 << 8;
+const INT_DRTO: u32 = 1 << 9;
+const INT_FRUN: u32 = 1 << 11;
+const INT_ACD: u32 = 1 << 14;
+const INT_EBE: u32 = 1 << 15;
+
+/// R1 card status bits
+const R1_OUT_OF_RANGE: u32 = 1 << 31;
+const R1_ADDRESS_ERROR: u32 = 1 << 30;
+const R1_READY_FOR_DATA: u32 = 1 << 8;
+const R1_ERASE_SEQ_ERROR: u32 = 1 << 28;
+const R1_SWITCH_ERROR: u32 = 1 << 7;
+const R1_APP_CMD: u32 = 1 << 5;
+
+/// Bytes of the EXT_CSD the host may change with CMD6
+const EXT_CSD_WRITABLE: [usize; 3] = [
+    EXT_CSD_PARTITION_CONFIG as usize,
+    EXT_CSD_BUS_WIDTH as usize,
+    EXT_CSD_HS_TIMING as usize,
+];
+/// Size of each boot partition and of the RPMB partition of the eMMC
+const MMC_PARTITION_SIZE: usize = 128 * 1024;
+/// PARTITION_ACCESS of the RPMB partition
+#[cfg(feature = "rpmb")]
+const PARTITION_RPMB: u8 = 3;
+
+/// ACMD41/CMD1 calls answered with busy before the card is ready
+const OP_COND_BUSY: usize = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CardState {
+    Idle,
+    Ready,
+    Ident,
+    Stby,
+    Tran,
+    Data,
+    Rcv,
+    Prg,
+}
+
+impl CardState {
+    fn bits(&self) -> u32 {
+        let state = match self {
+            CardState::Idle => 0,
+            CardState::Ready => 1,
+            CardState::Ident => 2,
+            CardState::Stby => 3,
+            CardState::Tran => 4,
+            CardState::Data => 5,
+            CardState::Rcv => 6,
+            CardState::Prg => 7,
+        };
+        state << 9
+    }
+}
+
+/// A response of the card to a command
+enum Response {
+    /// The card does not answer
+    None,
+    Short(u32),
+    Long(u128),
+}
+
+/// The data phase the card starts after a command
+enum DataPhase {
+    None,
+    /// Send the bytes to the host
+    Read(Vec<u8>),
+    /// Receive bytes from the host and store them at the given byte address
+    Write(usize),
+    /// The card rejected the data command
+    Rejected,
+}
+
+/// An SD memory card or eMMC backed by an in-memory image
+pub struct SimCard {
+    image: Vec<u8>,
+    mmc: bool,
+    boot: [Vec<u8>; 2],
+    #[cfg(feature = "rpmb")]
+    rpmb: SimRpmb,
+    ext_csd: Vec<u8>,
+    switch_error: bool,
+    version: u8,
+    high_capacity: bool,
+    high_speed_support: bool,
+    state: CardState,
+    app_cmd: bool,
+    op_cond_calls: usize,
+    hcs: bool,
+    rca: u32,
+    block_len: usize,
+    bus_width4: bool,
+    bus_width8: bool,
+    high_speed: bool,
+    /// CMD13 polls answered in the programming state after a write or erase
+    program_polls: usize,
+    /// Remaining CMD13 polls of the current programming state
+    programming: usize,
+    /// Byte addresses of the first and the last block to erase
+    erase_start: Option<usize>,
+    erase_end: Option<usize>,
+}
+
+impl SimCard {
+    /// A v2.0 SDHC card with high speed support, the image is truncated to a
+    /// multiple of 512 KiB
+    pub fn new(mut image: Vec<u8>) -> Self {
+        image.truncate(image.len() / (512 * 1024) * 512 * 1024);
+        Self {
+            image,
+            mmc: false,
+            boot: [Vec::new(), Vec::new()],
+            #[cfg(feature = "rpmb")]
+            rpmb: SimRpmb::default(),
+            ext_csd: std::vec![0; 512],
+            switch_error: false,
+            version: 2,
+            high_capacity: true,
+            high_speed_support: true,
+            state: CardState::Idle,
+            app_cmd: false,
+            op_cond_calls: 0,
+            hcs: false,
+            rca: 0,
+            block_len: 512,
+            bus_width4: false,
+            bus_width8: false,
+            high_speed: false,
+            program_polls: 0,
+            programming: 0,
+            erase_start: None,
+            erase_end: None,
+        }
+    }
+
+    /// A sector addressed eMMC supporting HS52, which answers CMD1 instead of ACMD41
+    ///
+    /// It has two 128 KiB boot partitions, and a 128 KiB RPMB partition with the `rpmb` feature.
+    pub fn with_mmc(mut self) -> Self {
+        self.mmc = true;
+        self.boot = [
+            std::vec![0; MMC_PARTITION_SIZE],
+            std::vec![0; MMC_PARTITION_SIZE],
+        ];
+        #[cfg(feature = "rpmb")]
+        {
+            self.rpmb = SimRpmb::new();
+        }
+        self.init_ext_csd();
+        self
+    }
+
+    /// Stay in the programming state for `polls` CMD13 after each write and erase
+    pub fn with_program_polls(mut self, polls: usize) -> Self {
+        self.program_polls = polls;
+        self
+    }
+
+    /// Load the image from a file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(std::fs::read(path)?))
+    }
+
+    /// A standard capacity card which is byte addressed
+    pub fn with_standard_capacity(mut self) -> Self {
+        self.high_capacity = false;
+        self
+    }
+
+    /// A v1.x card which does not know CMD8, implies standard capacity
+    pub fn with_version1(mut self) -> Self {
+        self.version = 1;
+        self.high_capacity = false;
+        self.high_speed_support = false;
+        self
+    }
+
+    pub fn with_high_speed_support(mut self, support: bool) -> Self {
+        self.high_speed_support = support;
+        self
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.image
+    }
+
+    /// Write the image to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, &self.image)
+    }
+
+    pub fn is_bus_width4(&self) -> bool {
+        self.bus_width4
+    }
+
+    pub fn is_bus_width8(&self) -> bool {
+        self.bus_width8
+    }
+
+    pub fn is_high_speed(&self) -> bool {
+        self.high_speed
+    }
+
+    /// The EXT_CSD of a MMC
+    pub fn ext_csd(&self) -> &[u8] {
+        &self.ext_csd
+    }
+
+    /// Boot partition `n` (1 or 2) of a MMC
+    pub fn boot_partition(&self, n: usize) -> &[u8] {
+        &self.boot[n - 1]
+    }
+
+    /// PARTITION_ACCESS, the partition the data commands access
+    fn partition(&self) -> u8 {
+        self.ext_csd[EXT_CSD_PARTITION_CONFIG as usize] & 0x7
+    }
+
+    fn partition_data(&self) -> &[u8] {
+        match self.partition() {
+            1 => &self.boot[0],
+            2 => &self.boot[1],
+            _ => &self.image,
+        }
+    }
+
+    /// CMD38, erase the blocks set by CMD32/CMD33 (SD) or CMD35/CMD36 (MMC)
+    fn erase(&mut self, arg: u32) -> u32 {
+        let (Some(start), Some(end)) = (self.erase_start.take(), self.erase_end.take()) else {
+            return R1_ERASE_SEQ_ERROR;
+        };
+        if start > end {
+            return R1_ERASE_SEQ_ERROR;
+        }
+        let (mut start, mut end) = (start, end + 512);
+        let value = if self.mmc {
+            self.ext_csd[181]
+        } else if self.scr() >> 55 & 1 != 0 {
+            0xff
+        } else {
+            0
+        };
+        match arg {
+            // DISCARD keeps the data
+            3 if self.mmc => {
+                self.start_programming();
+                return 0;
+            }
+            // TRIM erases write blocks
+            1 if self.mmc => {}
+            // MMC erases whole erase groups
+            _ if self.mmc => {
+                let group = if self.ext_csd[175] & 1 != 0 {
+                    self.ext_csd[224] as usize * 512 * 1024
+                } else {
+                    Csd::new(self.csd()).mmc_erase_grp_blocks() as usize * 512
+                };
+                start = start / group * group;
+                end = end.div_ceil(group) * group;
+            }
+            _ => {}
+        }
+        let data = match self.partition() {
+            1 => &mut self.boot[0],
+            2 => &mut self.boot[1],
+            _ => &mut self.image,
+        };
+        let end = end.min(data.len());
+        data[start..end].fill(value);
+        self.start_programming();
+        0
+    }
+
+    /// Store data received from the host at the byte address `addr`
+    fn store(&mut self, addr: usize, data: &[u8]) {
+        let target = match self.partition() {
+            1 => &mut self.boot[0],
+            2 => &mut self.boot[1],
+            #[cfg(feature = "rpmb")]
+            PARTITION_RPMB => return self.rpmb.request(data),
+            _ => &mut self.image,
+        };
+        target[addr..addr + data.len()].copy_from_slice(data);
+    }
+
+    fn is_standard_capacity(&self) -> bool {
+        !self.high_capacity || !self.hcs
+    }
+
+    fn ocr(&self) -> u32 {
+        let mut ocr = if self.mmc { 0x00ff_8080 } else { 0x00ff_8000 };
+        if self.op_cond_calls > OP_COND_BUSY {
+            ocr |= 1 << 31;
+            if self.high_capacity && self.hcs {
+                ocr |= 1 << 30;
+            }
+        }
+        ocr
+    }
+
+    fn cid(&self) -> u128 {
+        if self.mmc {
+            // mid 0x15, BGA, oid 1, "SIMMC1", 1.0, serial 0x12345678, 2024-01
+            return 0x1501_0153_494d_4d43_3110_1234_5678_1b01;
+        }
+        // mid 3, "SD", "SIM01", 1.0, serial 0x12345678, 2024-01
+        0x0353_4453_494d_3031_1012_3456_7801_8101
+    }
+
+    fn csd(&self) -> u128 {
+        // TAAC, NSAC, TRAN_SPEED 25 MHz, CCC 0x5b5
+        let common = (0x0e_u128 << 112) | (0x32 << 96) | (0x5b5 << 84) | (9 << 80);
+        // ERASE_BLK_EN, SECTOR_SIZE 127, WRITE_BL_LEN 9
+        let common = common | (1 << 46) | (0x7f << 39) | (9 << 22) | 1;
+        if self.mmc {
+            // CSD_STRUCTURE 1.2, SPEC_VERS 4, cards above 2 GB report the maximal C_SIZE
+            let c_size = if self.high_capacity {
+                0xfff
+            } else {
+                (self.image.len() / (512 * 512)) as u128 - 1
+            };
+            (2 << 126) | (4 << 122) | common | (c_size << 62) | (7 << 47)
+        } else if self.high_capacity {
+            let c_size = (self.image.len() / (512 * 1024)) as u128 - 1;
+            (1 << 126) | common | (c_size << 48)
+        } else {
+            // C_SIZE_MULT 7, (C_SIZE + 1) * 512 blocks
+            let c_size = (self.image.len() / (512 * 512)) as u128 - 1;
+            common | (c_size << 62) | (7 << 47)
+        }
+    }
+
+    fn init_ext_csd(&mut self) {
+        self.ext_csd.fill(0);
+        // EXT_CSD_REV 1.8
+        self.ext_csd[192] = 8;
+        self.ext_csd[196] = if self.high_speed_support { 0x03 } else { 0 };
+        // BOOT_SIZE_MULT and RPMB_SIZE_MULT in 128 KiB
+        self.ext_csd[226] = 1;
+        self.ext_csd[168] = cfg!(feature = "rpmb") as u8;
+        // 512 KiB erase groups if ERASE_GROUP_DEF is set, erase and trim take up to 300 ms
+        self.ext_csd[175] = self.high_capacity as u8;
+        self.ext_csd[224] = 1;
+        self.ext_csd[223] = 1;
+        self.ext_csd[232] = 1;
+        // SEC_GB_CL_EN, TRIM is supported
+        self.ext_csd[231] = 0x10;
+        if self.high_capacity {
+            let sectors = (self.image.len() / 512) as u32;
+            self.ext_csd[212..216].copy_from_slice(&sectors.to_le_bytes());
+        }
+    }
+
+    /// CMD6 of MMC, only the write byte access mode is supported
+    fn switch(&mut self, arg: u32) {
+        let index = (arg >> 16 & 0xff) as usize;
+        let value = (arg >> 8 & 0xff) as u8;
+        let valid = match index {
+            _ if arg >> 24 & 0x3 != 0b11 || !EXT_CSD_WRITABLE.contains(&index) => false,
+            i if i == EXT_CSD_BUS_WIDTH as usize => value <= 2,
+            i if i == EXT_CSD_PARTITION_CONFIG as usize => {
+                value & 0x7 <= 2 || (value & 0x7 == 3 && self.ext_csd[168] != 0)
+            }
+            _ => value <= 1,
+        };
+        if !valid {
+            self.switch_error = true;
+            return;
+        }
+        self.ext_csd[index] = value;
+        self.bus_width4 = self.ext_csd[EXT_CSD_BUS_WIDTH as usize] == 1;
+        self.bus_width8 = self.ext_csd[EXT_CSD_BUS_WIDTH as usize] == 2;
+        self.high_speed = self.ext_csd[EXT_CSD_HS_TIMING as usize] == 1;
+    }
+
+    fn scr(&self) -> u64 {
+        if self.version == 1 {
+            // SD 1.0, 1-bit and 4-bit
+            0x0005_0000_0000_0000
+        } else {
+            // SD 3.0, erased blocks read as 0xff, 1-bit and 4-bit
+            0x02b5_8000_0000_0000
+        }
+    }
+
+    fn switch_status(&self, arg: u32) -> Vec<u8> {
+        let mut status = std::vec![0u8; 64];
+        status[1] = 100;
+        let func = (arg & 0xf) as u8;
+        status[13] = if self.high_speed_support { 0x03 } else { 0x01 };
+        let selected = match func {
+            0 => 0,
+            1 if self.high_speed_support => 1,
+            0xf => self.high_speed as u8,
+            _ => 0xf,
+        };
+        status[16] = selected;
+        status
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = self.state.bits();
+        if self.state == CardState::Tran {
+            status |= R1_READY_FOR_DATA;
+        }
+        if self.app_cmd {
+            status |= R1_APP_CMD;
+        }
+        if self.switch_error {
+            status |= R1_SWITCH_ERROR;
+        }
+        status
+    }
+
+    /// The byte address of the data commands, or None if out of range
+    fn data_addr(&self, arg: u32, len: usize) -> Option<usize> {
+        let addr = if self.is_standard_capacity() {
+            arg as usize
+        } else {
+            arg as usize * 512
+        };
+        if addr + len <= self.partition_data().len() && addr % 512 == 0 && len % self.block_len == 0
+        {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = CardState::Idle;
+        self.app_cmd = false;
+        self.op_cond_calls = 0;
+        self.rca = 0;
+        self.block_len = 512;
+        self.bus_width4 = false;
+        self.bus_width8 = false;
+        self.high_speed = false;
+        self.switch_error = false;
+        self.erase_start = None;
+        self.erase_end = None;
+        if self.mmc {
+            self.init_ext_csd();
+        }
+    }
+
+    /// Execute a command, `len` is the byte count of the data phase
+    fn command(&mut self, index: u16, arg: u32, len: usize) -> (Response, DataPhase) {
+        let app_cmd = core::mem::take(&mut self.app_cmd);
+        let status = self.status();
+        let selected = |card: &Self| card.state == CardState::Tran;
+        match (app_cmd, index) {
+            (_, 0) => {
+                self.reset();
+                (Response::None, DataPhase::None)
+            }
+            (_, 8) if !self.mmc && self.version >= 2 && self.state == CardState::Idle => {
+                (Response::Short(arg & 0xfff), DataPhase::None)
+            }
+            (_, 55) if !self.mmc => {
+                self.app_cmd = true;
+                (Response::Short(self.status()), DataPhase::None)
+            }
+            (true, 41) if self.state == CardState::Idle || self.state == CardState::Ready => {
+                self.hcs = arg & (1 << 30) != 0;
+                self.op_cond_calls += 1;
+                let ocr = self.ocr();
+                if ocr & (1 << 31) != 0 {
+                    self.state = CardState::Ready;
+                }
+                (Response::Short(ocr), DataPhase::None)
+            }
+            (false, 1) if self.mmc && matches!(self.state, CardState::Idle | CardState::Ready) => {
+                self.hcs = arg & (1 << 30) != 0;
+                self.op_cond_calls += 1;
+                let ocr = self.ocr();
+                if ocr & (1 << 31) != 0 {
+                    self.state = CardState::Ready;
+                }
+                (Response::Short(ocr), DataPhase::None)
+            }
+            (_, 2) if self.state == CardState::Ready => {
+                self.state = CardState::Ident;
+                (Response::Long(self.cid()), DataPhase::None)
+            }
+            (_, 3) if self.mmc && self.state == CardState::Ident => {
+                self.state = CardState::Stby;
+                self.rca = arg >> 16;
+                (Response::Short(status), DataPhase::None)
+            }
+            (_, 3) if self.state == CardState::Ident || self.state == CardState::Stby => {
+                self.state = CardState::Stby;
+                self.rca = SIM_RCA;
+                // COM_CRC_ERROR, ILLEGAL_COMMAND and ERROR move to bits 15..13
+                let r6_status =
+                    (status & 0x1fff) | ((status >> 8) & 0xc000) | ((status >> 6) & 0x2000);
+                (
+                    Response::Short((self.rca << 16) | r6_status),
+                    DataPhase::None,
+                )
+            }
+            (_, 9) if self.state == CardState::Stby && arg >> 16 == self.rca => {
+                (Response::Long(self.csd()), DataPhase::None)
+            }
+            (_, 7) => {
+                if arg >> 16 == self.rca {
+                    self.state = CardState::Tran;
+                    (Response::Short(status), DataPhase::None)
+                } else {
+                    self.state = CardState::Stby;
+                    (Response::None, DataPhase::None)
+                }
+            }
+            (_, 13) if arg >> 16 == self.rca => {
+                self.switch_error = false;
+                if self.state == CardState::Prg {
+                    self.programming = self.programming.saturating_sub(1);
+                    if self.programming == 0 {
+                        self.state = CardState::Tran;
+                    }
+                }
+                (Response::Short(status), DataPhase::None)
+            }
+            (_, 8) if self.mmc && selected(self) => {
+                self.state = CardState::Data;
+                (
+                    Response::Short(status),
+                    DataPhase::Read(self.ext_csd.clone()),
+                )
+            }
+            (false, 6) if self.mmc && selected(self) => {
+                self.switch(arg);
+                (Response::Short(status), DataPhase::None)
+            }
+            (true, 6) if selected(self) => {
+                self.bus_width4 = arg & 0x3 == 2;
+                (Response::Short(status), DataPhase::None)
+            }
+            (true, 51) if selected(self) => {
+                self.state = CardState::Data;
+                let scr = self.scr().to_be_bytes().to_vec();
+                (Response::Short(status), DataPhase::Read(scr))
+            }
+            (false, 6) if !self.mmc && selected(self) && self.version >= 2 => {
+                let data = self.switch_status(arg);
+                if arg >> 31 != 0 && data[16] == 1 {
+                    self.high_speed = true;
+                }
+                self.state = CardState::Data;
+                (Response::Short(status), DataPhase::Read(data))
+            }
+            (_, 16) if selected(self) => {
+                self.block_len = arg as usize;
+                (Response::Short(status), DataPhase::None)
+            }
+            (false, 23) if self.mmc && selected(self) => (Response::Short(status), DataPhase::None),
+            // the erase range commands of SD are CMD32/CMD33, MMC uses CMD35/CMD36
+            (_, 32 | 33 | 35 | 36) if selected(self) && self.mmc == (index >= 35) => {
+                match self.data_addr(arg, self.block_len) {
+                    Some(addr) if index == 32 || index == 35 => {
+                        self.erase_start = Some(addr);
+                        (Response::Short(status), DataPhase::None)
+                    }
+                    Some(addr) => {
+                        self.erase_end = Some(addr);
+                        (Response::Short(status), DataPhase::None)
+                    }
+                    None => (
+                        Response::Short(status | R1_OUT_OF_RANGE | R1_ADDRESS_ERROR),
+                        DataPhase::None,
+                    ),
+                }
+            }
+            (_, 38) if selected(self) => {
+                let error = self.erase(arg);
+                (Response::Short(status | error), DataPhase::None)
+            }
+            // RPMB frames are only transferred by CMD18/CMD25 after CMD23
+            #[cfg(feature = "rpmb")]
+            (_, 18) if selected(self) && self.partition() == PARTITION_RPMB => {
+                self.state = CardState::Data;
+                (
+                    Response::Short(status),
+                    DataPhase::Read(self.rpmb.response(len)),
+                )
+            }
+            #[cfg(feature = "rpmb")]
+            (_, 25) if selected(self) && self.partition() == PARTITION_RPMB => {
+                self.state = CardState::Rcv;
+                (Response::Short(status), DataPhase::Write(0))
+            }
+            #[cfg(feature = "rpmb")]
+            (_, 17 | 24) if selected(self) && self.partition() == PARTITION_RPMB => (
+                Response::Short(status | R1_ADDRESS_ERROR),
+                DataPhase::Rejected,
+            ),
+            (_, 17 | 18) if selected(self) => match self.data_addr(arg, len) {
+                Some(addr) => {
+                    self.state = CardState::Data;
+                    let data = self.partition_data()[addr..addr + len].to_vec();
+                    (Response::Short(status), DataPhase::Read(data))
+                }
+                None => (
+                    Response::Short(status | R1_OUT_OF_RANGE | R1_ADDRESS_ERROR),
+                    DataPhase::Rejected,
+                ),
+            },
+            (_, 24 | 25) if selected(self) => match self.data_addr(arg, len) {
+                Some(addr) => {
+                    self.state = CardState::Rcv;
+                    (Response::Short(status), DataPhase::Write(addr))
+                }
+                None => (
+                    Response::Short(status | R1_OUT_OF_RANGE | R1_ADDRESS_ERROR),
+                    DataPhase::Rejected,
+                ),
+            },
+            (_, 12) if self.state == CardState::Data || self.state == CardState::Rcv => {
+                self.state = CardState::Tran;
+                (Response::Short(status), DataPhase::None)
+            }
+            (_, 12) if self.state == CardState::Prg => (Response::Short(status), DataPhase::None),
+            // illegal commands are not answered
+            _ => (Response::None, DataPhase::None),
+        }
+    }
+
+    /// The data phase of the last command finished
+    fn data_done(&mut self) {
+        match self.state {
+            CardState::Data => self.state = CardState::Tran,
+            CardState::Rcv => self.start_programming(),
+            _ => {}
+        }
+    }
+
+    /// Store the data or erase the blocks, the card leaves the state after the CMD13 polls
+    fn start_programming(&mut self) {
+        self.state = if self.program_polls > 0 {
+            CardState::Prg
+        } else {
+            CardState::Tran
+        };
+        self.programming = self.program_polls;
+    }
+}
+
+/// The RPMB partition of the simulated eMMC
+#[cfg(feature = "rpmb")]
+#[derive(Default)]
+struct SimRpmb {
+    data: Vec<u8>,
+    key: Option<crate::RpmbKey>,
+    counter: u32,
+    /// Response to the last program key or authenticated write request
+    result: Vec<u8>,
+    /// Frames returned by the next read
+    response: Vec<u8>,
+    /// Address and nonce of a pending authenticated read
+    read: Option<(u16, [u8; 16])>,
+}
+
+#[cfg(feature = "rpmb")]
+impl SimRpmb {
+    fn new() -> Self {
+        Self {
+            data: std::vec![0; MMC_PARTITION_SIZE],
+            ..Default::default()
+        }
+    }
+
+    /// A response frame, with the MAC if the key is programmed
+    fn frame(&self, resp: u16, result: u16, f: impl FnOnce(&mut [u8])) -> Vec<u8> {
+        use crate::rpmb::*;
+        let mut frame = std::vec![0; FRAME_SIZE];
+        f(&mut frame);
+        put_u16(&mut frame, RESULT, result);
+        put_u16(&mut frame, REQ_RESP, resp << 8);
+        if let Some(key) = self.key.as_ref() {
+            let mac = rpmb_mac(key, &frame);
+            frame[KEY_MAC..DATA].copy_from_slice(&mac);
+        }
+        frame
+    }
+
+    /// Handle the request frames written by CMD25
+    fn request(&mut self, frames: &[u8]) {
+        use crate::rpmb::*;
+        let first = &frames[..FRAME_SIZE];
+        let last = &frames[frames.len() - FRAME_SIZE..];
+        let address = get_u16(first, ADDRESS);
+        match get_u16(first, REQ_RESP) {
+            REQ_PROGRAM_KEY => {
+                let result = if self.key.is_some() {
+                    5
+                } else {
+                    self.key = Some(last[KEY_MAC..DATA].try_into().unwrap());
+                    0
+                };
+                self.result = self.frame(REQ_PROGRAM_KEY, result, |_| {});
+            }
+            REQ_WRITE_COUNTER => {
+                let result = if self.key.is_some() { 0 } else { 7 };
+                let counter = self.counter;
+                self.response = self.frame(REQ_WRITE_COUNTER, result, |frame| {
+                    frame[NONCE..NONCE + 16].copy_from_slice(&first[NONCE..NONCE + 16]);
+                    put_u32(frame, WRITE_COUNTER, counter);
+                });
+            }
+            REQ_AUTH_WRITE => {
+                let count = frames.len() / FRAME_SIZE;
+                let start = address as usize * crate::RPMB_DATA_SIZE;
+                let result = match self.key.as_ref() {
+                    None => 7,
+                    Some(key) if rpmb_mac(key, frames) != last[KEY_MAC..DATA] => 2,
+                    _ if get_u32(first, WRITE_COUNTER) != self.counter => 3,
+                    _ if start + count * crate::RPMB_DATA_SIZE > self.data.len() => 4,
+                    _ => {
+                        for (i, frame) in frames.chunks(FRAME_SIZE).enumerate() {
+                            let at = start + i * crate::RPMB_DATA_SIZE;
+                            self.data[at..at + crate::RPMB_DATA_SIZE]
+                                .copy_from_slice(&frame[DATA..NONCE]);
+                        }
+                        self.counter += 1;
+                        0
+                    }
+                };
+                let counter = self.counter;
+                self.result = self.frame(REQ_AUTH_WRITE, result, |frame| {
+                    put_u32(frame, WRITE_COUNTER, counter);
+                    put_u16(frame, ADDRESS, address);
+                });
+            }
+            REQ_AUTH_READ => {
+                self.read = Some((address, first[NONCE..NONCE + 16].try_into().unwrap()));
+            }
+            REQ_RESULT_READ => self.response = self.result.clone(),
+            _ => {}
+        }
+    }
+
+    /// The frames read by CMD18
+    fn response(&mut self, len: usize) -> Vec<u8> {
+        use crate::rpmb::*;
+        let Some((address, nonce)) = self.read.take() else {
+            let mut response = core::mem::take(&mut self.response);
+            response.resize(len, 0);
+            return response;
+        };
+        let count = len / FRAME_SIZE;
+        let start = address as usize * crate::RPMB_DATA_SIZE;
+        let result = if self.key.is_none() {
+            7
+        } else if start + count * crate::RPMB_DATA_SIZE > self.data.len() {
+            4
+        } else {
+            0
+        };
+        let mut frames = std::vec![0; len];
+        for (i, frame) in frames.chunks_mut(FRAME_SIZE).enumerate() {
+            if result == 0 {
+                let at = start + i * crate::RPMB_DATA_SIZE;
+                frame[DATA..NONCE].copy_from_slice(&self.data[at..at + crate::RPMB_DATA_SIZE]);
+            }
+            frame[NONCE..NONCE + 16].copy_from_slice(&nonce);
+            put_u16(frame, ADDRESS, address);
+            put_u16(frame, BLOCK_COUNT, count as u16);
+            put_u16(frame, RESULT, result);
+            put_u16(frame, REQ_RESP, REQ_AUTH_READ << 8);
+        }
+        if let Some(key) = self.key.as_ref() {
+            let mac = rpmb_mac(key, &frames);
+            frames[len - FRAME_SIZE + KEY_MAC..len - FRAME_SIZE + DATA].copy_from_slice(&mac);
+        }
+        frames
+    }
+}
+
+/// An error injected into a command
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// The card does not answer the command
+    ResponseTimeout,
+    /// The response is received with a CRC error
+    ResponseCrc,
+    /// The data phase ends with a CRC error, written data is discarded
+    DataCrc,
+    /// The FIFO under/overruns during the data phase
+    FifoUnderrun,
+    /// The card is pulled out after answering the command
+    RemoveCard,
+    /// The card keeps DAT0 low after the command until the faults are cleared
+    DataBusy,
+    /// The card answers the command but does not transfer the data until the
+    /// transfer is stopped with CMD12
+    DataStall,
+    /// The controller never accepts the command, `start_cmd` stays set until
+    /// the faults are cleared or the controller is reset
+    StuckStartCmd,
+    /// The internal DMA controller gets a bus error in the data phase of the command
+    DmaBusError,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct FaultEntry {
+    /// Command index, any command if None
+    cmd: Option<u8>,
+    /// Matching commands to let through first
+    skip: usize,
+    repeat: bool,
+    fault: Fault,
+}
+
+/// Faults the simulated controller injects into upcoming commands
+///
+/// Commands are matched by index, so ACMDs match the CMD of the same index.
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    entries: Vec<FaultEntry>,
+}
+
+impl FaultPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inject `fault` into the next command with index `cmd`
+    pub fn on_cmd(self, cmd: u8, fault: Fault) -> Self {
+        self.on_nth_cmd(cmd, 1, fault)
+    }
+
+    /// Inject `fault` into the `nth` next command with index `cmd`, counting from 1
+    pub fn on_nth_cmd(mut self, cmd: u8, nth: usize, fault: Fault) -> Self {
+        self.entries.push(FaultEntry {
+            cmd: Some(cmd),
+            skip: nth.saturating_sub(1),
+            repeat: false,
+            fault,
+        });
+        self
+    }
+
+    /// Inject `fault` into every command with index `cmd`
+    pub fn on_every_cmd(mut self, cmd: u8, fault: Fault) -> Self {
+        self.entries.push(FaultEntry {
+            cmd: Some(cmd),
+            skip: 0,
+            repeat: true,
+            fault,
+        });
+        self
+    }
+
+    /// Inject `fault` into the next command whatever its index
+    pub fn on_next_cmd(mut self, fault: Fault) -> Self {
+        self.entries.push(FaultEntry {
+            cmd: None,
+            skip: 0,
+            repeat: false,
+            fault,
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The fault for a command with index `cmd`, the first matching entry wins
+    fn take(&mut self, cmd: u8) -> Option<Fault> {
+        let mut fault = None;
+        self.entries.retain_mut(|entry| {
+            if entry.cmd.is_some_and(|c| c != cmd) {
+                return true;
+            }
+            if entry.skip > 0 {
+                entry.skip -= 1;
+                return true;
+            }
+            if fault.is_some() {
+                return true;
+            }
+            fault = Some(entry.fault);
+            entry.repeat
+        });
+        fault
+    }
+}
+
+/// An ongoing data transfer between the FIFO and the card
+enum Transfer {
+    /// Bytes the card has not pushed into the FIFO yet
+    Read(VecDeque<u8>),
+    /// Bytes the card has received and the address they go to
+    Write {
+        addr: usize,
+        data: Vec<u8>,
+        len: usize,
+    },
+    /// The card holds the data back
+    Stalled,
+}
+
+struct Controller {
+    ctrl: u32,
+    pwren: u32,
+    clkdiv: u32,
+    clkena: u32,
+    ctype: u32,
+    blksiz: u32,
+    bytcnt: u32,
+    cmdarg: u32,
+    cmd: u32,
+    resp: [u32; 4],
+    rintsts: u32,
+    intmask: u32,
+    debnce: u32,
+    response_index: u8,
+    bmod: u32,
+    dbaddrl: u32,
+    dbaddru: u32,
+    idsts: u32,
+    idinten: u32,
+    /// Bus addresses of the IDMAC are host addresses
+    dma_mapped: bool,
+    /// The IDMAC fails the data phase in flight
+    dma_fault: bool,
+    fifo: VecDeque<u32>,
+    transfer: Option<Transfer>,
+    auto_stop: bool,
+    card: Option<SimCard>,
+    faults: FaultPlan,
+    /// Fault raised at the end of the data phase
+    data_fault: Option<Fault>,
+    data_busy: bool,
+    start_stuck: bool,
+}
+
+impl Controller {
+    fn new(card: Option<SimCard>) -> Self {
+        Self {
+            ctrl: 0,
+            pwren: 1,
+            clkdiv: 0,
+            clkena: 0,
+            ctype: 0,
+            blksiz: 0x200,
+            bytcnt: 0x200,
+            cmdarg: 0,
+            cmd: 0,
+            resp: [0; 4],
+            rintsts: 0,
+            intmask: 0,
+            debnce: 0xff_ffff,
+            response_index: 0,
+            bmod: 0,
+            dbaddrl: 0,
+            dbaddru: 0,
+            idsts: 0,
+            idinten: 0,
+            dma_mapped: false,
+            dma_fault: false,
+            fifo: VecDeque::new(),
+            transfer: None,
+            auto_stop: false,
+            card,
+            faults: FaultPlan::new(),
+            data_fault: None,
+            data_busy: false,
+            start_stuck: false,
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let fifo_count = self.fifo.len();
+        StatusReg::new()
+            .with_fifo_count(fifo_count as u16)
+            .with_response_index(self.response_index)
+            .with_data_state_mc_busy(self.transfer.is_some())
+            .with_data_busy(self.data_busy)
+            .with_data_3_status(self.card.is_some())
+            .with_fifo_full(fifo_count == FIFO_DEPTH)
+            .with_fifo_empty(fifo_count == 0)
+            .with_fifo_tx_watermark(fifo_count <= TX_WATERMARK)
+            .with_fifo_rx_watermark(fifo_count > 0)
+            .into()
+    }
+
+    fn read(&mut self, offset: usize) -> u32 {
+        self.tick();
+        match offset {
+            CTRL_REG => self.ctrl,
+            POWER_REG => self.pwren,
+            CLK_DIVIDER_REG => self.clkdiv,
+            CLOCK_ENABLE_REG => self.clkena,
+            CTYPE_REG => self.ctype,
+            BLK_SIZE_REG => self.blksiz,
+            BYTE_CNT_REG => self.bytcnt,
+            ARG_REG => self.cmdarg,
+            CMD_REG => self.cmd,
+            RESP0_REG => self.resp[0],
+            RESP1_REG => self.resp[1],
+            RESP2_REG => self.resp[2],
+            RESP3_REG => self.resp[3],
+            RAW_INT_STATUS_REG => self.rintsts,
+            INT_MASK_REG => self.intmask,
+            MASKED_INT_STATUS_REG => self.rintsts & self.intmask,
+            STATUS_REG => self.status(),
+            // card_detect_n is active low
+            CDETECT_REG => self.card.is_none() as u32,
+            DEBNCE_REG => self.debnce,
+            BUS_MODE_REG => self.bmod,
+            DBADDRL_REG => self.dbaddrl,
+            DBADDRU_REG => self.dbaddru,
+            IDSTS_REG => self.idsts,
+            IDINTEN_REG => self.idinten,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, val: u32) {
+        match offset {
+            CTRL_REG => {
+                let ctrl = ControlReg::from(val);
+                if ctrl.fifo_reset() {
+                    self.fifo.clear();
+                }
+                if ctrl.controller_reset() {
+                    self.start_stuck = false;
+                    self.cmd = CmdReg::from(self.cmd).with_start_cmd(false).into();
+                }
+                // the reset bits clear themselves
+                self.ctrl = ctrl
+                    .with_fifo_reset(false)
+                    .with_dma_reset(false)
+                    .with_controller_reset(false)
+                    .into();
+            }
+            POWER_REG => self.pwren = val,
+            CLK_DIVIDER_REG => self.clkdiv = val,
+            CLOCK_ENABLE_REG => self.clkena = val,
+            CTYPE_REG => self.ctype = val,
+            BLK_SIZE_REG => self.blksiz = val,
+            BYTE_CNT_REG => self.bytcnt = val,
+            ARG_REG => self.cmdarg = val,
+            CMD_REG => {
+                self.cmd = val;
+                if CmdReg::from(val).start_cmd() {
+                    self.start_cmd();
+                }
+            }
+            RAW_INT_STATUS_REG => self.rintsts &= !val,
+            INT_MASK_REG => self.intmask = val,
+            DEBNCE_REG => self.debnce = val & 0xff_ffff,
+            BUS_MODE_REG => self.bmod = BusModeReg::from(val).with_swr(false).into(),
+            DBADDRL_REG => self.dbaddrl = val,
+            DBADDRU_REG => self.dbaddru = val,
+            IDSTS_REG => self.idsts &= !val,
+            IDINTEN_REG => self.idinten = val,
+            _ => {}
+        }
+        self.tick();
+    }
+
+    fn start_cmd(&mut self) {
+        if self.start_stuck {
+            return;
+        }
+        let cmd = CmdReg::from(self.cmd);
+        let fault = if cmd.update_clock_registers_only() {
+            None
+        } else {
+            self.faults.take(cmd.cmd_index() as u8)
+        };
+        if fault == Some(Fault::StuckStartCmd) {
+            self.start_stuck = true;
+            return;
+        }
+        self.cmd = cmd.with_start_cmd(false).into();
+        if cmd.update_clock_registers_only() {
+            return;
+        }
+        self.response_index = cmd.cmd_index() as u8;
+        let clock_on = ClockEnableReg::from(self.clkena).clk_enable() & 1 != 0;
+        let len = if cmd.data_expected() {
+            self.bytcnt as usize
+        } else {
+            0
+        };
+        // a stop command ends the data phase in flight
+        if cmd.stop_abort_cmd() && self.transfer.take().is_some() {
+            self.rintsts |= INT_DTO;
+        }
+        let (resp, data) = match self.card.as_mut() {
+            Some(card) if clock_on && fault != Some(Fault::ResponseTimeout) => {
+                card.command(cmd.cmd_index(), self.cmdarg, len)
+            }
+            _ => (Response::None, DataPhase::None),
+        };
+        self.rintsts |= INT_CD;
+        match fault {
+            Some(Fault::ResponseCrc) => self.rintsts |= INT_RCRC,
+            Some(Fault::DataBusy) => self.data_busy = true,
+            Some(Fault::RemoveCard) => {
+                self.card = None;
+                self.rintsts |= INT_CARD_DETECT;
+            }
+            _ => {}
+        }
+        match resp {
+            Response::None if cmd.response_expect() => {
+                self.rintsts |= INT_RTO;
+                return;
+            }
+            Response::None => {}
+            Response::Short(resp) => self.resp = [resp, 0, 0, 0],
+            Response::Long(resp) => {
+                self.resp = [
+                    resp as u32,
+                    (resp >> 32) as u32,
+                    (resp >> 64) as u32,
+                    (resp >> 96) as u32,
+                ]
+            }
+        }
+        if !cmd.data_expected() {
+            return;
+        }
+        self.auto_stop = cmd.send_auto_stop();
+        self.data_fault = fault.filter(|f| matches!(f, Fault::DataCrc | Fault::FifoUnderrun));
+        self.dma_fault = fault == Some(Fault::DmaBusError);
+        let data = match data {
+            DataPhase::Read(_) | DataPhase::Write(_) if self.card.is_none() => DataPhase::Rejected,
+            data => data,
+        };
+        match data {
+            DataPhase::Read(_) | DataPhase::Write(_) if fault == Some(Fault::DataStall) => {
+                self.transfer = Some(Transfer::Stalled)
+            }
+            DataPhase::Read(data) => self.transfer = Some(Transfer::Read(data.into())),
+            DataPhase::Write(addr) => {
+                self.transfer = Some(Transfer::Write {
+                    addr,
+                    data: Vec::with_capacity(len),
+                    len,
+                })
+            }
+            // the controller times out waiting for the data
+            DataPhase::Rejected if cmd.transfer_dir() => self.rintsts |= INT_EBE,
+            DataPhase::Rejected => self.rintsts |= INT_DRTO,
+            DataPhase::None => {}
+        }
+    }
+
+    /// Move data between the card and the FIFO
+    fn tick(&mut self) {
+        let write = matches!(self.transfer, Some(Transfer::Write { .. }));
+        let dma = self.dma_active() && (write || matches!(self.transfer, Some(Transfer::Read(_))));
+        if dma && !self.dma_tick() {
+            return;
+        }
+        match self.transfer.as_mut() {
+            Some(Transfer::Read(pending)) => {
+                while self.fifo.len() < FIFO_DEPTH && !pending.is_empty() {
+                    let mut word = [0u8; 4];
+                    for byte in word.iter_mut() {
+                        *byte = pending.pop_front().unwrap_or(0);
+                    }
+                    self.fifo.push_back(u32::from_le_bytes(word));
+                }
+                if !self.fifo.is_empty() {
+                    self.rintsts |= INT_RXDR;
+                }
+                if pending.is_empty() {
+                    self.finish_transfer();
+                }
+            }
+            Some(Transfer::Write { addr, data, len }) => {
+                while let Some(word) = self.fifo.pop_front() {
+                    data.extend_from_slice(&word.to_le_bytes());
+                }
+                if data.len() >= *len {
+                    let (addr, len) = (*addr, *len);
+                    if let Some(card) = self.card.as_mut().filter(|_| self.data_fault.is_none()) {
+                        card.store(addr, &data[..len]);
+                    }
+                    self.finish_transfer();
+                } else {
+                    self.rintsts |= INT_TXDR;
+                }
+            }
+            Some(Transfer::Stalled) | None => {}
+        }
+        if dma && self.transfer.is_none() {
+            let status = IdmacStatusReg::from(self.idsts).with_nis(true);
+            let status = if self.rintsts & INT_DCRC != 0 {
+                status.with_ces(true).with_ais(true)
+            } else {
+                status
+            };
+            let status = if write {
+                status.with_ti(true)
+            } else {
+                status.with_ri(true)
+            };
+            self.idsts = status.into();
+        }
+    }
+
+    fn dma_active(&self) -> bool {
+        ControlReg::from(self.ctrl).use_internal_dmac() && BusModeReg::from(self.bmod).de()
+    }
+
+    /// Move the data of the transfer in flight between the card and the descriptor
+    /// buffers, false if the IDMAC stopped
+    fn dma_tick(&mut self) -> bool {
+        let write = matches!(self.transfer, Some(Transfer::Write { .. }));
+        if !self.dma_mapped || self.dma_fault {
+            self.dma_fault = false;
+            // EB: host abort received during transmission or reception
+            self.idsts = IdmacStatusReg::from(self.idsts)
+                .with_fbe(true)
+                .with_ais(true)
+                .with_eb(if write { 1 } else { 2 })
+                .into();
+            self.transfer = Some(Transfer::Stalled);
+            return false;
+        }
+        let mut addr = self.dbaddrl as usize | (self.dbaddru as usize) << 32;
+        loop {
+            let desc = addr as *mut [u32; 8];
+            // SAFETY: enable_dma guarantees the descriptors are valid host memory
+            let des = unsafe { desc.read_volatile() };
+            if des[0] & DES0_OWN == 0 {
+                break;
+            }
+            let len = (des[2] & 0x1fff) as usize;
+            let buf = (des[4] as usize | (des[5] as usize) << 32) as *mut u8;
+            match self.transfer.as_mut() {
+                Some(Transfer::Read(pending)) => {
+                    let len = len.min(pending.len());
+                    // SAFETY: enable_dma guarantees the buffers are valid host memory
+                    let buf = unsafe { core::slice::from_raw_parts_mut(buf, len) };
+                    for (byte, data) in buf.iter_mut().zip(pending.drain(..len)) {
+                        *byte = data;
+                    }
+                }
+                Some(Transfer::Write { data, .. }) => {
+                    // SAFETY: enable_dma guarantees the buffers are valid host memory
+                    data.extend_from_slice(unsafe { core::slice::from_raw_parts(buf, len) });
+                }
+                _ => unreachable!(),
+            }
+            // SAFETY: des0 is the first word of the descriptor read above
+            unsafe { (desc as *mut u32).write_volatile(des[0] & !DES0_OWN) };
+            if des[0] & DES0_LD != 0 {
+                break;
+            }
+            addr = des[6] as usize | (des[7] as usize) << 32;
+        }
+        let done = match self.transfer.as_ref() {
+            Some(Transfer::Read(pending)) => pending.is_empty(),
+            Some(Transfer::Write { data, len, .. }) => data.len() >= *len,
+            _ => false,
+        };
+        if !done {
+            // the chain ended before the data phase
+            self.idsts = IdmacStatusReg::from(self.idsts)
+                .with_du(true)
+                .with_ais(true)
+                .into();
+            self.transfer = Some(Transfer::Stalled);
+        }
+        done
+    }
+
+    fn finish_transfer(&mut self) {
+        self.transfer = None;
+        self.rintsts |= INT_DTO;
+        match self.data_fault.take() {
+            Some(Fault::DataCrc) => self.rintsts |= INT_DCRC,
+            Some(Fault::FifoUnderrun) => self.rintsts |= INT_FRUN,
+            _ => {}
+        }
+        if let Some(card) = self.card.as_mut() {
+            card.data_done();
+        }
+        if self.auto_stop {
+            self.auto_stop = false;
+            self.rintsts |= INT_ACD;
+        }
+    }
+
+    fn read_fifo(&mut self) -> u64 {
+        self.tick();
+        if self.fifo.len() < 2 {
+            self.rintsts |= INT_FRUN;
+        }
+        let low = self.fifo.pop_front().unwrap_or(0) as u64;
+        let high = self.fifo.pop_front().unwrap_or(0) as u64;
+        self.tick();
+        low | (high << 32)
+    }
+
+    fn write_fifo(&mut self, val: u64) {
+        if self.fifo.len() + 2 > FIFO_DEPTH {
+            self.rintsts |= INT_FRUN;
+            return;
+        }
+        self.fifo.push_back(val as u32);
+        self.fifo.push_back((val >> 32) as u32);
+        self.tick();
+    }
+}
+
+/// A simulated controller implementing [`SDIo`]
+///
+/// # Example
+/// ```rust
+/// use visionfive2_sd::sim::{SimCard, SimSleep, SimulatedController};
+/// use visionfive2_sd::Vf2SdDriver;
+/// let card = SimCard::new(vec![0; 1024 * 1024]);
+/// let mut driver = Vf2SdDriver::<_, SimSleep>::new(SimulatedController::new(card));
+/// driver.init().unwrap();
+/// let mut buf = [0u8; 512];
+/// driver.read_block(1, &mut buf).unwrap();
+/// ```
+pub struct SimulatedController {
+    inner: Rc<RefCell<Controller>>,
+}
+
+impl SimulatedController {
+    pub fn new(card: SimCard) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Controller::new(Some(card)))),
+        }
+    }
+
+    /// A controller with an empty card slot
+    pub fn empty() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Controller::new(None))),
+        }
+    }
+
+    /// Another register interface of the same controller, e.g. for an interrupt handler
+    pub fn share(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Access the inserted card
+    pub fn with_card<R>(&self, f: impl FnOnce(Option<&mut SimCard>) -> R) -> R {
+        f(self.inner.borrow_mut().card.as_mut())
+    }
+
+    /// Insert `card`, raising the card detect interrupt
+    pub fn insert_card(&mut self, card: SimCard) {
+        let mut inner = self.inner.borrow_mut();
+        inner.card = Some(card);
+        inner.rintsts |= INT_CARD_DETECT;
+    }
+
+    /// Remove the card, raising the card detect interrupt
+    pub fn remove_card(&mut self) -> Option<SimCard> {
+        let mut inner = self.inner.borrow_mut();
+        inner.rintsts |= INT_CARD_DETECT;
+        inner.card.take()
+    }
+
+    /// The interrupt line, raised if the global interrupt enable is set and an unmasked
+    /// interrupt is pending
+    pub fn interrupt_pending(&self) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        inner.tick();
+        ControlReg::from(inner.ctrl).int_enable() && inner.rintsts & inner.intmask != 0
+            || inner.idsts & inner.idinten != 0
+    }
+
+    /// Let the internal DMA controller access host memory, bus addresses are taken as host
+    /// addresses. Without it every DMA transfer ends with a fatal bus error.
+    ///
+    /// # Safety
+    ///
+    /// Every descriptor chain and buffer handed to a DMA transfer of this controller, or
+    /// written to `DBADDRL`/`DBADDRU` directly, must be valid host memory until the
+    /// transfer completed.
+    pub unsafe fn enable_dma(&mut self) {
+        self.inner.borrow_mut().dma_mapped = true;
+    }
+
+    /// Replace the pending faults
+    pub fn set_fault_plan(&mut self, plan: FaultPlan) {
+        self.inner.borrow_mut().faults = plan;
+    }
+
+    /// Drop the pending faults and recover from a stuck `data_busy` or `start_cmd`
+    pub fn clear_faults(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.faults = FaultPlan::new();
+        inner.data_fault = None;
+        inner.data_busy = false;
+        if inner.start_stuck {
+            inner.start_stuck = false;
+            inner.cmd = CmdReg::from(inner.cmd).with_start_cmd(false).into();
+        }
+    }
+}
+
+impl SDIo for SimulatedController {
+    fn read_reg_at(&self, offset: usize) -> u32 {
+        self.inner.borrow_mut().read(offset)
+    }
+    fn write_reg_at(&mut self, offset: usize, val: u32) {
+        // 32-bit accesses of the FIFO window are not modelled
+        if offset >= FIFO_DATA_REG {
+            return;
+        }
+        self.inner.borrow_mut().write(offset, val)
+    }
+    fn read_data_at(&self, _offset: usize) -> u64 {
+        self.inner.borrow_mut().read_fifo()
+    }
+    fn write_data_at(&mut self, _offset: usize, val: u64) {
+        self.inner.borrow_mut().write_fifo(val)
+    }
+}
+
+/// [`SleepOps`] for the simulator, time does not pass and waiting polls a bounded number of times
+pub struct SimSleep;
+
+/// Polls of the condition per simulated millisecond
+const POLLS_PER_MS: usize = 4;
+
+impl SleepOps for SimSleep {
+    fn sleep_ms(_ms: usize) {}
+    fn sleep_ms_until(ms: usize, mut f: impl FnMut() -> bool) {
+        for _ in 0..ms.max(1) * POLLS_PER_MS {
+            if f() {
+                return;
+            }
+        }
+    }
+}
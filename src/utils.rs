@@ -1,21 +1,20 @@
-use crate::register::SDIO_BASE;
-
-pub fn read_fifo<T: SDIo>(io: &T, addr: usize) -> u64 {
-    io.read_data_at(addr - SDIO_BASE)
+pub fn read_fifo<T: SDIo>(io: &T, offset: usize) -> u64 {
+    io.read_data_at(offset)
 }
 
-pub fn write_fifo<T: SDIo>(io: &mut T, addr: usize, val: u64) {
-    io.write_data_at(addr - SDIO_BASE, val);
+pub fn write_fifo<T: SDIo>(io: &mut T, offset: usize, val: u64) {
+    io.write_data_at(offset, val);
 }
 
-pub fn write_reg<T: SDIo>(io: &mut T, addr: usize, val: u32) {
-    io.write_reg_at(addr - SDIO_BASE, val);
+pub fn write_reg<T: SDIo>(io: &mut T, offset: usize, val: u32) {
+    io.write_reg_at(offset, val);
 }
 
-pub fn read_reg<T: SDIo>(io: &T, addr: usize) -> u32 {
-    io.read_reg_at(addr - SDIO_BASE)
+pub fn read_reg<T: SDIo>(io: &T, offset: usize) -> u32 {
+    io.read_reg_at(offset)
 }
 
+/// Access to the registers of one controller, `offset` is relative to its base address
 pub trait SDIo {
     fn read_reg_at(&self, offset: usize) -> u32;
     fn write_reg_at(&mut self, offset: usize, val: u32);
@@ -23,6 +22,67 @@ pub trait SDIo {
     fn write_data_at(&mut self, offset: usize, val: u64);
 }
 
+/// SDIO controllers of the JH7110
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SdioInstance {
+    /// SDIO0, wired to the eMMC socket
+    Sdio0,
+    /// SDIO1, wired to the SD card slot
+    Sdio1,
+}
+
+impl SdioInstance {
+    /// Physical base address of the controller registers
+    pub const fn base(&self) -> usize {
+        match self {
+            SdioInstance::Sdio0 => 0x1601_0000,
+            SdioInstance::Sdio1 => 0x1602_0000,
+        }
+    }
+}
+
+/// [`SDIo`] with volatile accesses to memory mapped registers
+#[derive(Debug)]
+pub struct MmioSdIo {
+    base: usize,
+}
+
+impl MmioSdIo {
+    /// # Safety
+    ///
+    /// `base` must be the (virtual) address the controller registers are mapped at,
+    /// and no one else may access the controller while this value is alive.
+    pub const unsafe fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    /// # Safety
+    ///
+    /// The controller must be identity mapped, see [`MmioSdIo::new`].
+    pub const unsafe fn from_instance(instance: SdioInstance) -> Self {
+        Self::new(instance.base())
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+}
+
+impl SDIo for MmioSdIo {
+    fn read_reg_at(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+    fn write_reg_at(&mut self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+    fn read_data_at(&self, offset: usize) -> u64 {
+        unsafe { ((self.base + offset) as *const u64).read_volatile() }
+    }
+    fn write_data_at(&mut self, offset: usize, val: u64) {
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(val) }
+    }
+}
+
 pub trait SleepOps {
     fn sleep_ms(ms: usize);
     fn sleep_ms_until(ms: usize, f: impl FnMut() -> bool);
@@ -60,6 +120,21 @@ impl GetBit for u128 {
 mod tests {
 
     use super::*;
+    #[test]
+    fn test_mmio_offset() {
+        let mut regs = [0u64; 0x100];
+        let mut io = unsafe { MmioSdIo::new(regs.as_mut_ptr() as usize) };
+        io.write_reg_at(0x28, 0x1234);
+        io.write_data_at(0x600, u64::MAX);
+        assert_eq!(io.read_reg_at(0x28), 0x1234);
+        assert_eq!(regs[0x28 / 8], 0x1234);
+        assert_eq!(regs[0x600 / 8], u64::MAX);
+        assert_eq!(
+            SdioInstance::Sdio1.base() - SdioInstance::Sdio0.base(),
+            0x10000
+        );
+    }
+
     #[test]
     fn test_get_bit() {
         let val = 0b1010_1010u32;